@@ -1,38 +1,79 @@
 mod auth_checker;
+#[cfg(test)]
+mod corpus;
 mod entity_checker;
 mod link_checker;
 mod nlp_checker;
 
+pub use link_checker::LinkRechecker;
+
 use crate::analysis::auth_checker::AuthAnalyzer;
 use crate::analysis::entity_checker::EntityChecker;
 use crate::analysis::link_checker::LinkAnalyzer;
 use crate::analysis::nlp_checker::NLPChecker;
 use crate::command::AnalysisCommand;
+use crate::config::Config;
 use crate::email::OwnedEmail;
+use crate::http::HttpClient;
+use log::info;
+use crate::secrets::{SecretStore, SECRETS, VIRUSTOTAL};
+use mail_auth::Resolver;
 use mail_parser::{Address, Message};
 use rand::random;
 use rocket::serde::json::serde_json;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::broadcast::Sender;
 use tokio::sync::OnceCell;
-use crate::job::Job;
+use crate::job::{AnalyzerState, AnalyzerStatus, Job};
+use crate::recheck::{Recheck, VerdictChange};
+use crate::scoring::RiskAssessment;
 
 pub static ANALYZERS: OnceCell<Vec<Arc<dyn MailAnalyzer>>> = OnceCell::const_new();
 
-pub fn init_analyzers() {
-    let analyzers: Vec<Arc<dyn MailAnalyzer>> = vec![
-        Arc::new(EntityChecker),
-        Arc::new(LinkAnalyzer),
-        Arc::new(AuthAnalyzer),
-        Arc::new(NLPChecker),
-    ];
+/// Creates the analyzers enabled by the configuration
+pub fn init_analyzers(config: &Config) {
+    let analyzers = create_analyzers(config, SECRETS.get().unwrap());
+
     if ANALYZERS.set(analyzers).is_err() {
         panic!("analyzers should not be already initialized")
     };
 }
 
-#[derive(Debug, Clone, Serialize)]
+/// Returns the analyzers enabled by the configuration, whose keys are taken from `secrets`
+pub fn create_analyzers(config: &Config, secrets: &SecretStore) -> Vec<Arc<dyn MailAnalyzer>> {
+    let http = HttpClient::new(&config.http);
+
+    let analyzers: Vec<Arc<dyn MailAnalyzer>> = vec![
+        Arc::new(EntityChecker),
+        Arc::new(LinkAnalyzer::new(
+            config.services.virustotal.clone(),
+            secrets.key_ring(VIRUSTOTAL),
+            http.clone(),
+        )),
+        Arc::new(AuthAnalyzer::new(
+            Resolver::new_system_conf().expect("could not read the system DNS configuration"),
+        )),
+        Arc::new(NLPChecker::new(config.services.worker_url.clone(), http)),
+    ];
+
+    if let Some(enabled) = &config.analyzers.enabled {
+        for id in enabled {
+            if !analyzers.iter().any(|a| a.id() == id) {
+                panic!("unknown analyzer `{id}`")
+            }
+        }
+    }
+
+    analyzers
+        .into_iter()
+        .filter(|a| config.analyzers.is_enabled(a.id()))
+        .collect()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct AnalysisResult {
     id: usize,
@@ -40,7 +81,7 @@ pub struct AnalysisResult {
     pub verdict: AnalysisVerdict,
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AnalysisVerdict {
     pub kind: String,
     pub value: serde_json::Value,
@@ -55,8 +96,95 @@ impl AnalysisVerdict {
         }
     }
 
-    pub fn error<V: Serialize>(value: V) -> Self {
-        Self::new("error", value)
+}
+
+/// An error reported by an analyzer, either by one of its tasks or because a task panicked.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AnalysisError {
+    pub analysis_name: String,
+    pub message: String,
+}
+
+/// Analyzers to run on an email and their options, chosen at submission
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(default, rename_all = "camelCase")]
+pub struct AnalysisOptions {
+    /// Ids of the analyzers to run, every analyzer if not set
+    pub analyzers: Option<Vec<String>>,
+    /// Options by analyzer id, see [`MailAnalyzer::options`]
+    pub options: HashMap<String, HashMap<String, bool>>,
+}
+
+impl AnalysisOptions {
+    /// Returns a boolean option of an analyzer, if it was set
+    pub fn option(&self, analyzer_id: &str, name: &str) -> Option<bool> {
+        self.options.get(analyzer_id)?.get(name).copied()
+    }
+
+    pub fn runs(&self, analyzer_id: &str) -> bool {
+        self.analyzers
+            .as_ref()
+            .is_none_or(|ids| ids.iter().any(|id| id == analyzer_id))
+    }
+
+    /// Checks that the selected analyzers and their options exist among the given analyzers
+    pub fn validate(&self, analyzers: &[Arc<dyn MailAnalyzer>]) -> Result<(), String> {
+        let find = |id: &str| {
+            analyzers
+                .iter()
+                .find(|a| a.id() == id)
+                .ok_or_else(|| format!("unknown or disabled analyzer `{id}`"))
+        };
+
+        if self.analyzers.as_ref().is_some_and(Vec::is_empty) {
+            return Err(String::from("no analyzer is selected"));
+        }
+        for id in self.analyzers.iter().flatten() {
+            find(id)?;
+        }
+
+        for (id, options) in &self.options {
+            let analyzer = find(id)?;
+            if let Some(name) = options.keys().find(|name| !analyzer.options().contains(&name.as_str())) {
+                return Err(format!("unknown option `{name}` of analyzer `{id}`"));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Deadlines after which the work of analyzers is abandoned
+#[derive(Debug, Clone)]
+pub struct AnalysisTimeouts {
+    /// Maximum duration of a single task spawned by an analyzer
+    pub task: Duration,
+    /// Maximum duration of an analyzer, unless overridden in `analyzers`
+    pub analyzer: Duration,
+    /// Per-analyzer overrides of the `analyzer` timeout, by analyzer id
+    pub analyzers: HashMap<String, Duration>,
+    /// Maximum duration of a job, after which it is force-completed
+    pub job: Duration,
+}
+
+impl AnalysisTimeouts {
+    pub fn analyzer_timeout(&self, analyzer_id: &str) -> Duration {
+        self.analyzers
+            .get(analyzer_id)
+            .copied()
+            .unwrap_or(self.analyzer)
+    }
+}
+
+impl Default for AnalysisTimeouts {
+    fn default() -> Self {
+        Self {
+            task: Duration::from_secs(120),
+            analyzer: Duration::from_secs(300),
+            analyzers: HashMap::new(),
+            job: Duration::from_secs(600),
+        }
     }
 }
 
@@ -84,45 +212,85 @@ impl AnalysisResult {
 pub enum JobEvent {
     ExpandedResultCount(usize),
     Progress(AnalysisResult),
-    AnalysisDone(String),
-
-    //currently unuseds
-    Error(String),
+    AnalyzerStarted(AnalyzerStatus),
+    AnalyzerFinished(AnalyzerStatus),
+    Error(AnalysisError),
+    /// Final assessment of the job, sent right before its completion
+    RiskAssessed(RiskAssessment),
     JobComplete,
+    /// The named analyzers of a complete job run again: their results and failures are
+    /// discarded, along with the results of the rules and the risk, which are assessed again
+    Rerun(Vec<String>),
+    /// The links of a complete job were looked up again, see [`crate::recheck`]
+    LinksRechecked(Recheck),
+    /// A link of a complete job is now flagged as malicious, published right before the
+    /// lookup that flagged it
+    VerdictChanged(VerdictChange),
 }
 
 impl JobEvent {
+    /// Whether the event ends the events of a job when it is the last one: the completion of
+    /// the job, or the last event of a lookup of its links
     pub fn is_closing_action(&self) -> bool {
-        matches!(self, JobEvent::Error(_) | JobEvent::JobComplete)
+        matches!(self, JobEvent::JobComplete | JobEvent::LinksRechecked(_))
     }
 }
 
 pub trait MailAnalyzer: Send + Sync {
+    /// Short identifier of the analyzer, used in the configuration
+    fn id(&self) -> &'static str;
     fn name(&self) -> String;
+    /// Boolean options accepted at submission, read with [`AnalysisCommand::option`]
+    fn options(&self) -> &'static [&'static str] {
+        &[]
+    }
     fn analyze(&self, email: OwnedEmail, command: AnalysisCommand) -> AnalysisSetup;
 }
 
 pub async fn start_email_analysis(
     analyzers: Vec<Arc<dyn MailAnalyzer>>,
     job: Arc<Job>,
+    timeouts: &AnalysisTimeouts,
 ) {
+    {
+        // the analyzers that are not run keep their status, e.g. when a job is rerun
+        let mut statuses = job.analyzers.lock().unwrap();
+        for analyzer in &analyzers {
+            if !statuses.iter().any(|s| s.name == analyzer.name()) {
+                statuses.push(AnalyzerStatus::pending(analyzer.name()));
+            }
+        }
+    }
+
     let mut total_expected_verdict_count = 0;
     for analyzer in analyzers {
-        let email_string = job.email.clone();
+        let email = job.email.clone();
 
-        let command = AnalysisCommand::new(analyzer.name(), job.clone());
-        println!("Launched {}", analyzer.name());
-        let setup = analyzer.analyze(OwnedEmail::new(email_string), command);
+        let command = AnalysisCommand::new(
+            analyzer.name(),
+            job.clone(),
+            timeouts.task,
+            timeouts.analyzer_timeout(analyzer.id()),
+            job.options
+                .options
+                .get(analyzer.id())
+                .cloned()
+                .unwrap_or_default(),
+        );
+
+        job.publish(JobEvent::AnalyzerStarted(AnalyzerStatus {
+            state: AnalyzerState::Running,
+            started_at: Some(command.started_at()),
+            ..AnalyzerStatus::pending(analyzer.name())
+        }));
+
+        info!("Launched {}", analyzer.name());
+        let email = OwnedEmail::new(email).with_envelope(job.envelope().cloned());
+        let setup = analyzer.analyze(email, command);
 
         total_expected_verdict_count += setup.expected_verdict_count;
     }
 
-    job
-        .event_channel
-        .send(JobEvent::ExpandedResultCount(total_expected_verdict_count))
-        .unwrap();
-
-    //FIXME workaround for app's desynchronisation after a job is submitted
-    tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    job.publish(JobEvent::ExpandedResultCount(total_expected_verdict_count));
 }
 
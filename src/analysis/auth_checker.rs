@@ -1,35 +1,49 @@
 use crate::analysis::{AnalysisSetup, AnalysisVerdict, MailAnalyzer};
 use crate::command::AnalysisCommand;
 use crate::email::OwnedEmail;
+use crate::ingestion::SmtpEnvelope;
 use mail_auth::common::verify::VerifySignature;
 use mail_auth::{AuthenticatedMessage, DkimResult, DmarcResult, Resolver, SpfOutput, SpfResult};
 use mail_parser::{Address, Host, MessageParser};
 use rocket::serde::Serialize;
 use std::collections::HashMap;
 
-pub struct AuthAnalyzer;
+pub struct AuthAnalyzer {
+    resolver: Resolver,
+}
+
+impl AuthAnalyzer {
+    pub fn new(resolver: Resolver) -> Self {
+        Self { resolver }
+    }
+}
 
 impl MailAnalyzer for AuthAnalyzer {
+    fn id(&self) -> &'static str {
+        "auth"
+    }
+
     fn name(&self) -> String {
         String::from("Authentication Checks")
     }
 
     fn analyze(&self, email: OwnedEmail, command: AnalysisCommand) -> AnalysisSetup {
-        let email_string = String::from(email.raw_str());
-        let resolver = Resolver::new_system_conf().unwrap();
+        let envelope = email.envelope().cloned();
+        let email = email.into_bytes();
+        let resolver = &self.resolver;
 
         macro_rules! wrap_check_task {
-            ($fun:expr) => {{
+            ($fun:expr $(, $arg:expr)*) => {{
                 let resolver = resolver.clone();
-                let email_string = email_string.clone();
-                command.spawn($fun(resolver, email_string));
+                let email = email.clone();
+                command.try_spawn($fun(resolver, email $(, $arg.clone())*));
             }};
         }
 
         wrap_check_task!(verify_dkim);
         wrap_check_task!(verify_arc_chain);
-        wrap_check_task!(verify_spf);
-        wrap_check_task!(verify_dmarc);
+        wrap_check_task!(verify_spf, envelope);
+        wrap_check_task!(verify_dmarc, envelope);
 
         command.validate()
     }
@@ -72,32 +86,37 @@ impl From<&DkimResult> for DKIMAnalysisVerdict {
     }
 }
 
-async fn verify_dkim(resolver: Resolver, msg: String) -> AnalysisVerdict {
-    let msg = AuthenticatedMessage::parse(msg.as_bytes()).unwrap();
+fn parse_authenticated_message(msg: &[u8]) -> Result<AuthenticatedMessage<'_>, String> {
+    AuthenticatedMessage::parse(msg)
+        .ok_or_else(|| String::from("could not parse the email headers"))
+}
+
+async fn verify_dkim(resolver: Resolver, msg: Vec<u8>) -> Result<AnalysisVerdict, String> {
+    let msg = parse_authenticated_message(&msg)?;
 
     let outputs = resolver
         .verify_dkim(&msg)
         .await
         .iter()
-        .map(|output| {
-            let domain = output.signature().unwrap().domain().to_string();
+        .filter_map(|output| {
+            let domain = output.signature()?.domain().to_string();
             let value = DKIMAnalysisVerdict::from(output.result());
-            (domain, value)
+            Some((domain, value))
         })
         .collect::<HashMap<_, _>>();
 
-    AnalysisVerdict::new("auth-dkim", &outputs)
+    Ok(AnalysisVerdict::new("auth-dkim", &outputs))
 }
 
-async fn verify_arc_chain(resolver: Resolver, msg: String) -> AnalysisVerdict {
-    let msg = AuthenticatedMessage::parse(msg.as_bytes()).unwrap();
+async fn verify_arc_chain(resolver: Resolver, msg: Vec<u8>) -> Result<AnalysisVerdict, String> {
+    let msg = parse_authenticated_message(&msg)?;
 
     let result = resolver.verify_arc(&msg).await;
 
-    AnalysisVerdict::new(
+    Ok(AnalysisVerdict::new(
         "auth-arc-chain",
         &DKIMAnalysisVerdict::from(result.result()),
-    )
+    ))
 }
 
 #[derive(Serialize)]
@@ -106,8 +125,12 @@ struct SpfAnalysisVerdict {
     result: String,
 }
 
-async fn verify_spf(resolver: Resolver, msg: String) -> AnalysisVerdict {
-    let spf_output = check_spf(resolver, msg).await;
+async fn verify_spf(
+    resolver: Resolver,
+    msg: Vec<u8>,
+    envelope: Option<SmtpEnvelope>,
+) -> Result<AnalysisVerdict, String> {
+    let spf_output = check_spf(resolver, msg, envelope.as_ref()).await?;
 
     let result = match spf_output.result() {
         SpfResult::Pass => "pass",
@@ -118,32 +141,56 @@ async fn verify_spf(resolver: Resolver, msg: String) -> AnalysisVerdict {
         SpfResult::None => "unknown",
     };
 
-    AnalysisVerdict::new(
+    Ok(AnalysisVerdict::new(
         "auth-spf",
         &SpfAnalysisVerdict {
             domain: spf_output.domain().to_string(),
             result: result.to_string(),
         },
-    )
+    ))
 }
 
-async fn check_spf(resolver: Resolver, msg: String) -> SpfOutput {
-    let msg = MessageParser::new().parse(&msg).unwrap();
+/// Checks the SPF record of the sender, using the envelope when the email was received over
+/// SMTP, and the last `Received` header otherwise
+async fn check_spf(
+    resolver: Resolver,
+    msg: Vec<u8>,
+    envelope: Option<&SmtpEnvelope>,
+) -> Result<SpfOutput, String> {
+    if let Some(envelope) = envelope {
+        return Ok(resolver
+            .verify_spf_sender(
+                envelope.client_ip,
+                &envelope.helo,
+                &envelope.helo,
+                &envelope.mail_from,
+            )
+            .await);
+    }
 
-    let sender_email_address = match msg.from().unwrap() {
-        Address::List(l) => l[0].address().unwrap(),
-        Address::Group(g) => g[0].addresses[0].address().unwrap(),
-    };
+    let msg = MessageParser::new()
+        .parse(&msg)
+        .ok_or_else(|| String::from("could not parse the email"))?;
+
+    let sender_email_address = match msg.from() {
+        Some(Address::List(l)) => l.first().and_then(|a| a.address()),
+        Some(Address::Group(g)) => g
+            .first()
+            .and_then(|g| g.addresses.first())
+            .and_then(|a| a.address()),
+        None => None,
+    }
+    .ok_or_else(|| String::from("the email has no sender address"))?;
 
     let Some(received) = msg.received() else {
-        return SpfOutput::default();
+        return Ok(SpfOutput::default());
     };
 
     let Some(sender_ip) = received.from_ip else {
-        return SpfOutput::default()
+        return Ok(SpfOutput::default())
     };
     let sender_host_domain = match received.from() {
-        None => return SpfOutput::default(),
+        None => return Ok(SpfOutput::default()),
         Some(Host::Name(domain)) => domain.to_string(),
         Some(Host::IpAddr(addr)) => addr.to_string(),
     };
@@ -151,17 +198,17 @@ async fn check_spf(resolver: Resolver, msg: String) -> SpfOutput {
     let helo_domain = match received.helo() {
         Some(Host::Name(domain)) => domain.to_string(),
         Some(Host::IpAddr(addr)) => addr.to_string(),
-        None => return SpfOutput::default(),
+        None => return Ok(SpfOutput::default()),
     };
 
-    resolver
+    Ok(resolver
         .verify_spf_sender(
             sender_ip,
             &helo_domain,
             &sender_host_domain,
-            &sender_email_address,
+            sender_email_address,
         )
-        .await
+        .await)
 }
 
 #[derive(Serialize)]
@@ -170,17 +217,23 @@ struct DmarcAnalysisVerdict {
     spf: String,
 }
 
-async fn verify_dmarc(resolver: Resolver, msg_string: String) -> AnalysisVerdict {
-    let spf_result = check_spf(resolver.clone(), msg_string.clone()).await;
+async fn verify_dmarc(
+    resolver: Resolver,
+    raw_msg: Vec<u8>,
+    envelope: Option<SmtpEnvelope>,
+) -> Result<AnalysisVerdict, String> {
+    let spf_result = check_spf(resolver.clone(), raw_msg.clone(), envelope.as_ref()).await?;
 
-    let msg = AuthenticatedMessage::parse(msg_string.as_bytes()).unwrap();
+    let msg = parse_authenticated_message(&raw_msg)?;
     let dkim_result = resolver.verify_dkim(&msg).await;
 
-    let sender_email_address = msg.from();
-    let sender_email_domain = sender_email_address.rsplit('@').next().unwrap();
+    let mail_from_domain = match &envelope {
+        Some(envelope) => envelope.mail_from_domain(),
+        None => msg.from().rsplit('@').next().unwrap_or_default(),
+    };
 
     let dmarc_output = resolver
-        .verify_dmarc(&msg, &dkim_result, sender_email_domain, &spf_result, |d| {
+        .verify_dmarc(&msg, &dkim_result, mail_from_domain, &spf_result, |d| {
             psl::domain_str(d).unwrap_or(d)
         })
         .await;
@@ -199,11 +252,11 @@ async fn verify_dmarc(resolver: Resolver, msg_string: String) -> AnalysisVerdict
         DmarcResult::None => "unknown",
     };
 
-    AnalysisVerdict::new(
+    Ok(AnalysisVerdict::new(
         "auth-dmarc",
         &DmarcAnalysisVerdict {
             dkim: dkim_value.to_string(),
             spf: spf_value.to_string(),
         },
-    )
+    ))
 }
@@ -0,0 +1,292 @@
+//! Golden-corpus regression tests of the analyzers.
+//!
+//! Every directory of `tests/corpus` is a fixture holding:
+//! - `email.eml`, the submitted email, read as the server reads submissions
+//! - `services.toml`, optional answers of the external services (see [`Services`])
+//! - `envelope.toml`, an optional [`SmtpEnvelope`] the email was received with
+//! - `options.toml`, optional [`AnalysisOptions`] the email is submitted with
+//! - `expected.json`, the snapshot of the verdicts and failures produced by every analyzer
+//!
+//! Run the tests with `BLESS=1` to write the snapshots instead of comparing them.
+
+mod stub;
+
+use crate::analysis::auth_checker::AuthAnalyzer;
+use crate::analysis::entity_checker::EntityChecker;
+use crate::analysis::link_checker::LinkAnalyzer;
+use crate::analysis::nlp_checker::NLPChecker;
+use crate::analysis::{
+    start_email_analysis, AnalysisOptions, AnalysisTimeouts, JobEvent, MailAnalyzer,
+};
+use crate::analysis::corpus::stub::{StubServer, STUB_VT_KEY};
+use crate::config::VirusTotalConfig;
+use crate::email::read_email;
+use crate::entity::Entity;
+use crate::http::HttpClient;
+use crate::ingestion::{JobSource, SmtpEnvelope};
+use crate::job::Job;
+use crate::secrets::{KeyRing, Secret};
+use mail_auth::common::parse::TxtRecordParser;
+use mail_auth::common::verify::DomainKey;
+use mail_auth::dmarc::Dmarc;
+use mail_auth::hickory_resolver::config::{ResolverConfig, ResolverOpts};
+use mail_auth::hickory_resolver::proto::op::ResponseCode;
+use mail_auth::spf::Spf;
+use mail_auth::{Error, Resolver, Txt};
+use rocket::async_test;
+use rocket::serde::json::{json, serde_json, Value};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const CORPUS_DIRECTORY: &str = "tests/corpus";
+
+/// Values that change from one run to another, replaced in the snapshots.
+/// Entity investigations are still a random placeholder.
+const VOLATILE_VALUES: &[(&str, &str)] = &[("entity-investigation", "/is_known_on_internet")];
+
+/// Answers of the external services for a fixture
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Services {
+    /// TXT records by name. SPF, DKIM and DMARC records are recognized by their content,
+    /// other names do not exist.
+    dns: HashMap<String, String>,
+    /// Number of engines flagging a URL or a domain, links that are not listed are clean
+    virustotal: HashMap<String, EngineStats>,
+    worker: WorkerAnswers,
+}
+
+#[derive(Deserialize, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+struct EngineStats {
+    malicious: u64,
+    suspicious: u64,
+    /// The link was never analyzed, VirusTotal does not know it
+    unknown: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct WorkerAnswers {
+    summary: String,
+    entities: Vec<Entity>,
+    /// Text found in the images, by image
+    ocr: HashMap<String, String>,
+}
+
+impl Default for WorkerAnswers {
+    fn default() -> Self {
+        Self {
+            summary: String::from("Nothing to report."),
+            entities: vec![],
+            ocr: HashMap::new(),
+        }
+    }
+}
+
+impl Services {
+    fn load(fixture: &Path) -> Self {
+        let path = fixture.join("services.toml");
+        if !path.exists() {
+            return Self::default();
+        }
+        toml::from_str(&std::fs::read_to_string(&path).unwrap())
+            .unwrap_or_else(|err| panic!("invalid {}: {err}", path.display()))
+    }
+
+    /// A resolver without any name server: the records of the fixture are cached, and the
+    /// `test` feature of mail-auth answers NXDOMAIN for every other name
+    fn resolver(&self) -> Resolver {
+        let resolver =
+            Resolver::with_capacity(ResolverConfig::new(), ResolverOpts::default(), 128).unwrap();
+        let valid_until = Instant::now() + Duration::from_secs(3600);
+
+        for (name, record) in &self.dns {
+            let bytes = record.as_bytes();
+            let txt: Txt = if record.starts_with("v=spf1") {
+                Spf::parse(bytes).map(Txt::from)
+            } else if record.starts_with("v=DMARC1") {
+                Dmarc::parse(bytes).map(Txt::from)
+            } else {
+                DomainKey::parse(bytes).map(Txt::from)
+            }
+            .unwrap_or_else(|err| panic!("invalid DNS record {name}: {err}"));
+
+            resolver.txt_add(name.as_str(), txt, valid_until);
+        }
+        resolver
+    }
+}
+
+/// Runs every analyzer over the email of a fixture and returns the snapshot of the results
+async fn analyze_fixture(fixture: &Path) -> Value {
+    let services = Services::load(fixture);
+    let resolver = services.resolver();
+    let stub = StubServer::start(services).await;
+
+    let analyzers: Vec<Arc<dyn MailAnalyzer>> = vec![
+        Arc::new(EntityChecker),
+        Arc::new(LinkAnalyzer::new(
+            VirusTotalConfig {
+                url: stub.virustotal_url(),
+            },
+            Arc::new(KeyRing::new("virustotal", vec![Secret::new(STUB_VT_KEY)])),
+            HttpClient::default(),
+        )),
+        Arc::new(AuthAnalyzer::new(resolver)),
+        Arc::new(NLPChecker::new(stub.worker_url(), HttpClient::default())),
+    ];
+    let mut remaining = analyzers.len();
+
+    let email = read_email(std::fs::read(fixture.join("email.eml")).unwrap(), false)
+        .unwrap_or_else(|| panic!("{}: not a valid email", fixture.display()));
+    let mut job = Job::new(email.email, 1);
+    job.source = load_optional::<SmtpEnvelope>(fixture, "envelope.toml").map(JobSource::Smtp);
+    job.options = load_optional::<AnalysisOptions>(fixture, "options.toml").unwrap_or_default();
+    let job = Arc::new(job);
+    let mut events = job.subscribe_events(0);
+
+    start_email_analysis(analyzers, job.clone(), &AnalysisTimeouts::default()).await;
+
+    while remaining > 0 {
+        match events.next().await {
+            Some((_, JobEvent::AnalyzerFinished(_))) => remaining -= 1,
+            Some(_) => {}
+            None => break,
+        }
+    }
+
+    snapshot(&job)
+}
+
+fn load_optional<T: DeserializeOwned>(fixture: &Path, file: &str) -> Option<T> {
+    let path = fixture.join(file);
+    let content = std::fs::read_to_string(&path).ok()?;
+    Some(
+        toml::from_str(&content)
+            .unwrap_or_else(|err| panic!("invalid {}: {err}", path.display())),
+    )
+}
+
+/// Serializes the results of a job in a stable order, without the volatile values
+fn snapshot(job: &Job) -> Value {
+    let mut verdicts: Vec<_> = job
+        .results
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|result| {
+            let mut value = result.verdict.value.clone();
+            for (kind, pointer) in VOLATILE_VALUES {
+                if result.verdict.kind == *kind {
+                    if let Some(volatile) = value.pointer_mut(pointer) {
+                        *volatile = Value::String(String::from("<volatile>"));
+                    }
+                }
+            }
+            json!({
+                "analyzer": result.analysis_name,
+                "kind": result.verdict.kind,
+                "value": value,
+            })
+        })
+        .collect();
+    verdicts.sort_by_cached_key(Value::to_string);
+
+    let mut failures: Vec<_> = job
+        .failures
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|failure| json!({"analyzer": failure.analysis_name, "message": failure.message}))
+        .collect();
+    failures.sort_by_cached_key(Value::to_string);
+
+    let mut analyzers: Vec<_> = job
+        .analyzers
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|status| json!({"analyzer": status.name, "state": status.state}))
+        .collect();
+    analyzers.sort_by_cached_key(Value::to_string);
+
+    json!({
+        "analyzers": analyzers,
+        "verdicts": verdicts,
+        "failures": failures,
+    })
+}
+
+fn fixtures() -> Vec<PathBuf> {
+    let mut fixtures: Vec<_> = std::fs::read_dir(CORPUS_DIRECTORY)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.join("email.eml").exists())
+        .collect();
+    fixtures.sort();
+    fixtures
+}
+
+#[async_test]
+async fn test_corpus() {
+    let bless = std::env::var_os("BLESS").is_some();
+    let mut mismatches = vec![];
+
+    for fixture in fixtures() {
+        let actual = analyze_fixture(&fixture).await;
+        let expected_path = fixture.join("expected.json");
+
+        if bless {
+            let mut snapshot = serde_json::to_string_pretty(&actual).unwrap();
+            snapshot.push('\n');
+            std::fs::write(&expected_path, snapshot).unwrap();
+            continue;
+        }
+
+        let expected: Value = match std::fs::read_to_string(&expected_path) {
+            Ok(expected) => serde_json::from_str(&expected).unwrap(),
+            Err(_) => {
+                mismatches.push(format!(
+                    "{}: no snapshot, run with BLESS=1 to create it",
+                    fixture.display()
+                ));
+                continue;
+            }
+        };
+
+        if actual != expected {
+            mismatches.push(format!(
+                "{}: the results differ from the snapshot, run with BLESS=1 to update it\nexpected: {}\nactual: {}",
+                fixture.display(),
+                serde_json::to_string_pretty(&expected).unwrap(),
+                serde_json::to_string_pretty(&actual).unwrap(),
+            ));
+        }
+    }
+
+    assert!(mismatches.is_empty(), "{}", mismatches.join("\n\n"));
+}
+
+#[async_test]
+async fn test_unstubbed_names_do_not_exist() {
+    let mut services = Services::default();
+    services
+        .dns
+        .insert(String::from("example.org"), String::from("v=spf1 -all"));
+    let resolver = services.resolver();
+
+    assert!(resolver.txt_lookup::<Spf>("example.org").await.is_ok());
+    assert!(matches!(
+        resolver.txt_lookup::<Spf>("example.com").await,
+        Err(Error::DnsRecordNotFound(ResponseCode::NXDomain))
+    ));
+    assert!(matches!(
+        resolver.ipv4_lookup("example.com").await,
+        Err(Error::DnsRecordNotFound(ResponseCode::NXDomain))
+    ));
+}
@@ -0,0 +1,171 @@
+use crate::analysis::corpus::Services;
+use base64::prelude::BASE64_STANDARD_NO_PAD;
+use base64::Engine;
+use rocket::serde::json::{json, serde_json, Value};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use url::Url;
+
+/// API key the VirusTotal stub expects
+pub const STUB_VT_KEY: &str = "corpus-key";
+
+/// HTTP server standing in for VirusTotal (under `/vt/`) and the language processing worker
+/// (under `/worker/`), answering with the services of a fixture.
+pub struct StubServer {
+    address: SocketAddr,
+    handle: tokio::task::JoinHandle<()>,
+}
+
+impl StubServer {
+    pub async fn start(services: Services) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let services = Arc::new(services);
+
+        let handle = tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                tokio::spawn(serve(socket, services.clone()));
+            }
+        });
+
+        Self { address, handle }
+    }
+
+    pub fn virustotal_url(&self) -> Url {
+        Url::parse(&format!("http://{}/vt/", self.address)).unwrap()
+    }
+
+    pub fn worker_url(&self) -> Url {
+        Url::parse(&format!("http://{}/worker/", self.address)).unwrap()
+    }
+}
+
+impl Drop for StubServer {
+    fn drop(&mut self) {
+        self.handle.abort()
+    }
+}
+
+struct Request {
+    method: String,
+    path: String,
+    api_key: Option<String>,
+}
+
+/// Reads a single request and answers it, closing the connection afterward
+async fn serve(socket: TcpStream, services: Arc<Services>) {
+    let mut reader = BufReader::new(socket);
+
+    let mut line = String::new();
+    if reader.read_line(&mut line).await.is_err() {
+        return;
+    }
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let path = parts.next().unwrap_or_default().to_string();
+
+    let mut content_length = 0;
+    let mut api_key = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).await.unwrap_or(0) == 0 || header == "\r\n" {
+            break;
+        }
+        if let Some((name, value)) = header.trim_end().split_once(':') {
+            match name.to_lowercase().as_str() {
+                "content-length" => content_length = value.trim().parse().unwrap_or(0),
+                "x-apikey" => api_key = Some(value.trim().to_string()),
+                _ => {}
+            }
+        }
+    }
+
+    let mut body = vec![0; content_length];
+    if reader.read_exact(&mut body).await.is_err() {
+        return;
+    }
+
+    let request = Request {
+        method,
+        path,
+        api_key,
+    };
+    let (status, content_type, body) = respond(&request, &services);
+
+    let response = format!(
+        "HTTP/1.1 {status}\r\ncontent-type: {content_type}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    let _ = reader.get_mut().write_all(response.as_bytes()).await;
+}
+
+fn respond(request: &Request, services: &Services) -> (&'static str, &'static str, String) {
+    let path = request.path.as_str();
+
+    if let Some(path) = path.strip_prefix("/vt/") {
+        if request.api_key.as_deref() != Some(STUB_VT_KEY) {
+            return ("401 Unauthorized", "application/json", String::from("{}"));
+        }
+        return match virustotal_report(path, services) {
+            Some(report) => ("200 OK", "application/json", report.to_string()),
+            None => ("404 Not Found", "application/json", String::from("{}")),
+        };
+    }
+
+    match (request.method.as_str(), path) {
+        ("POST", "/worker/ocr") => (
+            "200 OK",
+            "application/json",
+            serde_json::to_string(&services.worker.ocr).unwrap(),
+        ),
+        ("POST", "/worker/llm/analyze") => (
+            "200 OK",
+            "text/plain",
+            format!(
+                "summary:\"{}\"\nentities:{}",
+                services.worker.summary,
+                serde_json::to_string(&services.worker.entities).unwrap()
+            ),
+        ),
+        _ => ("404 Not Found", "text/plain", String::new()),
+    }
+}
+
+/// Builds the report of a URL or a domain, flagged by the number of engines the fixture gives
+fn virustotal_report(path: &str, services: &Services) -> Option<Value> {
+    let (kind, id, link) = if let Some(url64) = path.strip_prefix("urls/") {
+        let url = String::from_utf8(BASE64_STANDARD_NO_PAD.decode(url64).ok()?).ok()?;
+        ("url", url64.to_string(), url)
+    } else if let Some(domain) = path.strip_prefix("domains/") {
+        ("domain", domain.to_string(), domain.to_string())
+    } else {
+        return None;
+    };
+
+    let stats = services.virustotal.get(&link).cloned().unwrap_or_default();
+    if stats.unknown {
+        return None;
+    }
+
+    let mut attributes = json!({
+        "last_analysis_stats": {
+            "malicious": stats.malicious,
+            "suspicious": stats.suspicious,
+            "harmless": 0,
+            "undetected": 0,
+        }
+    });
+    if kind == "url" {
+        attributes["url"] = Value::String(link);
+    }
+
+    Some(json!({
+        "data": {
+            "id": id,
+            "type": kind,
+            "attributes": attributes,
+        }
+    }))
+}
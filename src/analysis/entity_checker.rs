@@ -4,6 +4,7 @@ use crate::command::AnalysisCommand;
 use crate::email::OwnedEmail;
 use crate::entity::Entity;
 use crate::pipeline::Pipeline;
+use log::debug;
 use rocket::serde::json::serde_json;
 use serde::Serialize;
 pub struct EntityChecker;
@@ -14,16 +15,25 @@ struct EntityInvestigationResult {
 }
 
 impl MailAnalyzer for EntityChecker {
+    fn id(&self) -> &'static str {
+        "entities"
+    }
+
     fn name(&self) -> String {
         String::from("Entity Investigator")
     }
 
     fn analyze(&self, _email: OwnedEmail, command: AnalysisCommand) -> AnalysisSetup {
         command.spawn_pipeline(Pipeline::once_root(|cmd: AnalysisCommand| async move {
-            cmd.catch_all_verdicts("entity").await
-                .map(|v| serde_json::from_value::<Entity>(v.value).unwrap())
+            cmd.catch_all_verdicts("entity")
+                .map(|v| serde_json::from_value::<Entity>(v.value))
                 //execute the analysis using spawn here to let the command announce the analyse_entity task
-                .for_each(|e| async { cmd.spawn(analyse_entity(e)) })
+                .for_each(|e| async {
+                    match e {
+                        Ok(e) => cmd.spawn(analyse_entity(e)),
+                        Err(err) => cmd.error(format!("invalid entity: {err}")),
+                    }
+                })
                 .await;
         }));
 
@@ -32,7 +42,7 @@ impl MailAnalyzer for EntityChecker {
 }
 
 async fn analyse_entity(entity: Entity) -> AnalysisVerdict {
-    println!("Analyse entity : {entity:?}");
+    debug!("Analyse entity : {entity:?}");
     AnalysisVerdict::new(
         "entity-investigation",
         EntityInvestigationResult {
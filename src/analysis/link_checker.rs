@@ -1,31 +1,59 @@
 use crate::analysis::{AnalysisSetup, AnalysisVerdict, MailAnalyzer};
 use crate::command::AnalysisCommand;
+use crate::config::VirusTotalConfig;
 use crate::email::OwnedEmail;
 use crate::entity::Entity;
+use crate::http::{HttpClient, HttpError};
+use crate::secrets::KeyRing;
 use async_trait::async_trait;
 use base64::prelude::BASE64_STANDARD_NO_PAD;
 use base64::Engine;
 use mail_parser::{Address, Message};
 use regex::bytes::Regex;
-use reqwest::{Client, Response, StatusCode};
+use log::warn;
+use reqwest::{RequestBuilder, Response, StatusCode};
 use rocket::serde::json::serde_json;
 use serde::{Deserialize, Serialize};
 use std::collections::hash_map::Entry;
 use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 use url::Url;
 
-pub struct LinkAnalyzer;
+pub struct LinkAnalyzer {
+    virustotal: VirusTotalConfig,
+    keys: Arc<KeyRing>,
+    http: HttpClient,
+}
+
+impl LinkAnalyzer {
+    pub fn new(virustotal: VirusTotalConfig, keys: Arc<KeyRing>, http: HttpClient) -> Self {
+        Self {
+            virustotal,
+            keys,
+            http,
+        }
+    }
+}
 
 #[async_trait]
 impl MailAnalyzer for LinkAnalyzer {
+    fn id(&self) -> &'static str {
+        "links"
+    }
+
     fn name(&self) -> String {
         String::from("Links analysis")
     }
 
+    fn options(&self) -> &'static [&'static str] {
+        // whether URLs unknown to VirusTotal are submitted, which shares them publicly,
+        // and whether the links are looked up again later, see crate::recheck
+        &["submit_unknown", "recheck"]
+    }
+
     fn analyze(&self, email: OwnedEmail, command: AnalysisCommand) -> AnalysisSetup {
         let email = email.parse();
-        
-        let client = Client::new();
+        let submit_unknown = command.option("submit_unknown").unwrap_or(true);
 
         let (urls, domains) = collect_all_links(email);
         
@@ -39,21 +67,31 @@ impl MailAnalyzer for LinkAnalyzer {
             })
         }
 
+        if self.keys.is_empty() {
+            command.error("no VirusTotal API key is configured");
+            return command.validate();
+        }
+
+        let virustotal = VirusTotal {
+            http: self.http.clone(),
+            url: self.virustotal.url.clone(),
+            keys: self.keys.clone(),
+        };
+
         for (url, tags) in urls {
-            let client = client.clone();
-            command.spawn(analyze_url(
+            command.try_spawn(analyze_url(
                 url.to_string(),
-                tags.into_iter().collect(),
-                client,
+                sorted_tags(tags),
+                virustotal.clone(),
+                submit_unknown,
             ));
         }
 
         for (domain, tags) in domains {
-            let client = client.clone();
-            command.spawn(analyze_domain(
+            command.try_spawn(analyze_domain(
                 domain.to_string(),
-                tags.into_iter().collect(),
-                client,
+                sorted_tags(tags),
+                virustotal.clone(),
             ));
         }
         command.validate()
@@ -69,7 +107,9 @@ fn collect_all_links(email: Message<'_>) -> (LinkTags, LinkTags) {
     let mut domains: HashMap<String, HashSet<String>> = HashMap::new();
 
     for part in email.text_bodies() {
-        let text = part.text_contents().unwrap();
+        let Some(text) = part.text_contents() else {
+            continue;
+        };
 
         for url_match in link_regex.captures_iter(text.as_ref()).map(|c| c.get(0)) {
             let url_match = url_match.unwrap();
@@ -78,8 +118,13 @@ fn collect_all_links(email: Message<'_>) -> (LinkTags, LinkTags) {
                 .unwrap()
                 .to_string();
 
-            let url = Url::parse(&url_str).unwrap();
-            let domain = url.domain().unwrap();
+            // links to an IP address have no domain to check
+            let Ok(url) = Url::parse(&url_str) else {
+                continue;
+            };
+            let Some(domain) = url.domain() else {
+                continue;
+            };
             
             insert_and_tag(&mut urls, &url_str, "body");
             insert_and_tag(&mut domains, domain, "body");
@@ -87,16 +132,21 @@ fn collect_all_links(email: Message<'_>) -> (LinkTags, LinkTags) {
         }
     }
 
-    let addresses = match email.from().unwrap() {
-        Address::List(l) => l.clone(),
-        Address::Group(l) => l.iter().flat_map(|g| g.addresses.clone()).collect(),
+    let addresses = match email.from() {
+        Some(Address::List(l)) => l.clone(),
+        Some(Address::Group(l)) => l.iter().flat_map(|g| g.addresses.clone()).collect(),
+        None => vec![],
     };
 
     for address in addresses {
-        let address = address.address().unwrap();
+        let Some(address) = address.address() else {
+            continue;
+        };
         match Url::try_from(address) {
             Ok(url) => {
-                let domain = url.domain().unwrap();
+                let Some(domain) = url.domain() else {
+                    continue;
+                };
                 insert_and_tag(&mut urls, address, "sender");
                 insert_and_tag(&mut domains, domain, "sender");
                 insert_and_tag(&mut domains, &get_top_domain(domain), "deducted");
@@ -115,7 +165,7 @@ fn collect_all_links(email: Message<'_>) -> (LinkTags, LinkTags) {
 
 fn get_top_domain(fqdn: &str) -> String {
     let fqdn_items = fqdn.split('.').collect::<Vec<_>>();
-    fqdn_items[fqdn_items.len() - 2..].join(".")
+    fqdn_items[fqdn_items.len().saturating_sub(2)..].join(".")
 }
 
 fn insert_and_tag(map: &mut HashMap<String, HashSet<String>>, url: &str, tag: &str) {
@@ -129,6 +179,12 @@ fn insert_and_tag(map: &mut HashMap<String, HashSet<String>>, url: &str, tag: &s
     };
 }
 
+fn sorted_tags(tags: HashSet<String>) -> Vec<String> {
+    let mut tags: Vec<_> = tags.into_iter().collect();
+    tags.sort();
+    tags
+}
+
 struct TaggedLink {
     link: String,
     tags: Vec<String>,
@@ -144,59 +200,110 @@ struct LinkAnalalysis {
 
 #[derive(Serialize)]
 struct LinkAnalysisVerdict {
+    /// The analyzed URL or domain
+    link: String,
     tags: Vec<String>,
     /// The VT Report Response
     report: serde_json::Value,
 }
 
-const VT_KEY: &str = "44a1be194e97364ce779c6cde6aa0df72e7dcf6940db213cafbafac44c2ba9e4";
+#[derive(Clone)]
+struct VirusTotal {
+    http: HttpClient,
+    url: Url,
+    keys: Arc<KeyRing>,
+}
+
+impl VirusTotal {
+    async fn get(&self, path: &str) -> Result<Response, HttpError> {
+        self.send(|| self.http.get(format!("{}{path}", self.url))).await
+    }
 
-async fn analyze_url(url: String, tags: Vec<String>, client: Client) -> AnalysisVerdict {
-    let response = request_url_analysis(&url, &client).await;
-    let mut response = match response {
-        Ok(response) => response,
-        Err(err) => {
-            return AnalysisVerdict::error(&vec![format!("Error url analysis `{url}`: {err:?}")])
+    async fn post_form(&self, path: &str, form: &[(&str, &str)]) -> Result<Response, HttpError> {
+        self.send(|| self.http.post(format!("{}{path}", self.url)).form(form))
+            .await
+    }
+
+    /// Sends a request with the next API key, rotating to the other keys while the key is
+    /// rejected (revoked or out of quota).
+    async fn send(&self, request: impl Fn() -> RequestBuilder) -> Result<Response, HttpError> {
+        let mut attempts = self.keys.len().max(1);
+        loop {
+            let key = self.keys.key().unwrap_or_default();
+            let response = self
+                .http
+                .send(request().header("x-apikey", key.expose()))
+                .await?;
+
+            let is_rejected = matches!(
+                response.status(),
+                StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN | StatusCode::TOO_MANY_REQUESTS
+            );
+
+            attempts -= 1;
+            if !is_rejected || attempts == 0 {
+                return Ok(response);
+            }
+
+            warn!("VirusTotal rejected a key with status {}", response.status());
+            self.keys.reject(&key);
         }
-    };
+    }
+}
+
+async fn analyze_url(
+    url: String,
+    tags: Vec<String>,
+    virustotal: VirusTotal,
+    submit_unknown: bool,
+) -> Result<AnalysisVerdict, String> {
+    let mut response = request_url_analysis(&url, &virustotal)
+        .await
+        .map_err(|err| format!("Error url analysis `{url}`: {err:?}"))?;
+
+    if response.status() == StatusCode::NOT_FOUND && !submit_unknown {
+        let report = serde_json::Value::Null;
+        let verdict = LinkAnalysisVerdict {
+            link: url,
+            tags,
+            report,
+        };
+        return Ok(AnalysisVerdict::new("url", &verdict));
+    }
 
     //if no analysis is found, request a new one to VT and wait, then try again
     if response.status() == StatusCode::NOT_FOUND {
-        submit_url_analysis(&url, &client).await;
-
-        response = match request_url_analysis(&url, &client).await {
-            Ok(response) => response,
-            Err(err) => {
-                return AnalysisVerdict::error(&vec![format!(
-                    "Error url analysis `{url}`: {err:?}"
-                )])
-            }
-        };
+        submit_url_analysis(&url, &virustotal)
+            .await
+            .map_err(|err| format!("Error url analysis submission `{url}`: {err:?}"))?;
+
+        response = request_url_analysis(&url, &virustotal)
+            .await
+            .map_err(|err| format!("Error url analysis `{url}`: {err:?}"))?;
+
         if response.status() == StatusCode::NOT_FOUND {
-            return AnalysisVerdict::error(&"404 Not Found");
+            return Err(format!("Error url analysis `{url}`: 404 Not Found"));
         }
     }
 
-    let content = response.text().await;
-    match content {
-        Ok(content) => AnalysisVerdict::new(
-            "url",
-            &LinkAnalysisVerdict {
-                tags,
-                report: serde_json::from_str(&content).unwrap(),
-            },
-        ),
-        Err(err) => AnalysisVerdict::error(&vec![format!("Error url analysis `{url}`: {err:?}")]),
-    }
+    let report = response
+        .json()
+        .await
+        .map_err(|err| format!("Error url analysis `{url}`: {err:?}"))?;
+
+    Ok(AnalysisVerdict::new(
+        "url",
+        &LinkAnalysisVerdict {
+            link: url,
+            tags,
+            report,
+        },
+    ))
 }
 
-async fn request_url_analysis(url: &str, client: &Client) -> Result<Response, reqwest::Error> {
+async fn request_url_analysis(url: &str, virustotal: &VirusTotal) -> Result<Response, HttpError> {
     let url64 = BASE64_STANDARD_NO_PAD.encode(url);
-    client
-        .get(format!("https://www.virustotal.com/api/v3/urls/{url64}"))
-        .header("x-apikey", VT_KEY)
-        .send()
-        .await
+    virustotal.get(&format!("urls/{url64}")).await
 }
 
 #[derive(Deserialize)]
@@ -224,19 +331,12 @@ struct AnalysisSubmitResponseData {
     id: String,
 }
 
-async fn submit_url_analysis(url: &str, client: &Client) {
-    let response = client
-        .post("https://www.virustotal.com/api/v3/urls".to_string())
-        .header("x-apikey", VT_KEY)
-        .form(&[("url", url)])
-        .send()
-        .await
-        .unwrap();
+async fn submit_url_analysis(url: &str, virustotal: &VirusTotal) -> Result<(), HttpError> {
+    let response = virustotal.post_form("urls", &[("url", url)]).await?;
 
     let analysis_id = response
         .json::<AnalysisSubmitResponse>()
-        .await
-        .unwrap()
+        .await?
         .data
         .id;
 
@@ -244,52 +344,72 @@ async fn submit_url_analysis(url: &str, client: &Client) {
     loop {
         tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
 
-        let response = client
-            .get(format!(
-                "https://www.virustotal.com/api/v3/analyses/{analysis_id}"
-            ))
-            .header("x-apikey", VT_KEY)
-            .send()
-            .await
-            .unwrap();
+        let response = virustotal.get(&format!("analyses/{analysis_id}")).await?;
 
-        let response = response.json::<AnalysisResponse>().await.unwrap();
+        let response = response.json::<AnalysisResponse>().await?;
 
         if response.data.attributes.status == "completed" {
-            break;
+            return Ok(());
         }
     }
 }
 
-async fn analyze_domain(domain: String, tags: Vec<String>, client: Client) -> AnalysisVerdict {
-    let response = client
-        .get(format!(
-            "https://www.virustotal.com/api/v3/domains/{domain}"
-        ))
-        .header("x-apikey", VT_KEY)
-        .send()
-        .await;
-
-    let response = match response {
-        Ok(response) => response,
-        Err(err) => {
-            return AnalysisVerdict::error(&vec![format!(
-                "Error domain analysis `{domain}`: {err:?}"
-            )])
-        }
-    };
+async fn analyze_domain(domain: String, tags: Vec<String>, virustotal: VirusTotal) -> Result<AnalysisVerdict, String> {
+    let report = virustotal
+        .get(&format!("domains/{domain}"))
+        .await
+        .map_err(|err| format!("Error domain analysis `{domain}`: {err:?}"))?
+        .json()
+        .await
+        .map_err(|err| format!("Error domain analysis `{domain}`: {err:?}"))?;
+
+    Ok(AnalysisVerdict::new(
+        "domain",
+        &LinkAnalysisVerdict {
+            link: domain,
+            tags,
+            report,
+        },
+    ))
+}
 
-    let content = response.text().await;
-    match content {
-        Ok(content) => AnalysisVerdict::new(
-            "domain",
-            &&LinkAnalysisVerdict {
-                tags,
-                report: serde_json::from_str(&content).unwrap(),
+/// Looks up the links of analyzed emails again, see [`crate::recheck`]
+pub struct LinkRechecker {
+    virustotal: VirusTotal,
+}
+
+impl LinkRechecker {
+    pub fn new(virustotal: VirusTotalConfig, keys: Arc<KeyRing>, http: HttpClient) -> Self {
+        Self {
+            virustotal: VirusTotal {
+                http,
+                url: virustotal.url,
+                keys,
             },
-        ),
-        Err(err) => {
-            AnalysisVerdict::error(&vec![format!("Error domain analysis `{domain}`: {err:?}")])
         }
     }
+
+    /// Returns the current VirusTotal report of a `url` or a `domain`, null if the URL is unknown.
+    /// With `rescan`, the URL is scanned again first, which shares it with VirusTotal.
+    pub async fn report(
+        &self,
+        kind: &str,
+        link: &str,
+        rescan: bool,
+    ) -> Result<serde_json::Value, String> {
+        if kind == "domain" {
+            return analyze_domain(link.to_string(), vec![], self.virustotal.clone())
+                .await
+                .map(|verdict| verdict.value["report"].clone());
+        }
+
+        if rescan {
+            submit_url_analysis(link, &self.virustotal)
+                .await
+                .map_err(|err| format!("Error url analysis submission `{link}`: {err:?}"))?;
+        }
+        analyze_url(link.to_string(), vec![], self.virustotal.clone(), false)
+            .await
+            .map(|verdict| verdict.value["report"].clone())
+    }
 }
@@ -1,30 +1,55 @@
 use crate::analysis::{AnalysisSetup, AnalysisVerdict, MailAnalyzer};
 use crate::command::AnalysisCommand;
 use crate::email::OwnedEmail;
+use crate::http::HttpClient;
 use crate::pipeline::Pipeline;
 use lazy_static::lazy_static;
+use log::warn;
 use mail_parser::Message;
 use regex::Regex;
-use reqwest::Client;
 use rocket::futures::StreamExt;
 use std::collections::HashMap;
 use std::sync::Arc;
 use rocket::serde::json::serde_json;
 use tl::Node;
+use url::Url;
 use crate::entity::Entity;
 
-pub struct NLPChecker;
+pub struct NLPChecker {
+    /// Base URL of the language processing worker
+    worker_url: Url,
+    http: HttpClient,
+}
+
+impl NLPChecker {
+    pub fn new(worker_url: Url, http: HttpClient) -> Self {
+        Self { worker_url, http }
+    }
+}
 
 impl MailAnalyzer for NLPChecker {
+    fn id(&self) -> &'static str {
+        "nlp"
+    }
+
     fn name(&self) -> String {
         String::from("Natural Language Processing Analysis")
     }
 
     fn analyze(&self, email: OwnedEmail, command: AnalysisCommand) -> AnalysisSetup {
+        let ocr_url = self.worker_url.join("ocr").unwrap();
+        let llm_url = self.worker_url.join("llm/analyze").unwrap();
+        let ocr_http = self.http.clone();
+        let llm_http = self.http.clone();
+
         command.spawn_pipeline(
-            Pipeline::once_root(move |_: AnalysisCommand| extract_all_text(email))
-                .next_fn(|text, _| analyze_text(text))
+            Pipeline::once_root(move |_: AnalysisCommand| extract_all_text(email, ocr_url, ocr_http))
+                .next_fn(move |text, _| analyze_text(text, llm_url, llm_http))
                 .next_fn(|llm_result, c| async move {
+                    let llm_result = match llm_result.as_ref() {
+                        Ok(llm_result) => llm_result,
+                        Err(err) => return c.error(err.clone()),
+                    };
                     c.result(AnalysisVerdict::new("nlp-summary", &llm_result.summary));
                     for entity in &llm_result.entities {
                         c.submit_entity(entity)
@@ -40,25 +65,27 @@ struct LLMAnalysisResponse {
     entities: Vec<Entity>,
 }
 
-async fn analyze_text(text: Arc<String>) -> LLMAnalysisResponse {
-    match make_llm_request(text).await {
-        Ok((summary, entities)) => LLMAnalysisResponse {
-            summary,
-            entities: serde_json::from_str(&entities).unwrap(),
-        },
-        //TODO handle error in a better way (ew)
-        Err(err) => LLMAnalysisResponse {
-            summary: err.clone(),
-            entities: vec![],
-        },
-    }
+async fn analyze_text(
+    text: Arc<String>,
+    llm_url: Url,
+    http: HttpClient,
+) -> Result<LLMAnalysisResponse, String> {
+    let (summary, entities) = make_llm_request(text, llm_url, http).await?;
+
+    Ok(LLMAnalysisResponse {
+        summary,
+        entities: serde_json::from_str(&entities)
+            .map_err(|e| format!("invalid entities returned by LLM server: {}", e))?,
+    })
 }
 
-async fn make_llm_request(text: Arc<String>) -> Result<(String, String), String> {
-    let response = Client::new()
-        .post("http://localhost:8080/llm/analyze")
-        .body(text.to_string())
-        .send()
+async fn make_llm_request(
+    text: Arc<String>,
+    llm_url: Url,
+    http: HttpClient,
+) -> Result<(String, String), String> {
+    let response = http
+        .send(http.post(llm_url).body(text.to_string()))
         .await
         .map_err(|e| format!("error when requesting LLM server : {}", e))?;
 
@@ -69,33 +96,33 @@ async fn make_llm_request(text: Arc<String>) -> Result<(String, String), String>
 
     let summary_regex = Regex::new(r#"^summary:"([^"]*)"\nentities:([\s\S]*)$"#).unwrap();
 
-    let captures = summary_regex.captures(&result).unwrap();
+    let captures = summary_regex
+        .captures(&result)
+        .ok_or_else(|| format!("unexpected LLM server response: {}", result))?;
 
     let summary = String::from(captures.get(1).unwrap().as_str());
     let entities = String::from(captures.get(2).unwrap().as_str());
     Ok((summary, entities))
 }
 
-async fn extract_all_text(message: OwnedEmail) -> String {
+async fn extract_all_text(message: OwnedEmail, ocr_url: Url, http: HttpClient) -> String {
     let ExtractedBodyInformation { text, images, .. } =
         extract_body_information(message.parse()).await;
 
-    let ocr_response = Client::new()
-        .post("http://localhost:8080/ocr")
-        .body(images.join("\n"))
-        .send()
+    let ocr_response = http
+        .send(http.post(ocr_url).body(images.join("\n")))
         .await;
 
     match ocr_response {
         Err(e) => {
-            println!("ocr error: {e:?}");
+            warn!("ocr error: {e:?}");
             text
         }
         Ok(response) => {
             let mut buff = text;
 
             if !response.status().is_success() {
-                println!("ocr response is not 200: {}", response.status());
+                warn!("ocr response is not 200: {}", response.status());
                 return buff;
             }
 
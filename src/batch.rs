@@ -0,0 +1,264 @@
+mod archive;
+
+use crate::analysis::{AnalysisOptions, AnalysisTimeouts, MailAnalyzer};
+use crate::batch::archive::split_archive;
+use crate::email::{read_email, SubmittedEmail};
+use crate::job::{Job, JobState};
+use crate::scoring::RiskAssessment;
+use crate::state::{JobAddition, Jobs};
+use crate::submission::start_added_job;
+use chrono::{DateTime, Utc};
+use rocket::futures::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// A set of jobs created from the messages of a single mbox file or zip archive
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Batch {
+    pub id: usize,
+    pub created_at: DateTime<Utc>,
+    pub jobs: Vec<BatchJob>,
+    /// Messages of the archive for which no job was created
+    pub rejected: Vec<RejectedMessage>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BatchJob {
+    pub job_id: usize,
+    /// Position of the message in the archive
+    pub index: usize,
+    /// Path of the file in the archive, mbox messages have none
+    pub name: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RejectedMessage {
+    pub index: usize,
+    pub name: Option<String>,
+    pub reason: String,
+}
+
+/// Bounds of the archives accepted for bulk submission
+#[derive(Debug, Clone)]
+pub struct BatchLimits {
+    pub max_messages: usize,
+    /// Maximum size of a message, in bytes
+    pub max_message_size: u64,
+    /// Maximum size of the messages extracted from a zip archive, in bytes
+    pub max_total_size: u64,
+    /// Number of jobs of a batch analyzed at the same time
+    pub concurrency: usize,
+}
+
+impl Default for BatchLimits {
+    fn default() -> Self {
+        Self {
+            max_messages: 1000,
+            max_message_size: 25 * 1024 * 1024,
+            max_total_size: 500 * 1024 * 1024,
+            concurrency: 4,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum BatchError {
+    Std(Box<dyn std::error::Error + Send + Sync>),
+    UnsupportedFormat,
+    TooManyMessages(usize),
+    TooLarge(u64),
+    NoValidEmail,
+}
+
+impl<E: std::error::Error + Send + Sync + 'static> From<E> for BatchError {
+    fn from(value: E) -> Self {
+        BatchError::Std(Box::new(value))
+    }
+}
+
+impl Display for BatchError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BatchError::Std(err) => write!(f, "{err}"),
+            BatchError::UnsupportedFormat => write!(f, "not an mbox file or a zip archive"),
+            BatchError::TooManyMessages(count) => write!(f, "too many messages ({count})"),
+            BatchError::TooLarge(size) => {
+                write!(f, "the extracted messages are larger than {size} bytes")
+            }
+            BatchError::NoValidEmail => write!(f, "no valid email in the archive"),
+        }
+    }
+}
+
+/// Splits an mbox file or a zip archive into its messages, and creates one job per email.
+///
+/// The jobs are created at once, but only `limits.concurrency` of them are analyzed at the same
+/// time. Messages that can't be read as emails are listed as rejected.
+/// Every email is analyzed with the same `options`, and is a user report if `reported` is set.
+pub async fn submit_batch(
+    jobs: &Arc<Mutex<Jobs>>,
+    archive: &[u8],
+    reported: bool,
+    limits: &BatchLimits,
+    options: &AnalysisOptions,
+    analyzers: &[Arc<dyn MailAnalyzer>],
+    timeouts: &AnalysisTimeouts,
+) -> Result<Arc<Batch>, BatchError> {
+    let mut emails = vec![];
+    let mut rejected = vec![];
+
+    for (index, message) in split_archive(archive, limits)?.into_iter().enumerate() {
+        let email = message
+            .content
+            .and_then(|content| read_email(content, reported).ok_or_else(|| String::from("not a valid email")));
+        match email {
+            Ok(email) => emails.push((
+                index,
+                message.name,
+                SubmittedEmail {
+                    options: options.clone(),
+                    ..email
+                },
+            )),
+            Err(reason) => rejected.push(RejectedMessage {
+                index,
+                name: message.name,
+                reason,
+            }),
+        }
+    }
+
+    if emails.is_empty() {
+        return Err(BatchError::NoValidEmail);
+    }
+
+    let (batch, batch_jobs) = {
+        let mut jobs = jobs.lock().await;
+
+        let mut batch_jobs = vec![];
+        let mut entries = vec![];
+        for (index, name, email) in emails {
+            let added = jobs.add_job(email);
+            entries.push(BatchJob {
+                job_id: added.job().id,
+                index,
+                name,
+            });
+            batch_jobs.push(added);
+        }
+
+        (jobs.add_batch(entries, rejected), batch_jobs)
+    };
+
+    tokio::spawn(run_batch(
+        jobs.clone(),
+        batch_jobs,
+        limits.concurrency,
+        analyzers.to_vec(),
+        timeouts.clone(),
+    ));
+
+    Ok(batch)
+}
+
+/// Starts the jobs of a batch, waiting for a job to complete before starting the next one
+/// once `concurrency` jobs are running
+async fn run_batch(
+    jobs: Arc<Mutex<Jobs>>,
+    batch_jobs: Vec<JobAddition>,
+    concurrency: usize,
+    analyzers: Vec<Arc<dyn MailAnalyzer>>,
+    timeouts: AnalysisTimeouts,
+) {
+    stream::iter(batch_jobs)
+        .for_each_concurrent(concurrency.max(1), |added| {
+            let jobs = &jobs;
+            let analyzers = &analyzers;
+            let timeouts = &timeouts;
+            async move {
+                start_added_job(jobs, &added, analyzers, timeouts);
+
+                let mut events = added.job().subscribe_events(0);
+                while events.next().await.is_some() {}
+            }
+        })
+        .await;
+}
+
+/// Aggregate progress of a batch, as served by the `/batch/<id>` route.
+/// Deleted jobs are left out.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BatchDescription {
+    pub id: usize,
+    pub created_at: DateTime<Utc>,
+    pub total: usize,
+    pub complete: usize,
+    pub failed: usize,
+    pub deleted: usize,
+    pub is_complete: bool,
+    pub jobs: Vec<BatchJobSummary>,
+    pub rejected: Vec<RejectedMessage>,
+}
+
+/// Outcome of the analysis of one message of a batch
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BatchJobSummary {
+    pub job_id: usize,
+    pub index: usize,
+    pub name: Option<String>,
+    pub subject: Option<String>,
+    pub state: JobState,
+    pub is_complete: bool,
+    pub risk: Option<RiskAssessment>,
+}
+
+impl BatchDescription {
+    pub fn new(batch: &Batch, jobs: &Jobs) -> Self {
+        let summaries: Vec<_> = batch
+            .jobs
+            .iter()
+            .filter_map(|entry| {
+                let job = jobs.find_job(entry.job_id)?;
+                Some(BatchJobSummary::new(entry, &job))
+            })
+            .collect();
+
+        let complete = summaries.iter().filter(|s| s.is_complete).count();
+
+        Self {
+            id: batch.id,
+            created_at: batch.created_at,
+            total: summaries.len(),
+            complete,
+            failed: summaries
+                .iter()
+                .filter(|s| matches!(s.state, JobState::Error(_)))
+                .count(),
+            deleted: batch.jobs.len() - summaries.len(),
+            is_complete: complete == summaries.len(),
+            jobs: summaries,
+            rejected: batch.rejected.clone(),
+        }
+    }
+}
+
+impl BatchJobSummary {
+    pub fn new(entry: &BatchJob, job: &Job) -> Self {
+        Self {
+            job_id: job.id,
+            index: entry.index,
+            name: entry.name.clone(),
+            subject: job.email().subject().map(ToOwned::to_owned),
+            state: job.state.lock().unwrap().clone(),
+            is_complete: job.is_complete(),
+            risk: job.risk.lock().unwrap().clone(),
+        }
+    }
+}
@@ -0,0 +1,242 @@
+//! Splitting of mailbox exports and archives into their messages.
+
+use crate::batch::{BatchError, BatchLimits};
+use std::io::{Cursor, Read};
+use zip::ZipArchive;
+
+const ZIP_SIGNATURE: &[u8] = b"PK\x03\x04";
+const MBOX_SEPARATOR: &[u8] = b"From ";
+
+/// Extensions of the archived files read as emails
+const EMAIL_EXTENSIONS: &[&str] = &["eml", "msg"];
+
+/// A message of an archive, or the reason it could not be read
+pub struct ArchivedMessage {
+    /// Path of the file in the archive, mbox messages have none
+    pub name: Option<String>,
+    pub content: Result<Vec<u8>, String>,
+}
+
+/// Splits an mbox file or a zip archive of `.eml` and `.msg` files into its messages
+pub fn split_archive(archive: &[u8], limits: &BatchLimits) -> Result<Vec<ArchivedMessage>, BatchError> {
+    let messages = if archive.starts_with(ZIP_SIGNATURE) {
+        split_zip(archive, limits)?
+    } else if archive.starts_with(MBOX_SEPARATOR) {
+        split_mbox(archive, limits)
+    } else {
+        return Err(BatchError::UnsupportedFormat);
+    };
+
+    if messages.len() > limits.max_messages {
+        return Err(BatchError::TooManyMessages(messages.len()));
+    }
+
+    Ok(messages)
+}
+
+/// Splits an mbox file at its `From ` separator lines.
+///
+/// Separators are only recognized at the start of the file or after an empty line, and the
+/// `>From ` lines escaped by the mboxrd format are restored.
+fn split_mbox(mbox: &[u8], limits: &BatchLimits) -> Vec<ArchivedMessage> {
+    let mut messages = vec![];
+    let mut current: Option<Vec<u8>> = None;
+    let mut after_empty_line = true;
+
+    for line in mbox.split_inclusive(|byte| *byte == b'\n') {
+        let is_empty = line == b"\n" || line == b"\r\n";
+
+        if after_empty_line && line.starts_with(MBOX_SEPARATOR) {
+            messages.extend(current.take().map(|message| mbox_message(message, limits)));
+            current = Some(vec![]);
+            after_empty_line = false;
+            continue;
+        }
+        after_empty_line = is_empty;
+
+        let Some(message) = current.as_mut() else {
+            continue;
+        };
+        let unescaped = line.iter().position(|byte| *byte != b'>');
+        match unescaped {
+            Some(start) if start > 0 && line[start..].starts_with(MBOX_SEPARATOR) => {
+                message.extend_from_slice(&line[1..])
+            }
+            _ => message.extend_from_slice(line),
+        }
+    }
+    messages.extend(current.map(|message| mbox_message(message, limits)));
+
+    messages
+}
+
+/// Removes the empty line separating a message from the next one
+fn mbox_message(mut message: Vec<u8>, limits: &BatchLimits) -> ArchivedMessage {
+    if message.ends_with(b"\r\n\r\n") {
+        message.truncate(message.len() - 2);
+    } else if message.ends_with(b"\n\n") {
+        message.truncate(message.len() - 1);
+    }
+
+    let content = if message.len() as u64 > limits.max_message_size {
+        Err(too_large(limits))
+    } else {
+        Ok(message)
+    };
+
+    ArchivedMessage {
+        name: None,
+        content,
+    }
+}
+
+/// Reads the `.eml` and `.msg` files of a zip archive, other files are reported as rejected.
+///
+/// The number of files is checked before any of them is extracted, and the extraction stops
+/// once the extracted files exceed `limits.max_total_size`.
+fn split_zip(archive: &[u8], limits: &BatchLimits) -> Result<Vec<ArchivedMessage>, BatchError> {
+    let mut archive = ZipArchive::new(Cursor::new(archive))?;
+
+    let mut files = 0;
+    for name in archive.file_names() {
+        let name = name?;
+        if !name.ends_with('/') && !is_metadata(&name) {
+            files += 1;
+        }
+    }
+    if files > limits.max_messages {
+        return Err(BatchError::TooManyMessages(files));
+    }
+
+    let mut messages = vec![];
+    let mut extracted = 0;
+
+    for i in 0..archive.len() {
+        let file = archive.by_index(i)?;
+        let name = file.name()?.into_owned();
+
+        if file.is_dir() || is_metadata(&name) {
+            continue;
+        }
+
+        let is_email = name
+            .rsplit_once('.')
+            .is_some_and(|(_, extension)| EMAIL_EXTENSIONS.contains(&extension.to_lowercase().as_str()));
+
+        let content = if !is_email {
+            Err(String::from("not an .eml or .msg file"))
+        } else if file.size() > limits.max_message_size {
+            Err(too_large(limits))
+        } else {
+            // the declared size can't be trusted, never read more than the limits
+            let limit = limits.max_message_size.min(limits.max_total_size - extracted);
+            let mut content = vec![];
+            match file.take(limit + 1).read_to_end(&mut content) {
+                Ok(read) => {
+                    extracted += read as u64;
+                    if extracted > limits.max_total_size {
+                        return Err(BatchError::TooLarge(limits.max_total_size));
+                    }
+                    if read as u64 > limits.max_message_size {
+                        Err(too_large(limits))
+                    } else {
+                        Ok(content)
+                    }
+                }
+                Err(err) => Err(format!("could not extract the file: {err}")),
+            }
+        };
+
+        messages.push(ArchivedMessage {
+            name: Some(name),
+            content,
+        });
+    }
+
+    Ok(messages)
+}
+
+/// Whether a file of a zip archive is metadata added by macOS when compressing files
+fn is_metadata(name: &str) -> bool {
+    name.starts_with("__MACOSX/") || name.rsplit('/').next().is_some_and(|file| file.starts_with("._"))
+}
+
+fn too_large(limits: &BatchLimits) -> String {
+    format!("larger than {} bytes", limits.max_message_size)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::batch::archive::split_archive;
+    use crate::batch::{BatchError, BatchLimits};
+    use std::io::{Cursor, Write};
+    use zip::write::SimpleFileOptions;
+    use zip::ZipWriter;
+
+    #[test]
+    fn test_split_mbox() {
+        let mbox = b"From alice@example.org Mon Jan  6 10:00:00 2025\n\
+            Subject: 1\n\n\
+            first\n\
+            >From here on, it's escaped\n\n\
+            From bob@example.org Mon Jan  6 11:00:00 2025\n\
+            Subject: 2\n\n\
+            second\n\
+            From the middle of a paragraph\n";
+
+        let messages = split_archive(mbox, &BatchLimits::default()).unwrap();
+        let messages: Vec<_> = messages.into_iter().map(|m| m.content.unwrap()).collect();
+        assert_eq!(
+            messages,
+            [
+                b"Subject: 1\n\nfirst\nFrom here on, it's escaped\n".to_vec(),
+                b"Subject: 2\n\nsecond\nFrom the middle of a paragraph\n".to_vec(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_zip() {
+        let mut zip = ZipWriter::new(Cursor::new(vec![]));
+        for (name, content) in [
+            ("reports/1.eml", "Subject: 1\r\n\r\nfirst"),
+            ("reports/notes.txt", "not an email"),
+            ("__MACOSX/reports/._1.eml", "metadata"),
+            ("reports/2.EML", "Subject: 2\r\n\r\nsecond, too large"),
+        ] {
+            zip.start_file(name, SimpleFileOptions::default()).unwrap();
+            zip.write_all(content.as_bytes()).unwrap();
+        }
+        let zip = zip.finish().unwrap().into_inner();
+
+        let limits = BatchLimits {
+            max_message_size: 20,
+            ..BatchLimits::default()
+        };
+        let messages = split_archive(&zip, &limits).unwrap();
+
+        let names: Vec<_> = messages.iter().map(|m| m.name.as_deref().unwrap()).collect();
+        assert_eq!(names, ["reports/1.eml", "reports/notes.txt", "reports/2.EML"]);
+        assert_eq!(messages[0].content.as_deref().unwrap(), b"Subject: 1\r\n\r\nfirst");
+        assert!(messages[1].content.is_err());
+        assert!(messages[2].content.is_err());
+
+        let limits = BatchLimits {
+            max_messages: 2,
+            ..BatchLimits::default()
+        };
+        assert!(matches!(
+            split_archive(&zip, &limits),
+            Err(BatchError::TooManyMessages(3))
+        ));
+
+        let limits = BatchLimits {
+            max_total_size: 30,
+            ..BatchLimits::default()
+        };
+        assert!(matches!(
+            split_archive(&zip, &limits),
+            Err(BatchError::TooLarge(30))
+        ));
+    }
+}
@@ -0,0 +1,251 @@
+use crate::batch::{submit_batch, BatchDescription, BatchLimits};
+use crate::config::{AnalyzeArgs, BatchArgs, Config, OutputFormat};
+use crate::job::{Job, JobDescription, JobState};
+use crate::secrets::SECRETS;
+use crate::state::{Jobs, RetentionPolicy};
+use crate::storage::SqliteStorage;
+use crate::submission::submit_email;
+use crate::analysis::{AnalysisOptions, AnalysisTimeouts, ANALYZERS};
+use rocket::serde::json::serde_json;
+use std::fmt::Write;
+use std::io::Read;
+use std::path::Path;
+use std::process::ExitCode;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// Runs the `analyze` command: analyzes a single email with the configured analyzers,
+/// waits for the job to complete and prints its results.
+///
+/// Exits with a failure if the email can't be read or parsed, or if the analysis failed.
+pub async fn analyze(args: &AnalyzeArgs, config: &Config) -> ExitCode {
+    let Some(email) = read_input(&args.file) else {
+        return ExitCode::FAILURE;
+    };
+
+    let Some(jobs) = init_jobs().await else {
+        return ExitCode::FAILURE;
+    };
+
+    let timeouts = AnalysisTimeouts::from(&config.timeouts);
+
+    let options = AnalysisOptions::default();
+    let analyzers = ANALYZERS.get().unwrap();
+    let submitted = submit_email(&jobs, email, args.report, None, options, analyzers, &timeouts);
+    let Some(job) = submitted.await else {
+        eprintln!("{} is not a valid email", args.file.display());
+        return ExitCode::FAILURE;
+    };
+
+    let mut events = job.subscribe_events(0);
+    while events.next().await.is_some() {}
+
+    match args.format {
+        OutputFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&JobDescription::from_job(&job)).unwrap()
+        ),
+        OutputFormat::Text => print!("{}", text_report(&job)),
+    }
+
+    let state = job.state.lock().unwrap().clone();
+    match state {
+        JobState::Analyzed => ExitCode::SUCCESS,
+        _ => ExitCode::FAILURE,
+    }
+}
+
+/// Runs the `batch` command: analyzes every email of an mbox file or a zip archive,
+/// waits for every job to complete and prints their verdicts.
+///
+/// Exits with a failure if a message was rejected, or if the analysis of an email failed.
+pub async fn batch(args: &BatchArgs, config: &Config) -> ExitCode {
+    let Some(archive) = read_input(&args.file) else {
+        return ExitCode::FAILURE;
+    };
+
+    let Some(jobs) = init_jobs().await else {
+        return ExitCode::FAILURE;
+    };
+
+    let limits = BatchLimits::from(&config.batch);
+    let timeouts = AnalysisTimeouts::from(&config.timeouts);
+
+    let options = AnalysisOptions::default();
+    let analyzers = ANALYZERS.get().unwrap();
+    let submitted = submit_batch(
+        &jobs,
+        &archive,
+        args.report,
+        &limits,
+        &options,
+        analyzers,
+        &timeouts,
+    );
+    let batch = match submitted.await {
+        Ok(batch) => batch,
+        Err(err) => {
+            eprintln!("could not read {}: {err}", args.file.display());
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let batch_jobs: Vec<_> = {
+        let jobs = jobs.lock().await;
+        batch.jobs.iter().filter_map(|j| jobs.find_job(j.job_id)).collect()
+    };
+    for job in batch_jobs {
+        let mut events = job.subscribe_events(0);
+        while events.next().await.is_some() {}
+    }
+
+    let description = BatchDescription::new(&batch, &*jobs.lock().await);
+
+    match args.format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&description).unwrap()),
+        OutputFormat::Text => print!("{}", batch_report(&description)),
+    }
+
+    let all_analyzed = description
+        .jobs
+        .iter()
+        .all(|j| matches!(j.state, JobState::Analyzed));
+    if all_analyzed && description.rejected.is_empty() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+/// Reads the given file, or the standard input if the path is `-`
+fn read_input(path: &Path) -> Option<Vec<u8>> {
+    let content = if path.as_os_str() == "-" {
+        let mut content = vec![];
+        std::io::stdin().read_to_end(&mut content).map(|_| content)
+    } else {
+        std::fs::read(path)
+    };
+
+    content
+        .inspect_err(|err| eprintln!("could not read {}: {err}", path.display()))
+        .ok()
+}
+
+/// Loads the secrets and creates a job list that only lives for the duration of the command
+async fn init_jobs() -> Option<Arc<Mutex<Jobs>>> {
+    if let Err(err) = SECRETS.get().unwrap().reload().await {
+        eprintln!("could not load secrets: {err}");
+        return None;
+    }
+
+    let storage = SqliteStorage::open(":memory:").expect("could not open job storage");
+    let jobs = Jobs::load(Box::new(storage), RetentionPolicy::default())
+        .expect("could not load stored jobs");
+    Some(Arc::new(Mutex::new(jobs)))
+}
+
+fn batch_report(batch: &BatchDescription) -> String {
+    let mut report = String::new();
+
+    let _ = writeln!(
+        report,
+        "Batch: {} emails, {} failed, {} rejected",
+        batch.total,
+        batch.failed,
+        batch.rejected.len()
+    );
+
+    for job in &batch.jobs {
+        let name = job.name.clone().unwrap_or_else(|| format!("#{}", job.index + 1));
+        let outcome = match (&job.state, &job.risk) {
+            (JobState::Error(err), _) => format!("Error: {err}"),
+            (JobState::Cancelled, _) => String::from("Cancelled"),
+            (_, Some(risk)) => format!("{:?} ({}/100)", risk.label, risk.score.round() as i64),
+            (_, None) => String::from("<no verdict>"),
+        };
+        let _ = writeln!(
+            report,
+            "  [{name}] {}: {outcome}",
+            job.subject.as_deref().unwrap_or("<none>")
+        );
+    }
+
+    if !batch.rejected.is_empty() {
+        let _ = writeln!(report, "\nRejected:");
+        for message in &batch.rejected {
+            let name = message.name.clone().unwrap_or_else(|| format!("#{}", message.index + 1));
+            let _ = writeln!(report, "  [{name}] {}", message.reason);
+        }
+    }
+
+    report
+}
+
+fn text_report(job: &Job) -> String {
+    let mut report = String::new();
+
+    let email = job.email();
+    let _ = writeln!(report, "Subject: {}", email.subject().unwrap_or("<none>"));
+    if let Some(forwarded) = &job.report {
+        let reporter = match (&forwarded.reporter_name, &forwarded.reporter) {
+            (Some(name), Some(address)) => format!("{name} <{address}>"),
+            (None, Some(address)) => address.clone(),
+            (name, None) => name.clone().unwrap_or_else(|| String::from("<unknown>")),
+        };
+        let _ = writeln!(report, "Reported by: {reporter} ({:?})", forwarded.method);
+    }
+
+    match &*job.state.lock().unwrap() {
+        JobState::Error(err) => {
+            let _ = writeln!(report, "Error: {err}");
+        }
+        JobState::Cancelled => {
+            let _ = writeln!(report, "Cancelled");
+        }
+        JobState::Analyzing | JobState::Analyzed => {}
+    }
+
+    if let Some(risk) = &*job.risk.lock().unwrap() {
+        let _ = writeln!(report, "Risk: {:?} ({}/100)", risk.label, risk.score.round() as i64);
+        for factor in &risk.factors {
+            let _ = writeln!(
+                report,
+                "  {:+.0} {}: {}",
+                factor.weight, factor.kind, factor.description
+            );
+        }
+    }
+
+    let _ = writeln!(report, "\nAnalyzers:");
+    for status in job.analyzers.lock().unwrap().iter() {
+        let duration = status
+            .started_at
+            .zip(status.ended_at)
+            .map(|(start, end)| format!(", {:.1}s", (end - start).num_milliseconds() as f64 / 1000.0))
+            .unwrap_or_default();
+        let _ = writeln!(
+            report,
+            "  {}: {:?}, {} results{duration}",
+            status.name, status.state, status.result_count
+        );
+    }
+
+    let _ = writeln!(report, "\nResults:");
+    for result in job.results.lock().unwrap().iter() {
+        let _ = writeln!(
+            report,
+            "  [{}] {}: {}",
+            result.analysis_name, result.verdict.kind, result.verdict.value
+        );
+    }
+
+    let failures = job.failures.lock().unwrap();
+    if !failures.is_empty() {
+        let _ = writeln!(report, "\nFailures:");
+        for failure in failures.iter() {
+            let _ = writeln!(report, "  [{}] {}", failure.analysis_name, failure.message);
+        }
+    }
+
+    report
+}
@@ -1,15 +1,20 @@
-use crate::analysis::{AnalysisResult, AnalysisSetup, AnalysisVerdict, JobEvent};
+use crate::analysis::{AnalysisError, AnalysisResult, AnalysisSetup, AnalysisVerdict, JobEvent};
 use crate::entity::Entity;
-use crate::job::Job;
+use crate::job::{AnalyzerState, AnalyzerStatus, Job};
 use crate::pipeline::{AsyncRunnable, Pipeline};
+use crate::secrets::SECRETS;
+use chrono::{DateTime, Utc};
+use log::info;
+use serde::Serialize;
+use std::any::Any;
+use std::collections::HashMap;
 use std::future::Future;
 use std::ops::Deref;
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
-use std::sync::Arc;
-use tokio::sync::broadcast::Receiver;
-use tokio::sync::broadcast::Sender;
-use tokio_stream::wrappers::BroadcastStream;
-use tokio_stream::{Stream, StreamExt};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use rocket::futures::stream;
+use tokio_stream::Stream;
 
 #[derive(Clone)]
 pub struct AnalysisCommand {
@@ -22,21 +27,53 @@ struct AnalysisCommandInner {
     total_result_count: AtomicUsize,
     remaining_tasks: AtomicUsize,
     validated: AtomicBool,
+    concluded: AtomicBool,
+    timed_out: AtomicBool,
+    task_timeout: Duration,
+    analysis_timeout: Duration,
+    started_at: DateTime<Utc>,
+    result_count: AtomicUsize,
+    error_count: AtomicUsize,
+    /// Options of the analyzer chosen at submission
+    options: HashMap<String, bool>,
 }
 
 impl AnalysisCommand {
-    pub(crate) fn new(name: String, job: Arc<Job>) -> Self {
+    pub(crate) fn new(
+        name: String,
+        job: Arc<Job>,
+        task_timeout: Duration,
+        analysis_timeout: Duration,
+        options: HashMap<String, bool>,
+    ) -> Self {
         Self {
             inner: Arc::new(AnalysisCommandInner {
                 analysis_name: name,
                 job,
                 total_result_count: AtomicUsize::default(),
                 validated: AtomicBool::new(false),
+                concluded: AtomicBool::new(false),
+                timed_out: AtomicBool::new(false),
+                task_timeout,
+                analysis_timeout,
                 remaining_tasks: AtomicUsize::default(),
+                started_at: Utc::now(),
+                result_count: AtomicUsize::default(),
+                error_count: AtomicUsize::default(),
+                options,
             }),
         }
     }
 
+    pub fn started_at(&self) -> DateTime<Utc> {
+        self.inner.started_at
+    }
+
+    /// Returns a boolean option of the analyzer, if it was set at submission
+    pub fn option(&self, name: &str) -> Option<bool> {
+        self.inner.options.get(name).copied()
+    }
+
     fn get_expected_result_count(&self) -> usize {
         self.inner.total_result_count.load(Ordering::Acquire)
     }
@@ -45,6 +82,12 @@ impl AnalysisCommand {
         self.inner.result(verdict)
     }
 
+    /// Reports an error that occurred during the analysis.
+    /// The analysis keeps running, the error is recorded as a failure of this analyzer.
+    pub fn error(&self, message: impl Into<String>) {
+        self.inner.error(message.into())
+    }
+
     pub fn submit_entity(&self, entity: &Entity) {
         self.result(AnalysisVerdict::new("entity", entity))
     }
@@ -54,27 +97,29 @@ impl AnalysisCommand {
             .total_result_count
             .fetch_add(result_count, Ordering::Acquire);
 
-        self.inner
-            .remaining_tasks
-            .fetch_add(result_count, Ordering::AcqRel);
-
         if self.inner.validated.load(Ordering::Acquire) {
             self.inner
                 .job
-                .event_channel
-                .send(JobEvent::ExpandedResultCount(result_count))
-                .unwrap();
+                .publish(JobEvent::ExpandedResultCount(result_count));
         }
     }
 
     pub fn spawn(&self, task: impl Future<Output = AnalysisVerdict> + Send + 'static) {
-        self.add_result_count(1);
+        self.try_spawn(async move { Ok(task.await) });
+    }
 
-        let arc = self.inner.clone();
+    /// Spawns a task that may fail, its error is reported using [`AnalysisCommand::error`]
+    pub fn try_spawn(
+        &self,
+        task: impl Future<Output = Result<AnalysisVerdict, String>> + Send + 'static,
+    ) {
+        let command = self.clone();
 
-        tokio::spawn(async move {
-            let verdict = task.await;
-            arc.result(verdict);
+        self.run_task(1, async move {
+            match task.await {
+                Ok(verdict) => command.result(verdict),
+                Err(message) => command.error(message),
+            }
         });
     }
 
@@ -99,88 +144,325 @@ impl AnalysisCommand {
         input: PI,
         pipeline: Pipeline<AnalysisCommand, TI, PI, TO, PO>,
     ) {
-        self.add_result_count(pipeline.total_task_count());
-        tokio::spawn(pipeline.run(self.clone(), input));
+        let result_count = pipeline.total_task_count();
+        self.run_task(result_count, pipeline.run(self.clone(), input));
+    }
+
+    /// Runs a task of this analysis in the background.
+    /// A panicking task is reported as an error instead of silently vanishing,
+    /// a task that exceeds the task timeout is aborted and turned into a `timeout` verdict,
+    /// and the analysis concludes once all of its tasks are over.
+    /// The `result_count` results of the task are only expected once it actually runs.
+    fn run_task(&self, result_count: usize, task: impl Future<Output = ()> + Send + 'static) {
+        if self.inner.is_timed_out() || self.inner.job.is_cancelled() {
+            return;
+        }
+
+        self.add_result_count(result_count);
+
+        self.inner.remaining_tasks.fetch_add(1, Ordering::AcqRel);
+
+        let inner = self.inner.clone();
+
+        let handle = tokio::spawn(task);
+        let abort_handle = handle.abort_handle();
+        inner.job.register_task(&inner.analysis_name, abort_handle.clone());
+
+        tokio::spawn(async move {
+            match tokio::time::timeout(inner.task_timeout, handle).await {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) if err.is_cancelled() => {}
+                Ok(Err(err)) => inner.error(match err.try_into_panic() {
+                    Ok(panic) => format!("task panicked: {}", panic_message(panic)),
+                    Err(err) => format!("task failed: {err}"),
+                }),
+                Err(_) => {
+                    abort_handle.abort();
+                    inner.result(AnalysisVerdict::new(
+                        "timeout",
+                        TaskTimeoutVerdict {
+                            timeout_secs: inner.task_timeout.as_secs(),
+                        },
+                    ))
+                }
+            }
+
+            if inner.remaining_tasks.fetch_sub(1, Ordering::AcqRel) == 1 {
+                inner.try_conclude();
+            }
+        });
     }
 
     pub fn validate(self) -> AnalysisSetup {
-        self.inner.validated.store(true, Ordering::Relaxed);
+        self.inner.validated.store(true, Ordering::Release);
+        self.inner.try_conclude();
+
+        let inner = Arc::downgrade(&self.inner);
+        let timeout = self.inner.analysis_timeout;
+        tokio::spawn(async move {
+            tokio::time::sleep(timeout).await;
+            if let Some(inner) = Weak::upgrade(&inner) {
+                inner.time_out();
+            }
+        });
+
         AnalysisSetup {
             expected_verdict_count: self.inner.total_result_count.load(Ordering::Acquire),
         }
     }
 
-    pub fn subscribe_to_events(&self) -> Receiver<JobEvent> {
-        self.inner.job.event_channel.subscribe()
-    }
-
     // pub async fn await_specific_verdict(&self, verdict_name: &str) -> Option<AnalysisVerdict> {
-    //     let mut rx = self.subscribe_to_events();
-    //     while let Ok(event) = rx.recv().await {
+    //     let mut events = self.inner.job.subscribe_events(0);
+    //     while let Some((_, event)) = events.next().await {
     //         match event {
     //             JobEvent::Progress(AnalysisResult { verdict, .. })
     //                 if verdict.kind == verdict_name =>
     //             {
     //                 return Some(verdict)
     //             }
-    //             e if e.is_closing_action() => break,
     //             _ => {}
     //         }
     //     }
     //     None
     // }
 
-    pub async fn catch_all_verdicts<'a>(
+    /// Streams every verdict of the given kind submitted to the job, including the ones
+    /// submitted before the call. The stream ends once all the other analyzers of the job are over.
+    pub fn catch_all_verdicts<'a>(
         &'a self,
         verdict_kind: &'a str,
     ) -> impl Stream<Item = AnalysisVerdict> + 'a {
-        let rx = self.subscribe_to_events();
-        let results_stream = self
-            .inner
-            .job
-            .results
+        let job = &self.inner.job;
+
+        let awaited_analyzers: Vec<_> = job
+            .analyzers
             .lock()
-            .await
-            .clone()
-            .into_iter()
-            .map(|r| r.verdict);
-
-        let event_stream = BroadcastStream::new(rx).filter_map(|event| match event {
-            Ok(JobEvent::Progress(result)) => Some(result.verdict),
-            _ => None,
-        });
+            .unwrap()
+            .iter()
+            .filter(|s| s.name != self.inner.analysis_name && !s.is_over())
+            .map(|s| s.name.clone())
+            .collect();
 
-        tokio_stream::iter(results_stream)
-            .chain(event_stream)
-            .filter(move |v: &AnalysisVerdict| v.kind == verdict_kind)
+        stream::unfold(
+            (job.subscribe_events(0), awaited_analyzers),
+            move |(mut events, mut awaited_analyzers)| async move {
+                while !awaited_analyzers.is_empty() {
+                    match events.next().await? {
+                        (_, JobEvent::Progress(result)) if result.verdict.kind == verdict_kind => {
+                            return Some((result.verdict, (events, awaited_analyzers)))
+                        }
+                        (_, JobEvent::AnalyzerFinished(status)) => {
+                            awaited_analyzers.retain(|name| name != &status.name)
+                        }
+                        _ => {}
+                    }
+                }
+                None
+            },
+        )
     }
 }
 
 impl AnalysisCommandInner {
-    fn result(&self, verdict: AnalysisVerdict) {
-        result(
-            self.analysis_name.clone(),
-            verdict,
-            &self.remaining_tasks,
-            &self.job.event_channel,
-        )
+    fn result(&self, mut verdict: AnalysisVerdict) {
+        self.result_count.fetch_add(1, Ordering::AcqRel);
+        // services may echo the credentials they were given
+        if let Some(secrets) = SECRETS.get() {
+            secrets.redact_value(&mut verdict.value);
+        }
+        let result = AnalysisResult::new(self.analysis_name.clone(), verdict);
+        self.job.publish(JobEvent::Progress(result));
+    }
+
+    fn error(&self, mut message: String) {
+        self.error_count.fetch_add(1, Ordering::AcqRel);
+        if let Some(secrets) = SECRETS.get() {
+            message = secrets.redact(&message);
+        }
+        let error = AnalysisError {
+            analysis_name: self.analysis_name.clone(),
+            message,
+        };
+        self.job.publish(JobEvent::Error(error));
+    }
+
+    fn is_timed_out(&self) -> bool {
+        self.timed_out.load(Ordering::Acquire) || self.job.is_timed_out()
+    }
+
+    /// Aborts the remaining tasks of the analysis, which then concludes as timed out
+    fn time_out(&self) {
+        if self.concluded.load(Ordering::Acquire) {
+            return;
+        }
+        self.timed_out.store(true, Ordering::Release);
+        self.job.abort_tasks(Some(&self.analysis_name));
+    }
+
+    /// Concludes the analysis if it has been validated and none of its tasks are still running.
+    fn try_conclude(&self) {
+        if !self.validated.load(Ordering::Acquire)
+            || self.remaining_tasks.load(Ordering::Acquire) != 0
+        {
+            return;
+        }
+
+        if !self.concluded.swap(true, Ordering::AcqRel) {
+            conclude_analysis(self.status(), &self.job)
+        }
     }
+
+    fn status(&self) -> AnalyzerStatus {
+        let state = if self.job.is_cancelled() {
+            AnalyzerState::Cancelled
+        } else if self.is_timed_out() {
+            AnalyzerState::TimedOut
+        } else if self.error_count.load(Ordering::Acquire) > 0 {
+            AnalyzerState::Failed
+        } else {
+            AnalyzerState::Done
+        };
+
+        AnalyzerStatus {
+            name: self.analysis_name.clone(),
+            state,
+            started_at: Some(self.started_at),
+            ended_at: Some(Utc::now()),
+            result_count: self.result_count.load(Ordering::Acquire),
+        }
+    }
+}
+
+fn conclude_analysis(status: AnalyzerStatus, job: &Job) {
+    info!("Analysis Command {} concluded !", status.name);
+    job.publish(JobEvent::AnalyzerFinished(status));
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TaskTimeoutVerdict {
+    timeout_secs: u64,
 }
 
-fn conclude_analysis(name: String, sender: &Sender<JobEvent>) {
-    println!("Analysis Command {name} dropped !");
-    sender.send(JobEvent::AnalysisDone(name)).unwrap();
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        String::from("<unknown panic payload>")
+    }
 }
 
-fn result(
-    name: String,
-    verdict: AnalysisVerdict,
-    remaining_tasks: &AtomicUsize,
-    sender: &Sender<JobEvent>,
-) {
-    let result = AnalysisResult::new(name.clone(), verdict);
-    if remaining_tasks.fetch_sub(1, Ordering::AcqRel) == 1 {
-        conclude_analysis(name, sender)
-    };
-    sender.send(JobEvent::Progress(result)).unwrap();
+#[cfg(test)]
+mod test {
+    use crate::analysis::{AnalysisVerdict, JobEvent};
+    use crate::command::AnalysisCommand;
+    use crate::job::{AnalyzerState, Job};
+    use rocket::async_test;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[async_test]
+    async fn test_task_errors_are_reported() {
+        let job = Arc::new(Job::new(Vec::new(), 1));
+        let mut events = job.subscribe_events(0);
+
+        let command = AnalysisCommand::new(
+            String::from("test"),
+            job,
+            Duration::from_secs(10),
+            Duration::from_secs(10),
+            HashMap::new(),
+        );
+        command.try_spawn(async { Err(String::from("failure")) });
+        command.spawn(async { panic!("boom") });
+        command.spawn(async { AnalysisVerdict::new("test", "ok") });
+        command.validate();
+
+        let mut errors = vec![];
+        let mut verdicts = 0;
+        loop {
+            match events.next().await.unwrap().1 {
+                JobEvent::Error(err) => errors.push(err.message),
+                JobEvent::Progress(_) => verdicts += 1,
+                JobEvent::AnalyzerFinished(status) => {
+                    assert_eq!(status.name, "test");
+                    assert_eq!(status.state, AnalyzerState::Failed);
+                    assert_eq!(status.result_count, 1);
+                    break;
+                }
+                _ => {}
+            }
+        }
+
+        errors.sort();
+        assert_eq!(errors, vec!["failure", "task panicked: boom"]);
+        assert_eq!(verdicts, 1);
+    }
+
+    #[async_test]
+    async fn test_cancelled_tasks_are_not_expected() {
+        let job = Arc::new(Job::new(Vec::new(), 1));
+        let command = AnalysisCommand::new(
+            String::from("test"),
+            job.clone(),
+            Duration::from_secs(10),
+            Duration::from_secs(10),
+            HashMap::new(),
+        );
+        command.spawn(async { AnalysisVerdict::new("test", "ok") });
+        job.cancel();
+        // bails out without running
+        command.spawn(async { AnalysisVerdict::new("test", "late") });
+
+        assert_eq!(command.validate().expected_verdict_count, 1);
+    }
+
+    #[async_test]
+    async fn test_timeouts() {
+        let job = Arc::new(Job::new(Vec::new(), 1));
+        let mut events = job.subscribe_events(0);
+
+        let task_timeout = AnalysisCommand::new(
+            String::from("task"),
+            job.clone(),
+            Duration::from_millis(50),
+            Duration::from_secs(10),
+            HashMap::new(),
+        );
+        task_timeout.spawn(std::future::pending());
+        task_timeout.validate();
+
+        let analysis_timeout = AnalysisCommand::new(
+            String::from("analysis"),
+            job,
+            Duration::from_secs(10),
+            Duration::from_millis(50),
+            HashMap::new(),
+        );
+        analysis_timeout.spawn(std::future::pending());
+        analysis_timeout.validate();
+
+        let mut finished = vec![];
+        let mut verdicts = vec![];
+        while finished.len() < 2 {
+            match events.next().await.unwrap().1 {
+                JobEvent::Progress(result) => verdicts.push(result.verdict.kind),
+                JobEvent::AnalyzerFinished(status) => finished.push((status.name, status.state)),
+                _ => {}
+            }
+        }
+
+        finished.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            finished,
+            vec![
+                (String::from("analysis"), AnalyzerState::TimedOut),
+                (String::from("task"), AnalyzerState::Done),
+            ]
+        );
+        assert_eq!(verdicts, vec!["timeout"]);
+    }
 }
@@ -0,0 +1,675 @@
+use crate::analysis::AnalysisTimeouts;
+use crate::batch::BatchLimits;
+use crate::dedup::DedupPolicy;
+use crate::secrets::SECRET_ENV_PREFIX;
+use crate::splunk::SplunkClientConfig;
+use crate::state::RetentionPolicy;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use toml::{Table, Value};
+use url::Url;
+
+/// Prefix of the environment variables overriding the configuration file.
+/// Nested keys are separated by `__`, e.g. `MAILANALYZER_SERVER__PORT=8000`.
+/// Variables starting with [`SECRET_ENV_PREFIX`] hold secrets and are not configuration keys.
+const ENV_PREFIX: &str = "MAILANALYZER_";
+const ENV_SEPARATOR: &str = "__";
+
+#[derive(Parser, Debug)]
+#[command(version, about = "Mail analysis server")]
+pub struct Cli {
+    /// Runs a single analysis instead of the server
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Path of the TOML configuration file, ignored if it does not exist
+    #[arg(short, long, default_value = "config.toml", global = true)]
+    pub config: PathBuf,
+
+    /// Address to listen on
+    #[arg(long)]
+    pub address: Option<IpAddr>,
+
+    /// Port to listen on
+    #[arg(short, long)]
+    pub port: Option<u16>,
+
+    /// Allowed CORS origin, can be repeated
+    #[arg(long = "cors-origin")]
+    pub cors_origins: Vec<String>,
+
+    /// Identifiers of the analyzers to run, separated by commas
+    #[arg(long, value_delimiter = ',', global = true)]
+    pub analyzers: Option<Vec<String>>,
+
+    /// Path of the SQLite job database
+    #[arg(long)]
+    pub storage: Option<PathBuf>,
+
+    /// Directory of the detection rules
+    #[arg(long, global = true)]
+    pub rules: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Analyzes an email file and prints the results, without starting the server
+    Analyze(AnalyzeArgs),
+    /// Analyzes every email of an mbox file or a zip archive and prints their verdicts
+    Batch(BatchArgs),
+}
+
+#[derive(Args, Debug)]
+pub struct AnalyzeArgs {
+    /// Path of the .eml file to analyze, `-` reads it from the standard input
+    pub file: PathBuf,
+
+    /// The email is a user report, whose forwarded message is analyzed in its place
+    #[arg(long)]
+    pub report: bool,
+
+    /// Output format of the results
+    #[arg(short, long, value_enum, default_value_t = OutputFormat::Text)]
+    pub format: OutputFormat,
+}
+
+#[derive(Args, Debug)]
+pub struct BatchArgs {
+    /// Path of the mbox file or zip archive of .eml and .msg files to analyze
+    pub file: PathBuf,
+
+    /// The emails are user reports, whose forwarded message is analyzed in their place
+    #[arg(long)]
+    pub report: bool,
+
+    /// Output format of the results
+    #[arg(short, long, value_enum, default_value_t = OutputFormat::Text)]
+    pub format: OutputFormat,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Human-readable report
+    Text,
+    /// The job description, as served by the `/jobs` route
+    Json,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub storage: StorageConfig,
+    pub rules: RulesConfig,
+    pub analyzers: AnalyzersConfig,
+    pub services: ServicesConfig,
+    pub timeouts: TimeoutsConfig,
+    pub retention: RetentionConfig,
+    pub batch: BatchConfig,
+    pub deduplication: DeduplicationConfig,
+    pub recheck: RecheckConfig,
+    pub ingestion: IngestionConfig,
+    pub secrets: SecretsConfig,
+    pub http: HttpConfig,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub address: IpAddr,
+    pub port: u16,
+    pub cors_origins: Vec<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 8000,
+            cors_origins: vec![String::from("http://localhost:5173")],
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    /// Path of the SQLite job database
+    pub path: PathBuf,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("jobs.db"),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RulesConfig {
+    pub directory: PathBuf,
+}
+
+impl Default for RulesConfig {
+    fn default() -> Self {
+        Self {
+            directory: PathBuf::from("rules"),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AnalyzersConfig {
+    /// Identifiers of the analyzers to run, every analyzer runs if not set
+    pub enabled: Option<Vec<String>>,
+}
+
+impl AnalyzersConfig {
+    pub fn is_enabled(&self, analyzer_id: &str) -> bool {
+        self.enabled
+            .as_ref()
+            .is_none_or(|enabled| enabled.iter().any(|id| id == analyzer_id))
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServicesConfig {
+    /// Base URL of the language processing worker, serving the `/llm/analyze` and `/ocr` routes
+    pub worker_url: Url,
+    pub virustotal: VirusTotalConfig,
+    pub splunk: Option<SplunkClientConfig>,
+}
+
+impl Default for ServicesConfig {
+    fn default() -> Self {
+        Self {
+            worker_url: Url::parse("http://localhost:8080/").unwrap(),
+            virustotal: VirusTotalConfig::default(),
+            splunk: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct VirusTotalConfig {
+    pub url: Url,
+}
+
+impl Default for VirusTotalConfig {
+    fn default() -> Self {
+        Self {
+            url: Url::parse("https://www.virustotal.com/api/v3/").unwrap(),
+        }
+    }
+}
+
+/// Timeouts, in seconds. See [`AnalysisTimeouts`]
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutsConfig {
+    pub task: u64,
+    pub analyzer: u64,
+    /// Per-analyzer overrides of the `analyzer` timeout, by analyzer id
+    pub analyzers: HashMap<String, u64>,
+    pub job: u64,
+}
+
+impl Default for TimeoutsConfig {
+    fn default() -> Self {
+        let timeouts = AnalysisTimeouts::default();
+        Self {
+            task: timeouts.task.as_secs(),
+            analyzer: timeouts.analyzer.as_secs(),
+            analyzers: HashMap::new(),
+            job: timeouts.job.as_secs(),
+        }
+    }
+}
+
+impl From<&TimeoutsConfig> for AnalysisTimeouts {
+    fn from(config: &TimeoutsConfig) -> Self {
+        Self {
+            task: Duration::from_secs(config.task),
+            analyzer: Duration::from_secs(config.analyzer),
+            analyzers: config
+                .analyzers
+                .iter()
+                .map(|(id, secs)| (id.clone(), Duration::from_secs(*secs)))
+                .collect(),
+            job: Duration::from_secs(config.job),
+        }
+    }
+}
+
+/// See [`RetentionPolicy`]
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionConfig {
+    /// Age, in seconds, after which a job is deleted
+    pub max_age: Option<u64>,
+    pub max_count: Option<usize>,
+}
+
+impl From<&RetentionConfig> for RetentionPolicy {
+    fn from(config: &RetentionConfig) -> Self {
+        Self {
+            max_age: config.max_age.map(Duration::from_secs),
+            max_count: config.max_count,
+        }
+    }
+}
+
+/// See [`BatchLimits`]
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct BatchConfig {
+    pub max_messages: usize,
+    /// Maximum size of a message, in bytes
+    pub max_message_size: u64,
+    /// Maximum size of the messages extracted from a zip archive, in bytes
+    pub max_total_size: u64,
+    pub concurrency: usize,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        let limits = BatchLimits::default();
+        Self {
+            max_messages: limits.max_messages,
+            max_message_size: limits.max_message_size,
+            max_total_size: limits.max_total_size,
+            concurrency: limits.concurrency,
+        }
+    }
+}
+
+impl From<&BatchConfig> for BatchLimits {
+    fn from(config: &BatchConfig) -> Self {
+        Self {
+            max_messages: config.max_messages,
+            max_message_size: config.max_message_size,
+            max_total_size: config.max_total_size,
+            concurrency: config.concurrency,
+        }
+    }
+}
+
+/// See [`crate::dedup`]
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeduplicationConfig {
+    pub policy: DedupPolicy,
+}
+
+/// See [`crate::recheck`]
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RecheckConfig {
+    /// Delays after the submission of a job at which its links are looked up again, in seconds
+    pub delays: Vec<u64>,
+    /// URL receiving a POST of every verdict change
+    pub webhook: Option<Url>,
+    /// Number of checks at which a lookup is tried before it is recorded without the links
+    /// that could not be looked up
+    pub max_attempts: u32,
+}
+
+impl Default for RecheckConfig {
+    fn default() -> Self {
+        Self {
+            delays: vec![3600, 21600, 86400],
+            webhook: None,
+            max_attempts: 5,
+        }
+    }
+}
+
+/// Sources whose emails are submitted as jobs, see [`crate::ingestion`]
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct IngestionConfig {
+    pub imap: Vec<ImapConfig>,
+    pub maildir: Vec<MaildirConfig>,
+    /// The SMTP listener only runs if it is configured
+    pub smtp: Option<SmtpConfig>,
+}
+
+/// See [`crate::ingestion::ImapIngestion`]
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ImapConfig {
+    /// Name of the mailbox, jobs created from its messages are tagged with it
+    pub name: String,
+    pub host: String,
+    pub port: u16,
+    pub tls: bool,
+    pub username: String,
+    /// Name of the secret holding the password, see [`crate::secrets`]
+    pub password_secret: String,
+    pub folders: Vec<String>,
+    /// Keyword added to the submitted messages, messages that have it are skipped
+    pub processed_flag: String,
+    /// Folder the submitted messages are moved to, they stay in place if not set
+    pub move_to: Option<String>,
+    /// Whether to wait for new messages with IDLE, when the server supports it
+    pub idle: bool,
+    /// Interval between two checks of a folder in seconds, also bounds the duration of IDLE
+    pub poll_interval: u64,
+    /// Whether the messages are user reports, whose forwarded message is always analyzed in their place
+    pub reports: bool,
+}
+
+impl Default for ImapConfig {
+    fn default() -> Self {
+        Self {
+            name: String::from("imap"),
+            host: String::from("localhost"),
+            port: 993,
+            tls: true,
+            username: String::new(),
+            password_secret: String::from("imap"),
+            folders: vec![String::from("INBOX")],
+            processed_flag: String::from("$MailAnalyzer"),
+            move_to: None,
+            idle: true,
+            poll_interval: 60,
+            reports: false,
+        }
+    }
+}
+
+/// See [`crate::ingestion::MaildirIngestion`]
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct MaildirConfig {
+    /// Name of the directory, jobs created from its files are tagged with it
+    pub name: String,
+    /// A Maildir, recognized by its `new` subdirectory, or a plain directory
+    pub path: PathBuf,
+    /// Directory the processed files are moved to, instead of `cur` for a Maildir,
+    /// and of a `processed` subdirectory for a plain directory
+    pub archive: Option<PathBuf>,
+    /// Interval between two checks of the directory in seconds
+    pub poll_interval: u64,
+    /// Whether the files are user reports, whose forwarded message is always analyzed in their place
+    pub reports: bool,
+}
+
+impl Default for MaildirConfig {
+    fn default() -> Self {
+        Self {
+            name: String::from("maildir"),
+            path: PathBuf::from("Maildir"),
+            archive: None,
+            poll_interval: 10,
+            reports: false,
+        }
+    }
+}
+
+/// See [`crate::ingestion::SmtpReceiver`]
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct SmtpConfig {
+    pub address: IpAddr,
+    pub port: u16,
+    /// Name the listener greets clients with
+    pub hostname: String,
+    /// Maximum size of a message, in bytes
+    pub max_message_size: u64,
+    /// Whether the messages are user reports, whose forwarded message is always analyzed in their place
+    pub reports: bool,
+}
+
+impl Default for SmtpConfig {
+    fn default() -> Self {
+        Self {
+            address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 2525,
+            hostname: String::from("localhost"),
+            max_message_size: BatchLimits::default().max_message_size,
+            reports: false,
+        }
+    }
+}
+
+/// See [`crate::http::HttpClient`]
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    pub mode: HttpMode,
+    /// Directory of the recorded requests and responses
+    pub cassettes: PathBuf,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            mode: HttpMode::Live,
+            cassettes: PathBuf::from("cassettes"),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HttpMode {
+    /// Requests are sent to the external services
+    Live,
+    /// Requests are sent, and saved to the cassettes along with their responses
+    Record,
+    /// Requests are answered from the cassettes, without any network access
+    Replay,
+}
+
+/// Sources of the API keys, see [`crate::secrets`]
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct SecretsConfig {
+    /// Providers to query, the first one knowing a secret supplies it
+    pub providers: Vec<SecretProviderKind>,
+    /// Path of the secrets file, which must only be readable by the service
+    pub file: PathBuf,
+    pub vault: Option<VaultConfig>,
+}
+
+impl Default for SecretsConfig {
+    fn default() -> Self {
+        Self {
+            providers: vec![SecretProviderKind::Env, SecretProviderKind::File],
+            file: PathBuf::from("secrets.toml"),
+            vault: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SecretProviderKind {
+    Env,
+    File,
+    Vault,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct VaultConfig {
+    pub url: Url,
+    /// Mount point of the KV version 2 secrets engine
+    pub mount: String,
+    /// Path of the secret holding the keys, one field per service
+    pub path: String,
+    /// Environment variable holding the Vault token
+    pub token_env: String,
+}
+
+impl Default for VaultConfig {
+    fn default() -> Self {
+        Self {
+            url: Url::parse("http://127.0.0.1:8200/").unwrap(),
+            mount: String::from("secret"),
+            path: String::from("mailanalyzer"),
+            token_env: String::from("VAULT_TOKEN"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Std(Box<dyn std::error::Error + Send + Sync>),
+    Env(String),
+}
+
+impl<E: std::error::Error + Send + Sync + 'static> From<E> for ConfigError {
+    fn from(value: E) -> Self {
+        ConfigError::Std(Box::new(value))
+    }
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Std(err) => write!(f, "{err}"),
+            ConfigError::Env(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+impl Config {
+    /// Loads the configuration from, by increasing priority, the defaults, the configuration
+    /// file, the `MAILANALYZER_` environment variables and the command line.
+    pub fn load(cli: &Cli) -> Result<Self, ConfigError> {
+        let mut table = read_table(&cli.config)?;
+        apply_env_overrides(&mut table, std::env::vars())?;
+
+        let mut config: Config = table.try_into()?;
+        config.apply_cli(cli);
+        Ok(config)
+    }
+
+    fn apply_cli(&mut self, cli: &Cli) {
+        if let Some(address) = cli.address {
+            self.server.address = address;
+        }
+        if let Some(port) = cli.port {
+            self.server.port = port;
+        }
+        if !cli.cors_origins.is_empty() {
+            self.server.cors_origins = cli.cors_origins.clone();
+        }
+        if let Some(analyzers) = &cli.analyzers {
+            self.analyzers.enabled = Some(analyzers.clone());
+        }
+        if let Some(storage) = &cli.storage {
+            self.storage.path = storage.clone();
+        }
+        if let Some(rules) = &cli.rules {
+            self.rules.directory = rules.clone();
+        }
+    }
+}
+
+fn read_table(path: &Path) -> Result<Table, ConfigError> {
+    if !path.exists() {
+        return Ok(Table::new());
+    }
+    Ok(std::fs::read_to_string(path)?.parse()?)
+}
+
+/// Sets the keys given by `MAILANALYZER_` variables. Values are parsed as TOML when possible
+/// (`8000`, `["a", "b"]`), and kept as strings otherwise: a string that looks like a number
+/// must be quoted (`'"1234"'`).
+fn apply_env_overrides(
+    table: &mut Table,
+    vars: impl IntoIterator<Item = (String, String)>,
+) -> Result<(), ConfigError> {
+    for (name, value) in vars {
+        if name.starts_with(SECRET_ENV_PREFIX) {
+            continue;
+        }
+        let Some(path) = name.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+        let keys: Vec<_> = path.split(ENV_SEPARATOR).map(str::to_lowercase).collect();
+        let (key, parents) = keys.split_last().unwrap();
+
+        let mut current = &mut *table;
+        for parent in parents {
+            current = current
+                .entry(parent.clone())
+                .or_insert_with(|| Value::Table(Table::new()))
+                .as_table_mut()
+                .ok_or_else(|| ConfigError::Env(format!("{name}: `{parent}` is not a section")))?;
+        }
+
+        let value = format!("value = {value}")
+            .parse::<Table>()
+            .ok()
+            .and_then(|mut t| t.remove("value"))
+            .unwrap_or(Value::String(value));
+
+        current.insert(key.clone(), value);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::{apply_env_overrides, Config};
+    use toml::Table;
+
+    #[test]
+    fn test_env_overrides() {
+        let mut table: Table = r#"
+            [server]
+            port = 9000
+
+            [services.virustotal]
+            url = "http://from-file/"
+        "#
+        .parse()
+        .unwrap();
+
+        let vars = [
+            ("MAILANALYZER_SERVER__PORT", "8001"),
+            ("MAILANALYZER_SERVER__CORS_ORIGINS", r#"["http://a", "http://b"]"#),
+            ("MAILANALYZER_SERVICES__VIRUSTOTAL__URL", "http://from-env/"),
+            ("MAILANALYZER_SECRET_VIRUSTOTAL", "key"),
+            ("MAILANALYZER_ANALYZERS__ENABLED", r#"["auth"]"#),
+            ("PATH", "/usr/bin"),
+        ];
+        apply_env_overrides(
+            &mut table,
+            vars.map(|(k, v)| (k.to_string(), v.to_string())),
+        )
+        .unwrap();
+
+        let config: Config = table.try_into().unwrap();
+        assert_eq!(config.server.port, 8001);
+        assert_eq!(config.server.cors_origins, vec!["http://a", "http://b"]);
+        assert_eq!(config.services.virustotal.url.as_str(), "http://from-env/");
+        assert!(config.analyzers.is_enabled("auth"));
+        assert!(!config.analyzers.is_enabled("links"));
+        assert_eq!(config.storage.path.to_str(), Some("jobs.db"));
+    }
+
+    #[test]
+    fn test_example_config() {
+        let table: Table = std::fs::read_to_string("config.example.toml")
+            .unwrap()
+            .parse()
+            .unwrap();
+        let config: Config = table.try_into().unwrap();
+        assert_eq!(config.analyzers.enabled.unwrap().len(), 4);
+    }
+}
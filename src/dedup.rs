@@ -0,0 +1,131 @@
+//! Recognition of emails that were already submitted, e.g. the same phishing message reported
+//! by several users, so that it is only analyzed once.
+
+use crate::email::SubmittedEmail;
+use chrono::{DateTime, Utc};
+use mail_parser::{MessageParser, PartType};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// What to do with an email whose fingerprint matches the one of a job
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum DedupPolicy {
+    /// Every email is analyzed
+    #[default]
+    Off,
+    /// The existing job is returned in place of a new one
+    Existing,
+    /// A new job is created, linked to the existing one whose results it reuses
+    Linked,
+}
+
+/// A submission of the email of a job, including the one that created the job
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Submission {
+    /// The job created by the submission, the existing job for [`DedupPolicy::Existing`]
+    pub job_id: usize,
+    pub submitted_at: DateTime<Utc>,
+    /// Address of the user who reported the email, when it was submitted as a report
+    pub reporter: Option<String>,
+}
+
+impl Submission {
+    pub fn new(job_id: usize, email: &SubmittedEmail) -> Self {
+        Self {
+            job_id,
+            submitted_at: Utc::now(),
+            reporter: email.report.as_ref().and_then(|r| r.reporter.clone()),
+        }
+    }
+}
+
+/// Hashes the Message-ID of an email along with its text and HTML, whitespace-normalized so that
+/// copies re-encoded by different mail clients match, and the content of its attachments.
+/// The HTML keeps the links apart, which the text of an HTML body does not show.
+pub fn fingerprint(email: &[u8]) -> Option<String> {
+    let message = MessageParser::new().parse(email)?;
+
+    let mut hasher = Sha256::new();
+    hasher.update(message.message_id().unwrap_or_default());
+    hasher.update(b"\n");
+
+    let mut index = 0;
+    while let Some(text) = message.body_text(index) {
+        hash_words(&mut hasher, &text);
+        index += 1;
+    }
+    hasher.update(b"\n");
+
+    // text-only emails have no HTML, it would only be converted from their text
+    for part in message.html_bodies() {
+        if let PartType::Html(html) = &part.body {
+            hash_words(&mut hasher, html);
+        }
+    }
+
+    for attachment in message.attachments() {
+        hasher.update(Sha256::digest(attachment.contents()));
+    }
+
+    Some(format!("{:x}", hasher.finalize()))
+}
+
+fn hash_words(hasher: &mut Sha256, text: &str) {
+    for word in text.split_whitespace() {
+        hasher.update(word);
+        hasher.update(b" ");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::dedup::fingerprint;
+
+    #[test]
+    fn test_fingerprint() {
+        let email = b"Message-ID: <a@example.org>\r\nSubject: hi\r\n\r\nClick  here\r\nnow\r\n";
+        let rewrapped =
+            b"Subject: Fwd: hi\r\nMessage-ID: <a@example.org>\r\n\r\nClick here now\r\n";
+        let other = b"Message-ID: <b@example.org>\r\nSubject: hi\r\n\r\nClick here now\r\n";
+
+        assert_eq!(fingerprint(email), fingerprint(rewrapped));
+        assert_ne!(fingerprint(email), fingerprint(other));
+        assert!(fingerprint(b"").is_none());
+    }
+
+    #[test]
+    fn test_fingerprint_without_message_id() {
+        let html = |link: &str| {
+            format!(
+                "Subject: hi\r\nContent-Type: text/html\r\n\r\n\
+                <p>Sign in <a href=\"{link}\">here</a></p>\r\n"
+            )
+        };
+        let phishing = html("https://phish.test/login");
+        assert_eq!(
+            fingerprint(phishing.as_bytes()),
+            fingerprint(html("https://phish.test/login").as_bytes())
+        );
+        // the text is the same, but not the link
+        assert_ne!(
+            fingerprint(phishing.as_bytes()),
+            fingerprint(html("https://example.org/login").as_bytes())
+        );
+
+        let attached = |content: &str| {
+            format!(
+                "Subject: invoice\r\nContent-Type: multipart/mixed; boundary=\"b\"\r\n\r\n\
+                --b\r\nContent-Type: text/plain\r\n\r\nSee attached.\r\n\
+                --b\r\nContent-Type: application/octet-stream\r\n\
+                Content-Disposition: attachment; filename=\"invoice.html\"\r\n\r\n\
+                {content}\r\n--b--\r\n"
+            )
+        };
+        assert_ne!(
+            fingerprint(attached("<form action=\"https://phish.test\">").as_bytes()),
+            fingerprint(attached("<p>Invoice 42</p>").as_bytes())
+        );
+    }
+}
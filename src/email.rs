@@ -1,26 +1,169 @@
+mod msg;
+mod report;
+
+use crate::analysis::AnalysisOptions;
+use crate::ingestion::{JobSource, SmtpEnvelope};
+use log::warn;
 use mail_parser::{Message, MessageParser};
+use serde::{Deserialize, Serialize};
+
+pub use report::Report;
+
+/// Format in which an email was submitted
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum EmailFormat {
+    /// RFC 822 message, analyzed as is
+    Eml,
+    /// Outlook message, converted to MIME to be analyzed
+    Msg,
+}
+
+impl EmailFormat {
+    pub fn detect(content: &[u8]) -> Self {
+        if content.starts_with(&msg::CFB_SIGNATURE) {
+            EmailFormat::Msg
+        } else {
+            EmailFormat::Eml
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            EmailFormat::Eml => "message/rfc822",
+            EmailFormat::Msg => "application/vnd.ms-outlook",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            EmailFormat::Eml => "eml",
+            EmailFormat::Msg => "msg",
+        }
+    }
+}
+
+/// A submitted file that was converted to be analyzed, kept for download
+#[derive(Clone, Debug)]
+pub struct OriginalFile {
+    pub format: EmailFormat,
+    pub content: Vec<u8>,
+}
+
+/// The email to analyze out of a submitted file
+pub struct SubmittedEmail {
+    pub email: Vec<u8>,
+    /// The submitted file, when it differs from the analyzed email
+    pub original: Option<OriginalFile>,
+    /// The wrapper of the email, when it was forwarded by a user
+    pub report: Option<Report>,
+    pub source: Option<JobSource>,
+    pub options: AnalysisOptions,
+}
+
+impl SubmittedEmail {
+    pub fn new(email: Vec<u8>) -> Self {
+        Self {
+            email,
+            original: None,
+            report: None,
+            source: None,
+            options: AnalysisOptions::default(),
+        }
+    }
+}
+
+/// Reads a submitted file as a MIME email, converting it first if it is in another format.
+/// When the email is a user report, the forwarded message is the one to analyze, and the
+/// submitted file is kept as the original. `reported` is set when the file is known to be a
+/// report, see [`report::unwrap_report`].
+///
+/// Returns `None` if the file can't be converted or parsed.
+pub fn read_email(content: Vec<u8>, reported: bool) -> Option<SubmittedEmail> {
+    let format = EmailFormat::detect(&content);
+
+    let (email, original) = match format {
+        EmailFormat::Eml => (content, None),
+        EmailFormat::Msg => {
+            let email = msg::convert(&content)
+                .inspect_err(|err| warn!("Could not convert Outlook message: {err}"))
+                .ok()?
+                .into_bytes();
+            (email, Some(OriginalFile { format, content }))
+        }
+    };
+
+    let message = MessageParser::new().parse(&email)?;
+
+    let Some((forwarded, report)) = report::unwrap_report(&message, reported) else {
+        return Some(SubmittedEmail {
+            original,
+            ..SubmittedEmail::new(email)
+        });
+    };
+    drop(message);
+
+    let original = original.unwrap_or(OriginalFile {
+        format,
+        content: email,
+    });
+
+    Some(SubmittedEmail {
+        original: Some(original),
+        report: Some(report),
+        ..SubmittedEmail::new(forwarded)
+    })
+}
 
 pub struct OwnedEmail {
-    message: String,
+    message: Vec<u8>,
+    envelope: Option<SmtpEnvelope>,
 }
 
 
 impl OwnedEmail {
-    pub fn new(message: String) -> Self {
+    pub fn new(message: Vec<u8>) -> Self {
         Self {
-            message
+            message,
+            envelope: None,
         }
     }
 
-    pub fn raw_str(&self) -> &str {
-        &self.message
+    pub fn with_envelope(self, envelope: Option<SmtpEnvelope>) -> Self {
+        Self { envelope, ..self }
+    }
+
+    /// The SMTP envelope the email was received with, if it was received by the listener
+    pub fn envelope(&self) -> Option<&SmtpEnvelope> {
+        self.envelope.as_ref()
     }
 
-    pub fn get_text(self) -> String {
+    pub fn into_bytes(self) -> Vec<u8> {
         self.message
     }
 
     pub fn parse(&self) -> Message {
         MessageParser::new().parse(&self.message).unwrap()
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod test {
+    use crate::email::read_email;
+    use mail_parser::MessageParser;
+
+    #[test]
+    fn test_read_8bit_email() {
+        let content = b"Subject: Caf\xe9\r\n\
+            Content-Type: text/plain; charset=iso-8859-1\r\n\
+            Content-Transfer-Encoding: 8bit\r\n\r\n\
+            Cr\xe8me br\xfbl\xe9e\r\n"
+            .to_vec();
+
+        let email = read_email(content.clone(), false).unwrap();
+        assert_eq!(email.email, content);
+        assert!(email.original.is_none());
+
+        let message = MessageParser::new().parse(&email.email).unwrap();
+        assert_eq!(message.body_text(0).unwrap().trim(), "Crème brûlée");
+    }
+}
@@ -0,0 +1,541 @@
+//! Conversion of Outlook `.msg` files into MIME messages.
+//!
+//! A `.msg` file is a compound file (CFB) holding the MAPI properties of the message: fixed-size
+//! ones in a `__properties_version1.0` stream, variable-size ones in a `__substg1.0_<id><type>`
+//! stream each. Recipients and attachments are stored the same way in their own storages, and
+//! embedded messages are nested messages with the same layout.
+
+use mail_builder::headers::address::Address;
+use mail_builder::mime::{BodyPart, MimePart};
+use mail_builder::MessageBuilder;
+use cfb::CompoundFile;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::io::{Cursor, Read, Seek};
+use std::path::{Path, PathBuf};
+
+/// Signature of compound files, shared by `.msg` files and other legacy Office documents
+pub const CFB_SIGNATURE: [u8; 8] = [0xD0, 0xCF, 0x11, 0xE0, 0xA1, 0xB1, 0x1A, 0xE1];
+
+const PROPERTIES_STREAM: &str = "__properties_version1.0";
+const SUBSTORAGE_PREFIX: &str = "__substg1.0_";
+const RECIPIENT_PREFIX: &str = "__recip_version1.0_";
+const ATTACHMENT_PREFIX: &str = "__attach_version1.0_";
+
+/// Size of the header of the properties stream, before the property entries
+const MESSAGE_HEADER_SIZE: usize = 32;
+const EMBEDDED_MESSAGE_HEADER_SIZE: usize = 24;
+const RECIPIENT_HEADER_SIZE: usize = 8;
+const ATTACHMENT_HEADER_SIZE: usize = 8;
+
+// property types
+const PT_LONG: u16 = 0x0003;
+const PT_STRING8: u16 = 0x001E;
+const PT_UNICODE: u16 = 0x001F;
+const PT_SYSTIME: u16 = 0x0040;
+const PT_BINARY: u16 = 0x0102;
+
+// message properties
+const PR_SUBJECT: u16 = 0x0037;
+const PR_CLIENT_SUBMIT_TIME: u16 = 0x0039;
+const PR_SENT_REPRESENTING_NAME: u16 = 0x0042;
+const PR_SENT_REPRESENTING_EMAIL_ADDRESS: u16 = 0x0065;
+const PR_TRANSPORT_MESSAGE_HEADERS: u16 = 0x007D;
+const PR_SENDER_NAME: u16 = 0x0C1A;
+const PR_SENDER_EMAIL_ADDRESS: u16 = 0x0C1F;
+const PR_MESSAGE_DELIVERY_TIME: u16 = 0x0E06;
+const PR_BODY: u16 = 0x1000;
+const PR_HTML: u16 = 0x1013;
+const PR_INTERNET_MESSAGE_ID: u16 = 0x1035;
+const PR_SENDER_SMTP_ADDRESS: u16 = 0x5D01;
+const PR_SENT_REPRESENTING_SMTP_ADDRESS: u16 = 0x5D02;
+
+// recipient properties
+const PR_RECIPIENT_TYPE: u16 = 0x0C15;
+const PR_DISPLAY_NAME: u16 = 0x3001;
+const PR_EMAIL_ADDRESS: u16 = 0x3003;
+const PR_SMTP_ADDRESS: u16 = 0x39FE;
+const MAPI_TO: u64 = 1;
+const MAPI_CC: u64 = 2;
+
+// attachment properties
+const PR_ATTACH_DATA: u16 = 0x3701;
+const PR_ATTACH_FILENAME: u16 = 0x3704;
+const PR_ATTACH_METHOD: u16 = 0x3705;
+const PR_ATTACH_LONG_FILENAME: u16 = 0x3707;
+const PR_ATTACH_MIME_TAG: u16 = 0x370E;
+const PR_ATTACH_CONTENT_ID: u16 = 0x3712;
+const ATTACH_EMBEDDED_MSG: u64 = 5;
+/// Type of the storage holding an embedded message, in place of the attachment data
+const PT_OBJECT: u16 = 0x000D;
+
+/// Headers describing the original MIME structure, which is rebuilt from the properties
+const MIME_HEADERS: &[&str] = &[
+    "mime-version",
+    "content-type",
+    "content-transfer-encoding",
+    "content-disposition",
+    "content-id",
+];
+
+/// Seconds between the FILETIME epoch (1601-01-01) and the unix epoch
+const FILETIME_UNIX_OFFSET: i64 = 11_644_473_600;
+
+#[derive(Debug)]
+pub enum MsgError {
+    Std(Box<dyn std::error::Error + Send + Sync>),
+    NotAMessage,
+}
+
+impl<E: std::error::Error + Send + Sync + 'static> From<E> for MsgError {
+    fn from(value: E) -> Self {
+        MsgError::Std(Box::new(value))
+    }
+}
+
+impl Display for MsgError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MsgError::Std(err) => write!(f, "{err}"),
+            MsgError::NotAMessage => write!(f, "the compound file is not an Outlook message"),
+        }
+    }
+}
+
+enum Property {
+    String(String),
+    Binary(Vec<u8>),
+    /// Value of a fixed-size property, such as an integer or a time
+    Fixed(u64),
+}
+
+/// The properties of a message, a recipient or an attachment, by id
+#[derive(Default)]
+struct Properties(HashMap<u16, Property>);
+
+impl Properties {
+    fn string(&self, id: u16) -> Option<String> {
+        match self.0.get(&id)? {
+            Property::String(value) => Some(value.clone()),
+            Property::Binary(value) => Some(decode_string8(value)),
+            Property::Fixed(_) => None,
+        }
+        .filter(|value| !value.is_empty())
+    }
+
+    /// Returns the first of the given string properties that is set
+    fn first_string(&self, ids: &[u16]) -> Option<String> {
+        ids.iter().find_map(|id| self.string(*id))
+    }
+
+    fn binary(&self, id: u16) -> Option<&[u8]> {
+        match self.0.get(&id)? {
+            Property::Binary(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    fn fixed(&self, id: u16) -> Option<u64> {
+        match self.0.get(&id)? {
+            Property::Fixed(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// Converts a FILETIME property, in 100ns intervals since 1601, to a unix timestamp
+    fn timestamp(&self, id: u16) -> Option<i64> {
+        let filetime = self.fixed(id)?;
+        Some((filetime / 10_000_000) as i64 - FILETIME_UNIX_OFFSET)
+    }
+}
+
+/// Converts the content of a `.msg` file into an equivalent MIME message.
+///
+/// The transport headers of the message are kept as they were received when the file has them,
+/// otherwise the headers are rebuilt from the sender, recipients, subject and date.
+/// Attached messages are converted as well and attached as `message/rfc822` parts.
+pub fn convert(content: &[u8]) -> Result<String, MsgError> {
+    let mut file = CompoundFile::open(Cursor::new(content))?;
+    if !file.is_stream(Path::new("/").join(PROPERTIES_STREAM)) {
+        return Err(MsgError::NotAMessage);
+    }
+
+    let message = convert_message(&mut file, Path::new("/"), MESSAGE_HEADER_SIZE)?;
+    Ok(String::from_utf8(message)?)
+}
+
+fn convert_message<F: Read + Seek>(
+    file: &mut CompoundFile<F>,
+    storage: &Path,
+    header_size: usize,
+) -> Result<Vec<u8>, MsgError> {
+    let properties = read_properties(file, storage, header_size)?;
+
+    let mut builder = MessageBuilder::new();
+    if let Some(text) = properties.string(PR_BODY) {
+        builder = builder.text_body(text);
+    }
+    if let Some(html) = properties.string(PR_HTML) {
+        builder = builder.html_body(html);
+    }
+    for attachment in child_storages(file, storage, ATTACHMENT_PREFIX)? {
+        if let Some(part) = convert_attachment(file, &attachment)? {
+            builder.attachments.get_or_insert_with(Vec::new).push(part);
+        }
+    }
+
+    let mut message = vec![];
+    match properties.string(PR_TRANSPORT_MESSAGE_HEADERS) {
+        Some(headers) => {
+            message.extend_from_slice(transport_headers(&headers).as_bytes());
+            message.extend_from_slice(b"MIME-Version: 1.0\r\n");
+            builder.write_body(&mut message)?;
+        }
+        None => {
+            builder = rebuild_headers(file, storage, &properties, builder)?;
+            builder.write_to(&mut message)?;
+        }
+    }
+
+    Ok(message)
+}
+
+/// Keeps the transport headers, up to the end of the header section, without the headers
+/// describing the MIME structure
+fn transport_headers(headers: &str) -> String {
+    let mut kept = String::new();
+    let mut keep = false;
+
+    for line in headers.lines() {
+        if line.is_empty() {
+            break;
+        }
+        if !line.starts_with([' ', '\t']) {
+            let name = line.split(':').next().unwrap_or_default().trim();
+            keep = !MIME_HEADERS.contains(&name.to_lowercase().as_str());
+        }
+        if keep {
+            kept.push_str(line);
+            kept.push_str("\r\n");
+        }
+    }
+
+    kept
+}
+
+fn rebuild_headers<'x, F: Read + Seek>(
+    file: &mut CompoundFile<F>,
+    storage: &Path,
+    properties: &Properties,
+    mut builder: MessageBuilder<'x>,
+) -> Result<MessageBuilder<'x>, MsgError> {
+    let sender = properties.first_string(&[
+        PR_SENT_REPRESENTING_SMTP_ADDRESS,
+        PR_SENDER_SMTP_ADDRESS,
+        PR_SENT_REPRESENTING_EMAIL_ADDRESS,
+        PR_SENDER_EMAIL_ADDRESS,
+    ]);
+    if let Some(sender) = sender {
+        let name = properties.first_string(&[PR_SENT_REPRESENTING_NAME, PR_SENDER_NAME]);
+        builder = builder.from(Address::new_address(name, sender));
+    }
+
+    let mut to = vec![];
+    let mut cc = vec![];
+    for recipient in child_storages(file, storage, RECIPIENT_PREFIX)? {
+        let recipient = read_properties(file, &recipient, RECIPIENT_HEADER_SIZE)?;
+        let Some(address) = recipient.first_string(&[PR_SMTP_ADDRESS, PR_EMAIL_ADDRESS]) else {
+            continue;
+        };
+        let address = Address::new_address(recipient.string(PR_DISPLAY_NAME), address);
+
+        // blind copies are left out, as they are not part of a sent message
+        match recipient.fixed(PR_RECIPIENT_TYPE) {
+            Some(MAPI_TO) | None => to.push(address),
+            Some(MAPI_CC) => cc.push(address),
+            Some(_) => {}
+        }
+    }
+    if !to.is_empty() {
+        builder = builder.to(Address::new_list(to));
+    }
+    if !cc.is_empty() {
+        builder = builder.cc(Address::new_list(cc));
+    }
+
+    if let Some(subject) = properties.string(PR_SUBJECT) {
+        builder = builder.subject(subject);
+    }
+    if let Some(date) = properties
+        .timestamp(PR_CLIENT_SUBMIT_TIME)
+        .or_else(|| properties.timestamp(PR_MESSAGE_DELIVERY_TIME))
+    {
+        builder = builder.date(date);
+    }
+    if let Some(id) = properties.string(PR_INTERNET_MESSAGE_ID) {
+        let id = id.trim().trim_start_matches('<').trim_end_matches('>');
+        builder = builder.message_id(id.to_string());
+    }
+
+    Ok(builder)
+}
+
+/// Converts an attachment into a MIME part.
+/// Returns `None` for attachments without data, such as links to files or OLE objects.
+fn convert_attachment<F: Read + Seek>(
+    file: &mut CompoundFile<F>,
+    storage: &Path,
+) -> Result<Option<MimePart<'static>>, MsgError> {
+    let properties = read_properties(file, storage, ATTACHMENT_HEADER_SIZE)?;
+
+    if properties.fixed(PR_ATTACH_METHOD) == Some(ATTACH_EMBEDDED_MSG) {
+        let embedded = storage.join(substorage_name(PR_ATTACH_DATA, PT_OBJECT));
+        let message = convert_message(file, &embedded, EMBEDDED_MESSAGE_HEADER_SIZE)?;
+        let name = properties
+            .string(PR_DISPLAY_NAME)
+            .unwrap_or_else(|| String::from("message"));
+
+        let part = MimePart::new("message/rfc822", BodyPart::Binary(message.into()))
+            .attachment(format!("{name}.eml"));
+        return Ok(Some(part));
+    }
+
+    let Some(data) = properties.binary(PR_ATTACH_DATA) else {
+        return Ok(None);
+    };
+    let content_type = properties
+        .string(PR_ATTACH_MIME_TAG)
+        .unwrap_or_else(|| String::from("application/octet-stream"));
+    let name = properties
+        .first_string(&[PR_ATTACH_LONG_FILENAME, PR_ATTACH_FILENAME, PR_DISPLAY_NAME])
+        .unwrap_or_else(|| String::from("attachment"));
+
+    let mut part = MimePart::new(content_type, BodyPart::Binary(data.to_vec().into())).attachment(name);
+    if let Some(content_id) = properties.string(PR_ATTACH_CONTENT_ID) {
+        part = part.cid(content_id);
+    }
+
+    Ok(Some(part))
+}
+
+fn substorage_name(id: u16, kind: u16) -> String {
+    format!("{SUBSTORAGE_PREFIX}{id:04X}{kind:04X}")
+}
+
+/// Returns the storages of the recipients or the attachments of a message, in order
+fn child_storages<F: Read + Seek>(
+    file: &CompoundFile<F>,
+    storage: &Path,
+    prefix: &str,
+) -> Result<Vec<PathBuf>, MsgError> {
+    let mut storages: Vec<_> = file
+        .read_storage(storage)?
+        .filter(|entry| entry.is_storage() && entry.name().starts_with(prefix))
+        .map(|entry| entry.path().to_path_buf())
+        .collect();
+    storages.sort();
+    Ok(storages)
+}
+
+fn read_properties<F: Read + Seek>(
+    file: &mut CompoundFile<F>,
+    storage: &Path,
+    header_size: usize,
+) -> Result<Properties, MsgError> {
+    let streams: Vec<_> = file
+        .read_storage(storage)?
+        .filter(|entry| entry.is_stream())
+        .map(|entry| (entry.name().to_string(), entry.path().to_path_buf()))
+        .collect();
+
+    let mut properties = Properties::default();
+    for (name, path) in streams {
+        let mut data = vec![];
+        file.open_stream(&path)?.read_to_end(&mut data)?;
+
+        if name == PROPERTIES_STREAM {
+            for entry in data.get(header_size..).unwrap_or_default().chunks_exact(16) {
+                let kind = u16::from_le_bytes([entry[0], entry[1]]);
+                let id = u16::from_le_bytes([entry[2], entry[3]]);
+                if matches!(kind, PT_LONG | PT_SYSTIME) {
+                    let value = u64::from_le_bytes(entry[8..16].try_into().unwrap());
+                    properties.0.insert(id, Property::Fixed(value));
+                }
+            }
+            continue;
+        }
+
+        // multi-valued properties, whose names have a suffix, are not needed
+        let Some(tag) = name
+            .strip_prefix(SUBSTORAGE_PREFIX)
+            .filter(|tag| tag.len() == 8 && tag.is_ascii())
+        else {
+            continue;
+        };
+        let (Ok(id), Ok(kind)) = (
+            u16::from_str_radix(&tag[..4], 16),
+            u16::from_str_radix(&tag[4..], 16),
+        ) else {
+            continue;
+        };
+
+        let value = match kind {
+            PT_UNICODE => Property::String(decode_unicode(&data)),
+            PT_STRING8 => Property::String(decode_string8(&data)),
+            PT_BINARY => Property::Binary(data),
+            _ => continue,
+        };
+        properties.0.insert(id, value);
+    }
+
+    Ok(properties)
+}
+
+/// Decodes a UTF-16LE string, which may be terminated by a null character
+fn decode_unicode(data: &[u8]) -> String {
+    let units: Vec<u16> = data
+        .chunks_exact(2)
+        .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+        .collect();
+    String::from_utf16_lossy(&units)
+        .trim_end_matches('\0')
+        .to_string()
+}
+
+/// Decodes a string in the code page of the message, read as UTF-8 or else as Latin-1
+fn decode_string8(data: &[u8]) -> String {
+    let data = data.strip_suffix(&[0]).unwrap_or(data);
+    match std::str::from_utf8(data) {
+        Ok(string) => string.to_string(),
+        Err(_) => data.iter().map(|byte| *byte as char).collect(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::email::msg::convert;
+    use cfb::CompoundFile;
+    use mail_parser::{MessageParser, MimeHeaders, PartType};
+    use std::io::{Cursor, Write};
+
+    /// Writes a UTF-16 string property
+    fn write_string(file: &mut CompoundFile<Cursor<Vec<u8>>>, storage: &str, id: u16, value: &str) {
+        let data: Vec<u8> = value.encode_utf16().flat_map(u16::to_le_bytes).collect();
+        write_stream(file, &format!("{storage}/__substg1.0_{id:04X}001F"), &data);
+    }
+
+    /// Writes the fixed-size properties stream, as (type, id, value) entries
+    fn write_fixed(
+        file: &mut CompoundFile<Cursor<Vec<u8>>>,
+        storage: &str,
+        header_size: usize,
+        properties: &[(u16, u16, u64)],
+    ) {
+        let mut data = vec![0; header_size];
+        for (kind, id, value) in properties {
+            data.extend(kind.to_le_bytes());
+            data.extend(id.to_le_bytes());
+            data.extend(6u32.to_le_bytes());
+            data.extend(value.to_le_bytes());
+        }
+        write_stream(file, &format!("{storage}/__properties_version1.0"), &data);
+    }
+
+    fn write_stream(file: &mut CompoundFile<Cursor<Vec<u8>>>, path: &str, data: &[u8]) {
+        file.create_stream(path).unwrap().write_all(data).unwrap();
+    }
+
+    /// Builds a message without transport headers, with a recipient, a file attachment and an
+    /// attached message with transport headers
+    fn build_msg() -> Vec<u8> {
+        let mut file = CompoundFile::create(Cursor::new(vec![])).unwrap();
+
+        write_fixed(&mut file, "", 32, &[(0x0040, 0x0039, 133_000_000_000_000_000)]);
+        write_string(&mut file, "", 0x0037, "Überweisung fällig");
+        write_string(&mut file, "", 0x0C1A, "Billing");
+        write_string(&mut file, "", 0x5D01, "billing@invoices.test");
+        write_string(&mut file, "", 0x1000, "Please pay the attached invoice.");
+        write_string(&mut file, "", 0x1035, "<42@invoices.test>");
+
+        let recipient = "/__recip_version1.0_#00000000";
+        file.create_storage(recipient).unwrap();
+        write_fixed(&mut file, recipient, 8, &[(0x0003, 0x0C15, 1)]);
+        write_string(&mut file, recipient, 0x3001, "Alice");
+        write_string(&mut file, recipient, 0x39FE, "alice@company.test");
+
+        let attachment = "/__attach_version1.0_#00000000";
+        file.create_storage(attachment).unwrap();
+        write_fixed(&mut file, attachment, 8, &[(0x0003, 0x3705, 1)]);
+        write_string(&mut file, attachment, 0x3707, "invoice.pdf");
+        write_string(&mut file, attachment, 0x370E, "application/pdf");
+        write_stream(&mut file, &format!("{attachment}/__substg1.0_37010102"), b"%PDF-1.4");
+
+        let attachment = "/__attach_version1.0_#00000001";
+        file.create_storage(attachment).unwrap();
+        write_fixed(&mut file, attachment, 8, &[(0x0003, 0x3705, 5)]);
+        write_string(&mut file, attachment, 0x3001, "Original");
+        let embedded = format!("{attachment}/__substg1.0_3701000D");
+        file.create_storage(&embedded).unwrap();
+        write_fixed(&mut file, &embedded, 24, &[]);
+        write_string(
+            &mut file,
+            &embedded,
+            0x007D,
+            "Received: from mx.invoices.test\r\n\tby mx.company.test\r\nSubject: Original\r\n\
+             Content-Type: multipart/alternative; boundary=\"x\"\r\n\r\n",
+        );
+        write_string(&mut file, &embedded, 0x1000, "Original body");
+
+        file.flush().unwrap();
+        file.into_inner().into_inner()
+    }
+
+    #[test]
+    fn test_convert() {
+        let email = convert(&build_msg()).unwrap();
+        let message = MessageParser::new().parse(&email).unwrap();
+
+        assert_eq!(message.subject(), Some("Überweisung fällig"));
+        let from = message.from().unwrap().first().unwrap();
+        assert_eq!(from.name(), Some("Billing"));
+        assert_eq!(from.address(), Some("billing@invoices.test"));
+        assert_eq!(message.to().unwrap().first().unwrap().address(), Some("alice@company.test"));
+        assert_eq!(message.message_id(), Some("42@invoices.test"));
+        assert_eq!(message.date().unwrap().year, 2022);
+        assert_eq!(message.body_text(0).unwrap(), "Please pay the attached invoice.");
+
+        let invoice = message.attachment(0).unwrap();
+        assert_eq!(invoice.attachment_name(), Some("invoice.pdf"));
+        assert_eq!(invoice.contents(), b"%PDF-1.4");
+
+        let PartType::Message(original) = &message.attachment(1).unwrap().body else {
+            panic!("the attached message is not parsed as a message");
+        };
+        assert_eq!(original.subject(), Some("Original"));
+        assert_eq!(original.received().unwrap().from().unwrap().to_string(), "mx.invoices.test");
+        assert_eq!(original.body_text(0).unwrap(), "Original body");
+    }
+
+    #[test]
+    fn test_unknown_stream_names() {
+        let mut file = CompoundFile::create(Cursor::new(vec![])).unwrap();
+        write_fixed(&mut file, "", 32, &[]);
+        write_string(&mut file, "", 0x0037, "Invoice");
+        // eight bytes, but not a property tag
+        write_stream(&mut file, "/__substg1.0_abc€xy", b"");
+        file.flush().unwrap();
+
+        let email = convert(&file.into_inner().into_inner()).unwrap();
+        let message = MessageParser::new().parse(&email).unwrap();
+        assert_eq!(message.subject(), Some("Invoice"));
+    }
+
+    #[test]
+    fn test_not_a_message() {
+        let mut file = CompoundFile::create(Cursor::new(vec![])).unwrap();
+        file.create_stream("/WordDocument").unwrap();
+        file.flush().unwrap();
+
+        assert!(convert(&file.into_inner().into_inner()).is_err());
+    }
+}
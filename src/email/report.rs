@@ -0,0 +1,272 @@
+//! Detection of user reports, emails forwarded by a user to have them analyzed.
+//!
+//! A report wraps the suspicious email either as a `message/rfc822` attachment or inline, after a
+//! forwarding marker such as `---------- Forwarded message ---------` and a block of headers.
+//!
+//! Phishing emails also quote or attach other messages, as in reply chains, so an email is only
+//! unwrapped when it is known to be a report: it was submitted as one, or its subject marks it
+//! as forwarded.
+
+use chrono::{DateTime, Utc};
+use mail_parser::{Message, MessageParser};
+use serde::{Deserialize, Serialize};
+
+/// Lines introducing an inline-forwarded message, as written by the common mail clients,
+/// compared in lowercase once the dashes around them are removed
+const FORWARD_MARKERS: &[&str] = &[
+    "forwarded message",
+    "original message",
+    "begin forwarded message:",
+    "message transféré",
+    "weitergeleitete nachricht",
+];
+
+/// Subject prefixes of forwarded emails, as written by the common mail clients,
+/// compared in lowercase
+const FORWARD_PREFIXES: &[&str] = &["fwd:", "fw:", "tr:", "wg:"];
+
+/// Headers of an inline-forwarded message, with the name they are given in the rebuilt message
+const FORWARDED_HEADERS: &[(&str, &str)] = &[
+    ("from", "From"),
+    ("sent", "Date"),
+    ("date", "Date"),
+    ("to", "To"),
+    ("cc", "Cc"),
+    ("reply-to", "Reply-To"),
+    ("subject", "Subject"),
+];
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum ForwardMethod {
+    Attachment,
+    Inline,
+}
+
+/// The wrapper email of a report, whose forwarded message is analyzed in its place
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Report {
+    pub method: ForwardMethod,
+    pub reporter: Option<String>,
+    pub reporter_name: Option<String>,
+    pub subject: Option<String>,
+    pub reported_at: Option<DateTime<Utc>>,
+    pub message_id: Option<String>,
+    /// What the reporter wrote above the forwarded message
+    pub comment: Option<String>,
+}
+
+/// Extracts the forwarded message of a report, if the email is one.
+/// Returns the forwarded message and the description of the wrapper.
+///
+/// Unless `reported` is set, only emails with a forwarded subject are unwrapped.
+///
+/// Messages forwarded as attachments are taken byte for byte, the first one is kept when there
+/// are several. Inline-forwarded messages are rebuilt from the plain text of the wrapper, so that they
+/// only have the forwarded headers and a text body.
+pub fn unwrap_report(wrapper: &Message, reported: bool) -> Option<(Vec<u8>, Report)> {
+    if !reported && !wrapper.subject().is_some_and(is_forwarded_subject) {
+        return None;
+    }
+
+    if let Some(forwarded) = wrapper.attachments().find_map(|part| part.message()) {
+        let email = forwarded.raw_message().to_vec();
+        let comment = wrapper.body_text(0).map(|text| text.trim().to_string());
+        return Some((email, Report::new(wrapper, ForwardMethod::Attachment, comment)));
+    }
+
+    let text = wrapper.body_text(0)?;
+    let (comment, email) = unwrap_inline(&text)?;
+    MessageParser::new().parse(&email)?;
+
+    Some((email.into_bytes(), Report::new(wrapper, ForwardMethod::Inline, Some(comment))))
+}
+
+impl Report {
+    fn new(wrapper: &Message, method: ForwardMethod, comment: Option<String>) -> Self {
+        let reporter = wrapper.from().and_then(|from| from.first());
+
+        Self {
+            method,
+            reporter: reporter.and_then(|r| r.address()).map(ToOwned::to_owned),
+            reporter_name: reporter.and_then(|r| r.name()).map(ToOwned::to_owned),
+            subject: wrapper.subject().map(ToOwned::to_owned),
+            reported_at: wrapper
+                .date()
+                .and_then(|date| DateTime::from_timestamp(date.to_timestamp(), 0)),
+            message_id: wrapper.message_id().map(ToOwned::to_owned),
+            comment: comment.filter(|comment| !comment.is_empty()),
+        }
+    }
+}
+
+fn is_forwarded_subject(subject: &str) -> bool {
+    let subject = subject.trim_start().to_lowercase();
+    FORWARD_PREFIXES.iter().any(|prefix| subject.starts_with(prefix))
+}
+
+/// Splits the text of a wrapper at its forwarding marker, and rebuilds the forwarded message
+/// from the headers and the text that follow it.
+/// Returns `None` if there is no marker followed by at least a `From` header.
+fn unwrap_inline(text: &str) -> Option<(String, String)> {
+    let lines: Vec<&str> = text.lines().collect();
+    let marker = lines.iter().position(|line| is_forward_marker(line))?;
+
+    let mut headers = vec![];
+    let mut body_start = lines.len();
+    for (i, line) in lines.iter().enumerate().skip(marker + 1) {
+        // quoted forwards prefix every line with `>`
+        let line = line.trim_start_matches('>').trim();
+        if line.is_empty() {
+            if headers.is_empty() {
+                continue;
+            }
+            body_start = i + 1;
+            break;
+        }
+
+        let Some((name, value)) = forwarded_header(line) else {
+            body_start = i;
+            break;
+        };
+        headers.extend(value.map(|value| (name, value)));
+    }
+
+    if !headers.iter().any(|(name, _)| *name == "From") {
+        return None;
+    }
+
+    let mut email = String::new();
+    for (name, value) in headers {
+        email.push_str(&format!("{name}: {value}\r\n"));
+    }
+    email.push_str("MIME-Version: 1.0\r\n");
+    email.push_str("Content-Type: text/plain; charset=utf-8\r\n");
+    email.push_str("Content-Transfer-Encoding: 8bit\r\n\r\n");
+    for line in &lines[body_start.min(lines.len())..] {
+        email.push_str(line.strip_prefix('>').map_or(*line, str::trim_start));
+        email.push_str("\r\n");
+    }
+
+    let comment = lines[..marker].join("\n").trim().to_string();
+    Some((comment, email))
+}
+
+fn is_forward_marker(line: &str) -> bool {
+    let line = line
+        .trim()
+        .trim_matches(|c: char| c == '-' || c == '_' || c.is_whitespace())
+        .to_lowercase();
+    FORWARD_MARKERS.contains(&line.as_str())
+}
+
+/// Parses a header of the forwarded message, such as `From: Alice <alice@example.com>`.
+/// The value of dates that are not in the RFC 2822 format of email headers is left out.
+fn forwarded_header(line: &str) -> Option<(&'static str, Option<String>)> {
+    let (name, value) = line.split_once(':')?;
+    let name = name.trim().to_lowercase();
+    let (_, header) = FORWARDED_HEADERS.iter().find(|(n, _)| *n == name)?;
+    let value = value.trim();
+
+    let value = match *header {
+        "Date" => DateTime::parse_from_rfc2822(value)
+            .ok()
+            .map(|date| date.to_rfc2822()),
+        "Subject" => Some(value.to_string()),
+        // Outlook writes addresses as `Alice [mailto:alice@example.com]`
+        _ => Some(value.replace("[mailto:", "<").replace(']', ">")),
+    };
+    Some((header, value))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::email::report::{unwrap_report, ForwardMethod};
+    use mail_parser::MessageParser;
+
+    #[test]
+    fn test_forwarded_as_attachment() {
+        let wrapper = "From: Bob <bob@company.test>\r\n\
+            Subject: Fwd: Your account\r\n\
+            Content-Type: multipart/mixed; boundary=\"b\"\r\n\r\n\
+            --b\r\nContent-Type: text/plain\r\n\r\nIs this legit?\r\n\
+            --b\r\nContent-Type: message/rfc822\r\n\r\n\
+            From: support@phish.test\r\nSubject: Your account\r\n\r\nClick http://phish.test/login\r\n\
+            --b--\r\n";
+        let wrapper = MessageParser::new().parse(wrapper).unwrap();
+
+        let (email, report) = unwrap_report(&wrapper, false).unwrap();
+        let email = MessageParser::new().parse(&email).unwrap();
+        assert_eq!(email.subject(), Some("Your account"));
+        assert_eq!(
+            email.from().unwrap().first().unwrap().address(),
+            Some("support@phish.test")
+        );
+
+        assert_eq!(report.method, ForwardMethod::Attachment);
+        assert_eq!(report.reporter.as_deref(), Some("bob@company.test"));
+        assert_eq!(report.reporter_name.as_deref(), Some("Bob"));
+        assert_eq!(report.subject.as_deref(), Some("Fwd: Your account"));
+        assert_eq!(report.comment.as_deref(), Some("Is this legit?"));
+    }
+
+    #[test]
+    fn test_forwarded_inline() {
+        let wrapper = "From: bob@company.test\r\nSubject: FW: Invoice\r\n\r\n\
+            Got this today.\r\n\r\n\
+            -----Original Message-----\r\n\
+            From: Billing [mailto:billing@invoices.test]\r\n\
+            Sent: Monday, October 14, 2024 9:00 AM\r\n\
+            To: Bob\r\n\
+            Subject: Invoice\r\n\r\n\
+            Pay at http://invoices.test/pay\r\n";
+        let wrapper = MessageParser::new().parse(wrapper).unwrap();
+
+        let (email, report) = unwrap_report(&wrapper, false).unwrap();
+        let email = MessageParser::new().parse(&email).unwrap();
+        assert_eq!(email.subject(), Some("Invoice"));
+        let from = email.from().unwrap().first().unwrap();
+        assert_eq!(from.address(), Some("billing@invoices.test"));
+        assert_eq!(from.name(), Some("Billing"));
+        assert_eq!(email.body_text(0).unwrap().trim(), "Pay at http://invoices.test/pay");
+
+        assert_eq!(report.method, ForwardMethod::Inline);
+        assert_eq!(report.comment.as_deref(), Some("Got this today."));
+    }
+
+    #[test]
+    fn test_not_a_report() {
+        let email = "From: alice@company.test\r\nSubject: Lunch\r\n\r\nSee you at noon.\r\n";
+        let email = MessageParser::new().parse(email).unwrap();
+
+        assert!(unwrap_report(&email, false).is_none());
+    }
+
+    #[test]
+    fn test_reply_chain() {
+        // a BEC email quoting a made-up conversation is analyzed as it is
+        let email = "From: ceo@company-test.test\r\nSubject: RE: Wire transfer\r\n\r\n\
+            Please process it today.\r\n\r\n\
+            -----Original Message-----\r\n\
+            From: Alice [mailto:alice@company.test]\r\n\
+            Sent: Monday, October 14, 2024 9:00 AM\r\n\
+            Subject: Wire transfer\r\n\r\n\
+            Which account should I use?\r\n";
+        let email = MessageParser::new().parse(email).unwrap();
+        assert!(unwrap_report(&email, false).is_none());
+
+        let attached = "From: billing@invoices.test\r\nSubject: Invoice\r\n\
+            Content-Type: multipart/mixed; boundary=\"b\"\r\n\r\n\
+            --b\r\nContent-Type: text/plain\r\n\r\nSee the attached thread.\r\n\
+            --b\r\nContent-Type: message/rfc822\r\n\r\n\
+            From: alice@company.test\r\nSubject: Invoice\r\n\r\nApproved.\r\n\
+            --b--\r\n";
+        let attached = MessageParser::new().parse(attached).unwrap();
+        assert!(unwrap_report(&attached, false).is_none());
+
+        // unless it was submitted as a report
+        let (_, report) = unwrap_report(&attached, true).unwrap();
+        assert_eq!(report.method, ForwardMethod::Attachment);
+    }
+}
@@ -0,0 +1,162 @@
+mod cassette;
+
+use crate::config::{HttpConfig, HttpMode};
+use log::info;
+use reqwest::{Client, IntoUrl, RequestBuilder, Response};
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+
+use cassette::{Cassette, CassetteStore, RecordedRequest};
+
+/// HTTP client shared by everything calling external services.
+///
+/// Depending on its mode, requests are sent as is, sent and saved to cassettes, or answered
+/// from the saved cassettes without any network access.
+#[derive(Clone, Default)]
+pub struct HttpClient {
+    client: Client,
+    recorder: Option<Arc<Recorder>>,
+}
+
+struct Recorder {
+    store: CassetteStore,
+    replay: bool,
+}
+
+#[derive(Debug)]
+pub enum HttpError {
+    Request(reqwest::Error),
+    Cassette(String),
+}
+
+impl From<reqwest::Error> for HttpError {
+    fn from(value: reqwest::Error) -> Self {
+        HttpError::Request(value)
+    }
+}
+
+impl Display for HttpError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HttpError::Request(err) => write!(f, "{err}"),
+            HttpError::Cassette(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+impl HttpClient {
+    pub fn new(config: &HttpConfig) -> Self {
+        Self::with_client(config, Client::new())
+    }
+
+    /// Returns a client in the configured mode, sending its requests through the given client
+    pub fn with_client(config: &HttpConfig, client: Client) -> Self {
+        let recorder = match config.mode {
+            HttpMode::Live => None,
+            mode => {
+                info!(
+                    "HTTP requests are {} cassettes in {}",
+                    if mode == HttpMode::Replay { "replayed from" } else { "recorded to" },
+                    config.cassettes.display()
+                );
+                Some(Arc::new(Recorder {
+                    store: CassetteStore::new(config.cassettes.clone()),
+                    replay: mode == HttpMode::Replay,
+                }))
+            }
+        };
+
+        Self { client, recorder }
+    }
+
+    pub fn get(&self, url: impl IntoUrl) -> RequestBuilder {
+        self.client.get(url)
+    }
+
+    pub fn post(&self, url: impl IntoUrl) -> RequestBuilder {
+        self.client.post(url)
+    }
+
+    /// Sends a request built from this client
+    pub async fn send(&self, request: RequestBuilder) -> Result<Response, HttpError> {
+        let Some(recorder) = &self.recorder else {
+            return Ok(request.send().await?);
+        };
+
+        let request = request.build()?;
+        let recorded = RecordedRequest::new(&request);
+
+        if recorder.replay {
+            return recorder
+                .store
+                .load(&recorded)?
+                .map(Cassette::into_response)
+                .ok_or_else(|| {
+                    HttpError::Cassette(format!(
+                        "no recorded response for {} {}",
+                        recorded.method, recorded.url
+                    ))
+                });
+        }
+
+        let response = self.client.execute(request).await?;
+        let cassette = Cassette::record(recorded, response).await?;
+        recorder.store.save(&cassette)?;
+        Ok(cassette.into_response())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::{HttpConfig, HttpMode};
+    use crate::http::HttpClient;
+    use rocket::async_test;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[async_test]
+    async fn test_record_replay() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        // a stand-in answering a single request, so that the replay can't reach it
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = vec![0; 4096];
+            let _ = socket.read(&mut request).await.unwrap();
+
+            let body = r#"{"verdict": "clean"}"#;
+            let response = format!(
+                "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            socket.write_all(response.as_bytes()).await.unwrap();
+        });
+
+        let cassettes = std::env::temp_dir().join(format!("cassettes-{}", std::process::id()));
+        let url = format!("http://{address}/analyze");
+        let config = |mode| HttpConfig {
+            mode,
+            cassettes: cassettes.clone(),
+        };
+
+        let recorder = HttpClient::new(&config(HttpMode::Record));
+        let response = recorder.send(recorder.post(&url).body("text")).await.unwrap();
+        assert_eq!(response.text().await.unwrap(), r#"{"verdict": "clean"}"#);
+        server.await.unwrap();
+
+        let replayer = HttpClient::new(&config(HttpMode::Replay));
+        let response = replayer.send(replayer.post(&url).body("text")).await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["content-type"], "application/json");
+        assert_eq!(response.text().await.unwrap(), r#"{"verdict": "clean"}"#);
+
+        // a request that was never recorded is not sent
+        let missing = replayer.send(replayer.post(&url).body("other text")).await;
+        assert!(missing.is_err());
+
+        std::fs::remove_dir_all(&cassettes).unwrap();
+    }
+}
@@ -0,0 +1,179 @@
+use crate::http::HttpError;
+use crate::secrets::SECRETS;
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{Request, Response};
+use rocket::serde::json::serde_json;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// Directory of cassettes, holding one recorded interaction per file under
+/// `<host>/<method>-<hash>.json`. The hash identifies the method, URL and body of the request.
+pub struct CassetteStore {
+    directory: PathBuf,
+}
+
+/// A request and the response it received
+#[derive(Serialize, Deserialize)]
+pub struct Cassette {
+    request: RecordedRequest,
+    response: RecordedResponse,
+}
+
+/// The parts of a request identifying it. Headers are left out, as they carry the credentials.
+#[derive(Serialize, Deserialize)]
+pub struct RecordedRequest {
+    pub method: String,
+    pub url: String,
+    pub body: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RecordedResponse {
+    status: u16,
+    content_type: Option<String>,
+    body: String,
+    /// Whether the body is encoded in base64, when it is not valid UTF-8
+    #[serde(default)]
+    base64: bool,
+}
+
+impl RecordedRequest {
+    pub fn new(request: &Request) -> Self {
+        Self {
+            method: request.method().to_string(),
+            url: redact(request.url().as_str()),
+            body: request
+                .body()
+                .and_then(|body| body.as_bytes())
+                .map(|body| redact(&String::from_utf8_lossy(body))),
+        }
+    }
+
+    fn hash(&self) -> u64 {
+        let mut hash = Fnv1a::default();
+        hash.write(self.method.as_bytes());
+        hash.write(b" ");
+        hash.write(self.url.as_bytes());
+        if let Some(body) = &self.body {
+            hash.write(b"\n");
+            hash.write(body.as_bytes());
+        }
+        hash.0
+    }
+}
+
+impl Cassette {
+    pub async fn record(request: RecordedRequest, response: Response) -> Result<Self, HttpError> {
+        let status = response.status().as_u16();
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(ToOwned::to_owned);
+
+        let bytes = response.bytes().await?;
+        let (body, base64) = match String::from_utf8(bytes.to_vec()) {
+            Ok(body) => (redact(&body), false),
+            Err(_) => (BASE64_STANDARD.encode(&bytes), true),
+        };
+
+        Ok(Self {
+            request,
+            response: RecordedResponse {
+                status,
+                content_type,
+                body,
+                base64,
+            },
+        })
+    }
+
+    pub fn into_response(self) -> Response {
+        let response = self.response;
+        let body = if response.base64 {
+            BASE64_STANDARD.decode(&response.body).unwrap_or_default()
+        } else {
+            response.body.into_bytes()
+        };
+
+        let mut builder = http::Response::builder().status(response.status);
+        if let Some(content_type) = response.content_type {
+            builder = builder.header(CONTENT_TYPE, content_type);
+        }
+        Response::from(builder.body(body).unwrap())
+    }
+}
+
+impl CassetteStore {
+    pub fn new(directory: PathBuf) -> Self {
+        Self { directory }
+    }
+
+    fn path(&self, request: &RecordedRequest) -> PathBuf {
+        let host = url::Url::parse(&request.url)
+            .ok()
+            .and_then(|url| url.host_str().map(ToOwned::to_owned))
+            .unwrap_or_else(|| String::from("unknown"));
+
+        self.directory.join(host).join(format!(
+            "{}-{:016x}.json",
+            request.method.to_lowercase(),
+            request.hash()
+        ))
+    }
+
+    pub fn load(&self, request: &RecordedRequest) -> Result<Option<Cassette>, HttpError> {
+        let path = self.path(request);
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let content = std::fs::read_to_string(&path).map_err(|err| cassette_error(&path, err))?;
+        serde_json::from_str(&content)
+            .map(Some)
+            .map_err(|err| cassette_error(&path, err))
+    }
+
+    /// Saves a cassette, replacing the previous recording of the same request
+    pub fn save(&self, cassette: &Cassette) -> Result<(), HttpError> {
+        let path = self.path(&cassette.request);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).map_err(|err| cassette_error(&path, err))?;
+        }
+
+        let content = serde_json::to_string_pretty(cassette).unwrap();
+        std::fs::write(&path, content).map_err(|err| cassette_error(&path, err))
+    }
+}
+
+fn cassette_error(path: &std::path::Path, err: impl std::fmt::Display) -> HttpError {
+    HttpError::Cassette(format!("{}: {err}", path.display()))
+}
+
+/// Keeps the credentials out of the cassettes
+fn redact(text: &str) -> String {
+    match SECRETS.get() {
+        Some(secrets) => secrets.redact(text),
+        None => text.to_string(),
+    }
+}
+
+/// 64-bit FNV-1a, whose output does not depend on the Rust version, unlike `DefaultHasher`
+struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+}
+
+impl Fnv1a {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+}
@@ -0,0 +1,73 @@
+//! Services submitting the emails of external sources, such as a reporting mailbox, as jobs.
+
+mod imap;
+mod maildir;
+mod smtp;
+
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+
+pub use imap::ImapIngestion;
+pub use maildir::MaildirIngestion;
+pub use smtp::{SmtpEnvelope, SmtpReceiver};
+
+/// Where the email of a job was ingested from, when it was not submitted through the API
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum JobSource {
+    /// A message of an IMAP folder, `mailbox` is the name of the configured mailbox
+    #[serde(rename_all = "camelCase")]
+    Imap {
+        mailbox: String,
+        folder: String,
+        uid: u32,
+    },
+    /// A file of a watched directory, `directory` is the name of the configured directory
+    #[serde(rename_all = "camelCase")]
+    Maildir { directory: String, file: String },
+    /// A message received by the SMTP listener
+    Smtp(SmtpEnvelope),
+}
+
+#[derive(Debug)]
+pub enum IngestionError {
+    Std(Box<dyn std::error::Error + Send + Sync>),
+    /// The secret holding the credentials of the source has no key
+    MissingCredentials(String),
+    /// The connection was lost during a previous command
+    Disconnected,
+}
+
+impl<E: std::error::Error + Send + Sync + 'static> From<E> for IngestionError {
+    fn from(value: E) -> Self {
+        IngestionError::Std(Box::new(value))
+    }
+}
+
+impl Display for IngestionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IngestionError::Std(err) => write!(f, "{err}"),
+            IngestionError::MissingCredentials(secret) => {
+                write!(f, "no {secret} password is configured")
+            }
+            IngestionError::Disconnected => write!(f, "the connection was lost"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::state::{Jobs, RetentionPolicy};
+    use crate::storage::SqliteStorage;
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+
+    /// Jobs stored in memory, for the tests of the ingestion services. The services are given
+    /// no analyzer, so that their jobs are created without being analyzed.
+    pub fn memory_jobs() -> Arc<Mutex<Jobs>> {
+        let storage = SqliteStorage::open(":memory:").unwrap();
+        let jobs = Jobs::load(Box::new(storage), RetentionPolicy::default()).unwrap();
+        Arc::new(Mutex::new(jobs))
+    }
+}
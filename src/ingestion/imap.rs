@@ -0,0 +1,647 @@
+use crate::analysis::{AnalysisOptions, AnalysisTimeouts, MailAnalyzer};
+use crate::config::ImapConfig;
+use crate::ingestion::{IngestionError, JobSource};
+use crate::secrets::{KeyRing, SECRETS};
+use crate::state::Jobs;
+use crate::submission::submit_email;
+use async_imap::Session;
+use async_trait::async_trait;
+use log::{info, warn};
+use rocket::futures::TryStreamExt;
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio_native_tls::{native_tls, TlsConnector};
+
+/// Delay before connecting again after a connection error
+const RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// Opens sessions on an IMAP server
+#[async_trait]
+pub trait ImapConnector: Send + Sync {
+    async fn connect(&self) -> Result<Box<dyn ImapSession>, IngestionError>;
+}
+
+/// An authenticated IMAP session, reduced to the commands needed by the ingestion.
+/// Messages are identified by their UID in the selected folder.
+#[async_trait]
+pub trait ImapSession: Send {
+    async fn select(&mut self, folder: &str) -> Result<(), IngestionError>;
+
+    /// Returns the messages of the selected folder that don't have the given keyword, by UID
+    async fn search_without_keyword(&mut self, keyword: &str) -> Result<Vec<u32>, IngestionError>;
+
+    /// Returns the raw message, without marking it as seen
+    async fn fetch(&mut self, uid: u32) -> Result<Option<Vec<u8>>, IngestionError>;
+
+    async fn add_keyword(&mut self, uid: u32, keyword: &str) -> Result<(), IngestionError>;
+
+    async fn move_message(&mut self, uid: u32, folder: &str) -> Result<(), IngestionError>;
+
+    fn supports_idle(&self) -> bool;
+
+    /// Waits for a change of the selected folder, for at most `timeout`
+    async fn idle(&mut self, timeout: Duration) -> Result<(), IngestionError>;
+
+    async fn logout(&mut self) -> Result<(), IngestionError>;
+}
+
+/// Submits the messages of the folders of an IMAP mailbox as jobs, as they arrive.
+///
+/// Each folder is watched by its own session. Submitted messages get the `processed_flag`
+/// keyword, which excludes them from the next checks, and are moved to `move_to` if it is set.
+pub struct ImapIngestion {
+    config: ImapConfig,
+    connector: Box<dyn ImapConnector>,
+    jobs: Arc<Mutex<Jobs>>,
+    analyzers: Vec<Arc<dyn MailAnalyzer>>,
+    timeouts: AnalysisTimeouts,
+}
+
+impl ImapIngestion {
+    pub fn new(
+        config: ImapConfig,
+        jobs: Arc<Mutex<Jobs>>,
+        analyzers: Vec<Arc<dyn MailAnalyzer>>,
+        timeouts: AnalysisTimeouts,
+    ) -> Self {
+        let connector = RemoteConnector {
+            password: SECRETS.get().unwrap().key_ring(&config.password_secret),
+            config: config.clone(),
+        };
+        Self::with_connector(config, Box::new(connector), jobs, analyzers, timeouts)
+    }
+
+    pub fn with_connector(
+        config: ImapConfig,
+        connector: Box<dyn ImapConnector>,
+        jobs: Arc<Mutex<Jobs>>,
+        analyzers: Vec<Arc<dyn MailAnalyzer>>,
+        timeouts: AnalysisTimeouts,
+    ) -> Self {
+        Self {
+            config,
+            connector,
+            jobs,
+            analyzers,
+            timeouts,
+        }
+    }
+
+    /// Spawns the watch of every configured folder
+    pub fn start(self: Arc<Self>) {
+        for folder in &self.config.folders {
+            tokio::spawn(self.clone().watch_folder(folder.clone()));
+        }
+    }
+
+    /// Ingests the new messages of a folder forever, connecting again after errors
+    async fn watch_folder(self: Arc<Self>, folder: String) {
+        let name = &self.config.name;
+        loop {
+            match self.connector.connect().await {
+                Ok(mut session) => {
+                    info!("Watching IMAP folder {folder} of {name}");
+                    let err = self.watch_session(session.as_mut(), &folder).await;
+                    warn!("IMAP folder {folder} of {name}: {err}");
+                    let _ = session.logout().await;
+                }
+                Err(err) => warn!("could not connect to IMAP mailbox {name}: {err}"),
+            }
+            tokio::time::sleep(RECONNECT_DELAY).await;
+        }
+    }
+
+    /// Returns the error that ended the session
+    async fn watch_session(&self, session: &mut dyn ImapSession, folder: &str) -> IngestionError {
+        if let Err(err) = session.select(folder).await {
+            return err;
+        }
+
+        let interval = Duration::from_secs(self.config.poll_interval);
+        loop {
+            if let Err(err) = self.ingest_folder(session, folder).await {
+                return err;
+            }
+
+            if self.config.idle && session.supports_idle() {
+                if let Err(err) = session.idle(interval).await {
+                    return err;
+                }
+            } else {
+                tokio::time::sleep(interval).await;
+            }
+        }
+    }
+
+    /// Submits the messages of the selected folder that were not processed yet.
+    /// Messages that are not valid emails are flagged as processed too, and are only logged.
+    ///
+    /// Returns the number of jobs created.
+    pub async fn ingest_folder(
+        &self,
+        session: &mut dyn ImapSession,
+        folder: &str,
+    ) -> Result<usize, IngestionError> {
+        let mut uids = session
+            .search_without_keyword(&self.config.processed_flag)
+            .await?;
+        uids.sort();
+
+        let mut created = 0;
+        for uid in uids {
+            let Some(content) = session.fetch(uid).await? else {
+                continue;
+            };
+
+            let source = JobSource::Imap {
+                mailbox: self.config.name.clone(),
+                folder: folder.to_string(),
+                uid,
+            };
+            let options = AnalysisOptions::default();
+            let submitted = submit_email(
+                &self.jobs,
+                content,
+                self.config.reports,
+                Some(source),
+                options,
+                &self.analyzers,
+                &self.timeouts,
+            );
+            match submitted.await {
+                Some(job) => {
+                    info!("Job {} created from message {uid} of {folder}", job.id);
+                    created += 1;
+                }
+                None => warn!("message {uid} of IMAP folder {folder} is not a valid email"),
+            }
+
+            // flagged first, so that it is not submitted again if the move fails
+            session
+                .add_keyword(uid, &self.config.processed_flag)
+                .await?;
+            if let Some(destination) = &self.config.move_to {
+                session.move_message(uid, destination).await?;
+            }
+        }
+
+        Ok(created)
+    }
+}
+
+trait ImapStream: AsyncRead + AsyncWrite + Unpin + Send + Debug {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + Debug> ImapStream for T {}
+
+struct RemoteConnector {
+    config: ImapConfig,
+    password: Arc<KeyRing>,
+}
+
+#[async_trait]
+impl ImapConnector for RemoteConnector {
+    async fn connect(&self) -> Result<Box<dyn ImapSession>, IngestionError> {
+        let password = self
+            .password
+            .key()
+            .ok_or_else(|| IngestionError::MissingCredentials(self.config.password_secret.clone()))?;
+
+        let host = self.config.host.as_str();
+        let tcp = TcpStream::connect((host, self.config.port)).await?;
+        let stream: Box<dyn ImapStream> = if self.config.tls {
+            let connector = TlsConnector::from(native_tls::TlsConnector::new()?);
+            Box::new(connector.connect(host, tcp).await?)
+        } else {
+            Box::new(tcp)
+        };
+
+        let mut client = async_imap::Client::new(stream);
+        // the server greets the client before any command
+        client.read_response().await?;
+
+        let mut session = client
+            .login(&self.config.username, password.expose())
+            .await
+            .map_err(|(err, _)| err)?;
+        let capabilities = session.capabilities().await?;
+
+        Ok(Box::new(RemoteSession {
+            supports_idle: capabilities.has_str("IDLE"),
+            supports_move: capabilities.has_str("MOVE"),
+            supports_uidplus: capabilities.has_str("UIDPLUS"),
+            session: Some(session),
+        }))
+    }
+}
+
+struct RemoteSession {
+    /// Taken while idling, `None` if the connection was lost then
+    session: Option<Session<Box<dyn ImapStream>>>,
+    supports_idle: bool,
+    supports_move: bool,
+    supports_uidplus: bool,
+}
+
+impl RemoteSession {
+    fn session(&mut self) -> Result<&mut Session<Box<dyn ImapStream>>, IngestionError> {
+        self.session.as_mut().ok_or(IngestionError::Disconnected)
+    }
+
+    async fn store(&mut self, uid: u32, query: &str) -> Result<(), IngestionError> {
+        self.session()?
+            .uid_store(uid.to_string(), query)
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ImapSession for RemoteSession {
+    async fn select(&mut self, folder: &str) -> Result<(), IngestionError> {
+        self.session()?.select(folder).await?;
+        Ok(())
+    }
+
+    async fn search_without_keyword(&mut self, keyword: &str) -> Result<Vec<u32>, IngestionError> {
+        let uids = self
+            .session()?
+            .uid_search(format!("UNKEYWORD {keyword}"))
+            .await?;
+        Ok(uids.into_iter().collect())
+    }
+
+    async fn fetch(&mut self, uid: u32) -> Result<Option<Vec<u8>>, IngestionError> {
+        let messages: Vec<_> = self
+            .session()?
+            .uid_fetch(uid.to_string(), "BODY.PEEK[]")
+            .await?
+            .try_collect()
+            .await?;
+
+        Ok(messages
+            .iter()
+            .find(|m| m.uid == Some(uid))
+            .and_then(|m| m.body())
+            .map(ToOwned::to_owned))
+    }
+
+    async fn add_keyword(&mut self, uid: u32, keyword: &str) -> Result<(), IngestionError> {
+        self.store(uid, &format!("+FLAGS.SILENT ({keyword})")).await
+    }
+
+    async fn move_message(&mut self, uid: u32, folder: &str) -> Result<(), IngestionError> {
+        if self.supports_move {
+            self.session()?.uid_mv(uid.to_string(), folder).await?;
+            return Ok(());
+        }
+
+        self.session()?.uid_copy(uid.to_string(), folder).await?;
+        self.store(uid, "+FLAGS.SILENT (\\Deleted)").await?;
+
+        // without UIDPLUS, the messages deleted by other clients are expunged too
+        if self.supports_uidplus {
+            self.session()?
+                .uid_expunge(uid.to_string())
+                .await?
+                .try_collect::<Vec<_>>()
+                .await?;
+        } else {
+            self.session()?
+                .expunge()
+                .await?
+                .try_collect::<Vec<_>>()
+                .await?;
+        }
+        Ok(())
+    }
+
+    fn supports_idle(&self) -> bool {
+        self.supports_idle
+    }
+
+    async fn idle(&mut self, timeout: Duration) -> Result<(), IngestionError> {
+        let session = self.session.take().ok_or(IngestionError::Disconnected)?;
+
+        let mut idle = session.idle();
+        idle.init().await?;
+        {
+            let (wait, _stop) = idle.wait_with_timeout(timeout);
+            wait.await?;
+        }
+        self.session = Some(idle.done().await?);
+
+        Ok(())
+    }
+
+    async fn logout(&mut self) -> Result<(), IngestionError> {
+        self.session()?.logout().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::analysis::AnalysisTimeouts;
+    use crate::config::ImapConfig;
+    use crate::ingestion::imap::{ImapConnector, ImapIngestion, ImapSession, RemoteConnector};
+    use crate::ingestion::test::memory_jobs;
+    use crate::ingestion::{IngestionError, JobSource};
+    use crate::secrets::{KeyRing, Secret};
+    use async_trait::async_trait;
+    use rocket::async_test;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+    use tokio::task::JoinHandle;
+
+    struct StoredMessage {
+        uid: u32,
+        content: Vec<u8>,
+        keywords: Vec<String>,
+    }
+
+    /// Stand-in for an IMAP server, holding its folders in memory
+    #[derive(Default)]
+    struct MemoryMailbox {
+        folders: HashMap<String, Vec<StoredMessage>>,
+        selected: String,
+    }
+
+    impl MemoryMailbox {
+        fn message(&mut self, uid: u32) -> Result<&mut StoredMessage, IngestionError> {
+            self.folders
+                .get_mut(&self.selected)
+                .and_then(|folder| folder.iter_mut().find(|m| m.uid == uid))
+                .ok_or(IngestionError::Disconnected)
+        }
+    }
+
+    #[async_trait]
+    impl ImapSession for MemoryMailbox {
+        async fn select(&mut self, folder: &str) -> Result<(), IngestionError> {
+            self.selected = folder.to_string();
+            Ok(())
+        }
+
+        async fn search_without_keyword(
+            &mut self,
+            keyword: &str,
+        ) -> Result<Vec<u32>, IngestionError> {
+            let folder = self.folders.entry(self.selected.clone()).or_default();
+            Ok(folder
+                .iter()
+                .filter(|m| !m.keywords.iter().any(|k| k == keyword))
+                .map(|m| m.uid)
+                .collect())
+        }
+
+        async fn fetch(&mut self, uid: u32) -> Result<Option<Vec<u8>>, IngestionError> {
+            Ok(Some(self.message(uid)?.content.clone()))
+        }
+
+        async fn add_keyword(&mut self, uid: u32, keyword: &str) -> Result<(), IngestionError> {
+            self.message(uid)?.keywords.push(keyword.to_string());
+            Ok(())
+        }
+
+        async fn move_message(&mut self, uid: u32, folder: &str) -> Result<(), IngestionError> {
+            let source = self.folders.get_mut(&self.selected).unwrap();
+            let index = source.iter().position(|m| m.uid == uid).unwrap();
+            let message = source.remove(index);
+            self.folders.entry(folder.to_string()).or_default().push(message);
+            Ok(())
+        }
+
+        fn supports_idle(&self) -> bool {
+            false
+        }
+
+        async fn idle(&mut self, _timeout: Duration) -> Result<(), IngestionError> {
+            Ok(())
+        }
+
+        async fn logout(&mut self) -> Result<(), IngestionError> {
+            Ok(())
+        }
+    }
+
+    struct NoConnector;
+
+    #[async_trait]
+    impl ImapConnector for NoConnector {
+        async fn connect(&self) -> Result<Box<dyn ImapSession>, IngestionError> {
+            Err(IngestionError::Disconnected)
+        }
+    }
+
+    #[async_test]
+    async fn test_ingest_folder() {
+        let jobs = memory_jobs();
+
+        let config = ImapConfig {
+            name: String::from("reports"),
+            move_to: Some(String::from("Processed")),
+            ..ImapConfig::default()
+        };
+        let ingestion = ImapIngestion::with_connector(
+            config,
+            Box::new(NoConnector),
+            jobs.clone(),
+            vec![],
+            AnalysisTimeouts::default(),
+        );
+
+        let message = |uid: u32, content: &str, keywords: &[&str]| StoredMessage {
+            uid,
+            content: content.as_bytes().to_vec(),
+            keywords: keywords.iter().map(|k| k.to_string()).collect(),
+        };
+        let mut mailbox = MemoryMailbox::default();
+        mailbox.folders.insert(
+            String::from("INBOX"),
+            vec![
+                message(1, "Subject: already done\r\n\r\n", &["$MailAnalyzer"]),
+                message(2, "Subject: new\r\n\r\nhello", &[]),
+                message(3, "", &[]),
+            ],
+        );
+
+        mailbox.select("INBOX").await.unwrap();
+        let created = ingestion.ingest_folder(&mut mailbox, "INBOX").await.unwrap();
+        assert_eq!(created, 1);
+
+        let jobs = jobs.lock().await;
+        let job = jobs.iter_jobs().next().unwrap();
+        assert_eq!(job.email().subject(), Some("new"));
+        assert_eq!(
+            job.source,
+            Some(JobSource::Imap {
+                mailbox: String::from("reports"),
+                folder: String::from("INBOX"),
+                uid: 2,
+            })
+        );
+
+        let remaining: Vec<_> = mailbox.folders["INBOX"].iter().map(|m| m.uid).collect();
+        assert_eq!(remaining, [1]);
+        let processed = &mailbox.folders["Processed"];
+        assert_eq!(processed.iter().map(|m| m.uid).collect::<Vec<_>>(), [2, 3]);
+        assert!(processed.iter().all(|m| m.keywords == ["$MailAnalyzer"]));
+
+        // processed messages are not submitted again
+        assert_eq!(ingestion.ingest_folder(&mut mailbox, "INBOX").await.unwrap(), 0);
+    }
+
+    const REPORT: &str = "Subject: Your account\r\n\r\nhello";
+
+    /// Serves a single connection: greets the client, then checks that each command is the
+    /// next one of the script and writes its answer, where `{tag}` is the tag of the command
+    async fn scripted_server(script: Vec<(&'static str, String)>) -> (u16, JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            let mut reader = BufReader::new(reader);
+            writer.write_all(b"* OK IMAP4rev1 ready\r\n").await.unwrap();
+
+            let mut tag = String::new();
+            for (expected, answer) in script {
+                let mut line = String::new();
+                reader.read_line(&mut line).await.unwrap();
+                let line = line.trim_end();
+
+                // the end of IDLE is untagged, and answered with the tag of IDLE
+                let command = match line.split_once(' ') {
+                    Some((command_tag, command)) => {
+                        tag = command_tag.to_string();
+                        command
+                    }
+                    None => line,
+                };
+                assert_eq!(command, expected);
+                writer
+                    .write_all(answer.replace("{tag}", &tag).as_bytes())
+                    .await
+                    .unwrap();
+            }
+        });
+
+        (port, server)
+    }
+
+    /// Script of a session that logs in, then submits message 7 of INBOX
+    fn ingestion_script(capabilities: &str) -> Vec<(&'static str, String)> {
+        let fetched = format!(
+            "* 1 FETCH (UID 7 BODY[] {{{}}}\r\n{REPORT})\r\n{{tag}} OK FETCH completed\r\n",
+            REPORT.len()
+        );
+        vec![
+            (
+                r#"LOGIN "phishing@example.com" "hunter2""#,
+                String::from("{tag} OK LOGIN completed\r\n"),
+            ),
+            (
+                "CAPABILITY",
+                format!("* CAPABILITY IMAP4rev1 {capabilities}\r\n{{tag}} OK done\r\n"),
+            ),
+            (
+                r#"SELECT "INBOX""#,
+                String::from("* 1 EXISTS\r\n{tag} OK [READ-WRITE] SELECT completed\r\n"),
+            ),
+            (
+                "UID SEARCH UNKEYWORD $MailAnalyzer",
+                String::from("* SEARCH 7\r\n{tag} OK SEARCH completed\r\n"),
+            ),
+            ("UID FETCH 7 BODY.PEEK[]", fetched),
+            (
+                "UID STORE 7 +FLAGS.SILENT ($MailAnalyzer)",
+                String::from("{tag} OK STORE completed\r\n"),
+            ),
+        ]
+    }
+
+    async fn remote_ingestion(port: u16) -> ImapIngestion {
+        let config = ImapConfig {
+            name: String::from("reports"),
+            host: String::from("127.0.0.1"),
+            port,
+            tls: false,
+            username: String::from("phishing@example.com"),
+            move_to: Some(String::from("Processed")),
+            ..ImapConfig::default()
+        };
+        let connector = RemoteConnector {
+            config: config.clone(),
+            password: Arc::new(KeyRing::new("imap", vec![Secret::new("hunter2")])),
+        };
+        ImapIngestion::with_connector(
+            config,
+            Box::new(connector),
+            memory_jobs(),
+            vec![],
+            AnalysisTimeouts::default(),
+        )
+    }
+
+    #[async_test]
+    async fn test_remote_session() {
+        let mut script = ingestion_script("IDLE MOVE");
+        script.extend([
+            (r#"UID MOVE 7 "Processed""#, String::from("{tag} OK MOVE completed\r\n")),
+            ("IDLE", String::from("+ idling\r\n* 2 EXISTS\r\n")),
+            ("DONE", String::from("{tag} OK IDLE terminated\r\n")),
+            ("LOGOUT", String::from("* BYE\r\n{tag} OK LOGOUT completed\r\n")),
+        ]);
+        let (port, server) = scripted_server(script).await;
+        let ingestion = remote_ingestion(port).await;
+
+        let mut session = ingestion.connector.connect().await.unwrap();
+        assert!(session.supports_idle());
+        session.select("INBOX").await.unwrap();
+        let created = ingestion.ingest_folder(session.as_mut(), "INBOX").await.unwrap();
+        assert_eq!(created, 1);
+        // returns once the server announces the new message
+        session.idle(Duration::from_secs(30)).await.unwrap();
+        session.logout().await.unwrap();
+        server.await.unwrap();
+
+        let jobs = ingestion.jobs.lock().await;
+        let job = jobs.iter_jobs().next().unwrap();
+        assert_eq!(job.email().subject(), Some("Your account"));
+    }
+
+    #[async_test]
+    async fn test_remote_session_without_move() {
+        let mut script = ingestion_script("UIDPLUS");
+        script.extend([
+            (r#"UID COPY 7 "Processed""#, String::from("{tag} OK COPY completed\r\n")),
+            (
+                r"UID STORE 7 +FLAGS.SILENT (\Deleted)",
+                String::from("{tag} OK STORE completed\r\n"),
+            ),
+            (
+                "UID EXPUNGE 7",
+                String::from("* 1 EXPUNGE\r\n{tag} OK EXPUNGE completed\r\n"),
+            ),
+        ]);
+        let (port, server) = scripted_server(script).await;
+        let ingestion = remote_ingestion(port).await;
+
+        let mut session = ingestion.connector.connect().await.unwrap();
+        assert!(!session.supports_idle());
+        session.select("INBOX").await.unwrap();
+        let created = ingestion.ingest_folder(session.as_mut(), "INBOX").await.unwrap();
+        assert_eq!(created, 1);
+        server.await.unwrap();
+    }
+}
@@ -0,0 +1,267 @@
+use crate::analysis::{AnalysisOptions, AnalysisTimeouts, MailAnalyzer};
+use crate::config::MaildirConfig;
+use crate::ingestion::{IngestionError, JobSource};
+use crate::state::Jobs;
+use crate::submission::submit_email;
+use chrono::Utc;
+use log::{info, warn};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::Mutex;
+
+/// Files of a plain directory modified more recently than this may still be written, and are
+/// left for the next check. Maildir deliveries are atomic and don't need it.
+const SETTLE_DELAY: Duration = Duration::from_secs(5);
+
+/// Submits the files dropped in a directory as jobs, as they arrive.
+///
+/// The directory is either a Maildir, whose `new` messages are moved to `cur` once submitted,
+/// or a plain directory, whose files are moved to a `processed` subdirectory. Both can be moved
+/// to the `archive` directory instead.
+///
+/// A file is only moved after its job is created. If the move did not happen, e.g. because the
+/// server stopped in between, the file is recognized by its job the next time and only moved.
+pub struct MaildirIngestion {
+    config: MaildirConfig,
+    jobs: Arc<Mutex<Jobs>>,
+    analyzers: Vec<Arc<dyn MailAnalyzer>>,
+    timeouts: AnalysisTimeouts,
+}
+
+impl MaildirIngestion {
+    pub fn new(
+        config: MaildirConfig,
+        jobs: Arc<Mutex<Jobs>>,
+        analyzers: Vec<Arc<dyn MailAnalyzer>>,
+        timeouts: AnalysisTimeouts,
+    ) -> Self {
+        Self {
+            config,
+            jobs,
+            analyzers,
+            timeouts,
+        }
+    }
+
+    /// Spawns the watch of the directory
+    pub fn start(self: Arc<Self>) {
+        tokio::spawn(async move {
+            info!(
+                "Watching directory {} of {}",
+                self.config.path.display(),
+                self.config.name
+            );
+            let interval = Duration::from_secs(self.config.poll_interval);
+            loop {
+                if let Err(err) = self.ingest_directory().await {
+                    warn!("directory {}: {err}", self.config.path.display());
+                }
+                tokio::time::sleep(interval).await;
+            }
+        });
+    }
+
+    fn is_maildir(&self) -> bool {
+        self.config.path.join("new").is_dir()
+    }
+
+    fn archive(&self) -> PathBuf {
+        match &self.config.archive {
+            Some(archive) => archive.clone(),
+            None if self.is_maildir() => self.config.path.join("cur"),
+            None => self.config.path.join("processed"),
+        }
+    }
+
+    /// Submits the files of the directory that were not processed yet, then moves them.
+    /// Files that are not valid emails are moved too, and are only logged. A file that can't be
+    /// read or moved is logged and left in place, the next files are still ingested.
+    ///
+    /// Returns the number of jobs created.
+    pub async fn ingest_directory(&self) -> Result<usize, IngestionError> {
+        let maildir = self.is_maildir();
+        let incoming = if maildir {
+            self.config.path.join("new")
+        } else {
+            self.config.path.clone()
+        };
+        let archive = self.archive();
+        tokio::fs::create_dir_all(&archive).await?;
+
+        let mut created = 0;
+        for (file, path) in list_files(&incoming, !maildir).await? {
+            match self.ingest_file(file, &path, maildir, &archive).await {
+                Ok(true) => created += 1,
+                Ok(false) => {}
+                Err(err) => warn!("could not ingest {}: {err}", path.display()),
+            }
+        }
+
+        Ok(created)
+    }
+
+    /// Submits a file unless its job already exists, then moves it to the archive.
+    /// Returns whether a job was created.
+    async fn ingest_file(
+        &self,
+        file: String,
+        path: &Path,
+        maildir: bool,
+        archive: &Path,
+    ) -> Result<bool, IngestionError> {
+        let content = tokio::fs::read(path).await?;
+        let source = JobSource::Maildir {
+            directory: self.config.name.clone(),
+            file: file.clone(),
+        };
+
+        let mut created = false;
+        if let Some(job_id) = self.find_submitted(&source, &content).await {
+            info!("{file} was already submitted as job {job_id}");
+        } else {
+            let options = AnalysisOptions::default();
+            let submitted = submit_email(
+                &self.jobs,
+                content,
+                self.config.reports,
+                Some(source),
+                options,
+                &self.analyzers,
+                &self.timeouts,
+            )
+            .await;
+            match submitted {
+                Some(job) => {
+                    info!("Job {} created from {}", job.id, path.display());
+                    created = true;
+                }
+                None => warn!("{} is not a valid email", path.display()),
+            }
+        }
+
+        let name = if maildir && !file.contains(":2,") {
+            // marked as seen, as in Maildir++
+            format!("{file}:2,S")
+        } else {
+            file
+        };
+        tokio::fs::rename(path, unused_path(archive, &name)).await?;
+
+        Ok(created)
+    }
+
+    /// Returns the job already created from the given file, if any
+    async fn find_submitted(&self, source: &JobSource, content: &[u8]) -> Option<usize> {
+        let jobs = self.jobs.lock().await;
+        let job = jobs.iter_jobs().find(|job| {
+            let submitted = job.original.as_ref().map_or(&job.email, |o| &o.content);
+            job.source.as_ref() == Some(source) && submitted == content
+        })?;
+        Some(job.id)
+    }
+}
+
+/// Returns the name and the path of the files of a directory, sorted by name.
+/// Hidden files are skipped, as well as the files that may still be written if `settle` is set.
+async fn list_files(
+    directory: &Path,
+    settle: bool,
+) -> Result<Vec<(String, PathBuf)>, IngestionError> {
+    let mut files = vec![];
+    let mut entries = tokio::fs::read_dir(directory).await?;
+
+    while let Some(entry) = entries.next_entry().await? {
+        let metadata = entry.metadata().await?;
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        if !metadata.is_file() || name.starts_with('.') {
+            continue;
+        }
+
+        let age = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| SystemTime::now().duration_since(modified).ok());
+        if settle && age.is_none_or(|age| age < SETTLE_DELAY) {
+            continue;
+        }
+
+        files.push((name, entry.path()));
+    }
+
+    files.sort();
+    Ok(files)
+}
+
+/// Returns the path of the given name in a directory, suffixed if a file already has it
+fn unused_path(directory: &Path, name: &str) -> PathBuf {
+    let path = directory.join(name);
+    if path.exists() {
+        directory.join(format!("{name}.{}", Utc::now().timestamp_millis()))
+    } else {
+        path
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::analysis::AnalysisTimeouts;
+    use crate::config::MaildirConfig;
+    use crate::ingestion::maildir::MaildirIngestion;
+    use crate::ingestion::test::memory_jobs;
+    use crate::ingestion::JobSource;
+    use rocket::async_test;
+
+    #[async_test]
+    async fn test_ingest_maildir() {
+        let jobs = memory_jobs();
+
+        let path = std::env::temp_dir().join(format!("maildir-{}", std::process::id()));
+        for subdirectory in ["new", "cur", "tmp"] {
+            std::fs::create_dir_all(path.join(subdirectory)).unwrap();
+        }
+        std::fs::write(path.join("new/1.gateway"), "Subject: first\r\n\r\nhello").unwrap();
+        std::fs::write(path.join("new/2.gateway"), "").unwrap();
+        std::fs::write(path.join("tmp/3.gateway"), "Subject: partial\r\n").unwrap();
+
+        let config = MaildirConfig {
+            name: String::from("quarantine"),
+            path: path.clone(),
+            ..MaildirConfig::default()
+        };
+        let ingestion =
+            MaildirIngestion::new(config, jobs.clone(), vec![], AnalysisTimeouts::default());
+
+        assert_eq!(ingestion.ingest_directory().await.unwrap(), 1);
+        {
+            let jobs = jobs.lock().await;
+            let job = jobs.iter_jobs().next().unwrap();
+            assert_eq!(job.email().subject(), Some("first"));
+            assert_eq!(
+                job.source,
+                Some(JobSource::Maildir {
+                    directory: String::from("quarantine"),
+                    file: String::from("1.gateway"),
+                })
+            );
+        }
+
+        let mut processed: Vec<_> = std::fs::read_dir(path.join("cur"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        processed.sort();
+        assert_eq!(processed, ["1.gateway:2,S", "2.gateway:2,S"]);
+        assert!(path.join("tmp/3.gateway").exists());
+
+        // a file that was submitted but not moved is not submitted again
+        std::fs::rename(path.join("cur/1.gateway:2,S"), path.join("new/1.gateway")).unwrap();
+        assert_eq!(ingestion.ingest_directory().await.unwrap(), 0);
+        assert_eq!(jobs.lock().await.iter_jobs().count(), 1);
+        assert!(!path.join("new/1.gateway").exists());
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
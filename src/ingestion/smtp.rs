@@ -0,0 +1,457 @@
+use crate::analysis::{AnalysisOptions, AnalysisTimeouts, MailAnalyzer};
+use crate::config::SmtpConfig;
+use crate::ingestion::JobSource;
+use crate::state::Jobs;
+use crate::submission::submit_email;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::sync::Mutex;
+
+/// Maximum length of a command line, as set by RFC 5321
+const MAX_COMMAND_LENGTH: u64 = 512;
+/// Length of the chunks message content is read by, longer lines span several chunks
+const TEXT_CHUNK_LENGTH: u64 = 8192;
+const MAX_RECIPIENTS: usize = 100;
+/// Delay after which an inactive client is disconnected
+const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// The SMTP transaction an email was received with
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct SmtpEnvelope {
+    /// Reverse path given by `MAIL FROM`, empty for bounces
+    pub mail_from: String,
+    pub rcpt_to: Vec<String>,
+    pub client_ip: IpAddr,
+    /// Name given by the client in `HELO` or `EHLO`
+    pub helo: String,
+}
+
+impl SmtpEnvelope {
+    /// Domain of the reverse path, or the HELO name for bounces
+    pub fn mail_from_domain(&self) -> &str {
+        self.mail_from
+            .rsplit_once('@')
+            .map_or(self.helo.as_str(), |(_, domain)| domain)
+    }
+}
+
+/// Accepts emails over SMTP, e.g. journaled or forwarded by a mail gateway, and submits each
+/// of them as a job along with its envelope.
+///
+/// Messages are never relayed. STARTTLS and authentication are not supported, the listener is
+/// meant to only be reachable by the gateway.
+pub struct SmtpReceiver {
+    config: SmtpConfig,
+    jobs: Arc<Mutex<Jobs>>,
+    analyzers: Vec<Arc<dyn MailAnalyzer>>,
+    timeouts: AnalysisTimeouts,
+}
+
+/// A reply to the client, written at the end of a command
+struct Reply(u16, String);
+
+impl SmtpReceiver {
+    pub fn new(
+        config: SmtpConfig,
+        jobs: Arc<Mutex<Jobs>>,
+        analyzers: Vec<Arc<dyn MailAnalyzer>>,
+        timeouts: AnalysisTimeouts,
+    ) -> Self {
+        Self {
+            config,
+            jobs,
+            analyzers,
+            timeouts,
+        }
+    }
+
+    /// Spawns the listener
+    pub fn start(self: Arc<Self>) {
+        tokio::spawn(async move {
+            let address = (self.config.address, self.config.port);
+            match TcpListener::bind(address).await {
+                Ok(listener) => self.serve(listener).await,
+                Err(err) => error!(
+                    "could not listen for SMTP on {}:{}: {err}",
+                    address.0, address.1
+                ),
+            }
+        });
+    }
+
+    async fn serve(self: Arc<Self>, listener: TcpListener) {
+        if let Ok(address) = listener.local_addr() {
+            info!("Listening for SMTP on {address}");
+        }
+
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(connection) => connection,
+                Err(err) => {
+                    warn!("could not accept an SMTP connection: {err}");
+                    continue;
+                }
+            };
+
+            let receiver = self.clone();
+            tokio::spawn(async move {
+                if let Err(err) = receiver.handle_connection(stream, peer.ip()).await {
+                    warn!("SMTP connection from {peer}: {err}")
+                }
+            });
+        }
+    }
+
+    /// Runs an SMTP session, until the client quits or stays inactive for too long
+    async fn handle_connection(
+        &self,
+        stream: impl AsyncRead + AsyncWrite + Unpin,
+        client_ip: IpAddr,
+    ) -> std::io::Result<()> {
+        let (reader, mut writer) = tokio::io::split(stream);
+        let mut reader = BufReader::new(reader);
+        let hostname = &self.config.hostname;
+
+        write_reply(&mut writer, Reply(220, format!("{hostname} ESMTP MailAnalyzer"))).await?;
+
+        let mut helo: Option<String> = None;
+        let mut mail_from: Option<String> = None;
+        let mut rcpt_to: Vec<String> = vec![];
+
+        loop {
+            let Some(line) = read_line(&mut reader, MAX_COMMAND_LENGTH).await? else {
+                return Ok(());
+            };
+            let Ok(line) = String::from_utf8(line) else {
+                write_reply(&mut writer, Reply(500, String::from("invalid command"))).await?;
+                continue;
+            };
+
+            let line = line.trim_end_matches(['\r', '\n']);
+            let (verb, argument) = line.split_once(' ').unwrap_or((line, ""));
+            let argument = argument.trim();
+
+            let reply = match verb.to_uppercase().as_str() {
+                "EHLO" | "HELO" if argument.is_empty() => {
+                    Reply(501, String::from("a domain or address is required"))
+                }
+                "EHLO" => {
+                    helo = Some(argument.to_string());
+                    mail_from = None;
+                    rcpt_to.clear();
+                    Reply(
+                        250,
+                        format!(
+                            "{hostname}\nSIZE {}\n8BITMIME",
+                            self.config.max_message_size
+                        ),
+                    )
+                }
+                "HELO" => {
+                    helo = Some(argument.to_string());
+                    mail_from = None;
+                    rcpt_to.clear();
+                    Reply(250, hostname.clone())
+                }
+                "MAIL" if helo.is_none() => Reply(503, String::from("send HELO or EHLO first")),
+                "MAIL" if mail_from.is_some() => Reply(503, String::from("nested MAIL command")),
+                "MAIL" => match parse_path(argument, "FROM:") {
+                    Some((_, parameters)) if self.exceeds_size(parameters) => Reply(
+                        552,
+                        String::from("message size exceeds the maximum allowed"),
+                    ),
+                    Some((path, _)) => {
+                        mail_from = Some(path);
+                        Reply(250, String::from("OK"))
+                    }
+                    None => Reply(501, String::from("syntax: MAIL FROM:<address>")),
+                },
+                "RCPT" if mail_from.is_none() => Reply(503, String::from("send MAIL first")),
+                "RCPT" if rcpt_to.len() >= MAX_RECIPIENTS => {
+                    Reply(452, String::from("too many recipients"))
+                }
+                "RCPT" => match parse_path(argument, "TO:") {
+                    Some((path, _)) if !path.is_empty() => {
+                        rcpt_to.push(path);
+                        Reply(250, String::from("OK"))
+                    }
+                    _ => Reply(501, String::from("syntax: RCPT TO:<address>")),
+                },
+                "DATA" if rcpt_to.is_empty() => Reply(503, String::from("send RCPT first")),
+                "DATA" => {
+                    write_reply(
+                        &mut writer,
+                        Reply(354, String::from("end data with <CR><LF>.<CR><LF>")),
+                    )
+                    .await?;
+
+                    let envelope = SmtpEnvelope {
+                        mail_from: mail_from.take().unwrap_or_default(),
+                        rcpt_to: std::mem::take(&mut rcpt_to),
+                        client_ip,
+                        helo: helo.clone().unwrap_or_default(),
+                    };
+                    self.receive_message(&mut reader, envelope).await?
+                }
+                "RSET" => {
+                    mail_from = None;
+                    rcpt_to.clear();
+                    Reply(250, String::from("OK"))
+                }
+                "NOOP" => Reply(250, String::from("OK")),
+                "VRFY" => Reply(252, String::from("cannot verify addresses")),
+                "QUIT" => {
+                    write_reply(&mut writer, Reply(221, format!("{hostname} closing"))).await?;
+                    return Ok(());
+                }
+                _ => Reply(502, String::from("command not implemented")),
+            };
+
+            write_reply(&mut writer, reply).await?;
+        }
+    }
+
+    /// Whether the `SIZE` declared in the parameters of `MAIL` exceeds the maximum
+    fn exceeds_size(&self, parameters: &str) -> bool {
+        parameters
+            .split_whitespace()
+            .filter_map(|p| p.split_once('='))
+            .filter(|(name, _)| name.eq_ignore_ascii_case("SIZE"))
+            .any(|(_, size)| size.parse().is_ok_and(|size: u64| size > self.config.max_message_size))
+    }
+
+    /// Reads the content of a message up to the terminating `.` line, and submits it
+    async fn receive_message(
+        &self,
+        reader: &mut (impl AsyncBufReadExt + Unpin),
+        envelope: SmtpEnvelope,
+    ) -> std::io::Result<Reply> {
+        let mut content = vec![];
+        let mut too_large = false;
+        let mut at_line_start = true;
+
+        loop {
+            let Some(chunk) = read_line(reader, TEXT_CHUNK_LENGTH).await? else {
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            };
+            let is_line_start = std::mem::replace(&mut at_line_start, chunk.ends_with(b"\n"));
+
+            let chunk = if is_line_start {
+                if chunk == b".\r\n" || chunk == b".\n" {
+                    break;
+                }
+                // lines starting with a dot are escaped by another one
+                chunk.strip_prefix(b".").unwrap_or(&chunk)
+            } else {
+                &chunk
+            };
+
+            if content.len() + chunk.len() > self.config.max_message_size as usize {
+                too_large = true;
+                content.clear();
+            }
+            if !too_large {
+                content.extend_from_slice(chunk);
+            }
+        }
+
+        if too_large {
+            return Ok(Reply(552, String::from("message size exceeds the maximum allowed")));
+        }
+
+        let client = envelope.client_ip;
+        let source = Some(JobSource::Smtp(envelope));
+        let options = AnalysisOptions::default();
+        let submitted = submit_email(
+            &self.jobs,
+            content,
+            self.config.reports,
+            source,
+            options,
+            &self.analyzers,
+            &self.timeouts,
+        );
+        match submitted.await {
+            Some(job) => {
+                info!("Job {} created from an SMTP message sent by {client}", job.id);
+                Ok(Reply(250, format!("queued as job {}", job.id)))
+            }
+            None => Ok(Reply(554, String::from("not a valid email"))),
+        }
+    }
+}
+
+/// Reads a line, including its line ending. Lines longer than `limit` are cut.
+/// Returns `None` once the client closed the connection.
+async fn read_line(
+    reader: &mut (impl AsyncBufReadExt + Unpin),
+    limit: u64,
+) -> std::io::Result<Option<Vec<u8>>> {
+    let mut line = vec![];
+    let read = tokio::time::timeout(IDLE_TIMEOUT, reader.take(limit).read_until(b'\n', &mut line))
+        .await
+        .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))??;
+
+    Ok((read > 0).then_some(line))
+}
+
+/// Writes a reply, whose lines are separated by `\n`
+async fn write_reply(writer: &mut (impl AsyncWrite + Unpin), reply: Reply) -> std::io::Result<()> {
+    let Reply(code, text) = reply;
+    let lines: Vec<_> = text.lines().collect();
+
+    let mut output = String::new();
+    for (i, line) in lines.iter().enumerate() {
+        let separator = if i + 1 < lines.len() { '-' } else { ' ' };
+        output.push_str(&format!("{code}{separator}{line}\r\n"));
+    }
+
+    writer.write_all(output.as_bytes()).await?;
+    writer.flush().await
+}
+
+/// Parses the argument of `MAIL` or `RCPT`, such as `FROM:<alice@example.org> SIZE=1000`.
+/// Returns the address, empty for the null path `<>`, and the parameters that follow.
+fn parse_path<'a>(argument: &'a str, keyword: &str) -> Option<(String, &'a str)> {
+    let prefix = argument.get(..keyword.len())?;
+    if !prefix.eq_ignore_ascii_case(keyword) {
+        return None;
+    }
+
+    let path = argument[keyword.len()..].trim_start().strip_prefix('<')?;
+    let (address, parameters) = path.split_once('>')?;
+
+    // source routes, e.g. `<@relay.example:alice@example.org>`, are ignored
+    let address = address.rsplit_once(':').map_or(address, |(_, address)| address);
+    Some((address.to_string(), parameters.trim()))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::analysis::{create_analyzers, AnalysisTimeouts, JobEvent};
+    use crate::config::{Config, SmtpConfig};
+    use crate::ingestion::smtp::{SmtpEnvelope, SmtpReceiver};
+    use crate::ingestion::test::memory_jobs;
+    use crate::ingestion::JobSource;
+    use crate::secrets::SecretStore;
+    use rocket::async_test;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::time::Duration;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    #[async_test]
+    async fn test_receive_message() {
+        let jobs = memory_jobs();
+
+        let config = SmtpConfig {
+            max_message_size: 1000,
+            ..SmtpConfig::default()
+        };
+        let receiver = SmtpReceiver::new(config, jobs.clone(), vec![], AnalysisTimeouts::default());
+
+        let (client, server) = tokio::io::duplex(4096);
+        let client_ip = IpAddr::V4(Ipv4Addr::new(198, 51, 100, 7));
+        let session = tokio::spawn(async move {
+            receiver.handle_connection(server, client_ip).await.unwrap();
+        });
+
+        let (reader, mut writer) = tokio::io::split(client);
+        let mut reader = BufReader::new(reader).lines();
+        let mut reply = async || {
+            // the code of the last line of the reply
+            loop {
+                let line = reader.next_line().await.unwrap().unwrap();
+                if line.as_bytes()[3] == b' ' {
+                    return line[..3].to_string();
+                }
+            }
+        };
+
+        assert_eq!(reply().await, "220");
+        for (command, expected) in [
+            ("MAIL FROM:<bounce@mail.example.org>", "503"),
+            ("EHLO mail.example.org", "250"),
+            ("MAIL FROM:<bounce@mail.example.org> SIZE=2000", "552"),
+            ("MAIL FROM:<bounce@mail.example.org> SIZE=200", "250"),
+            ("DATA", "503"),
+            ("RCPT TO:<phishing@company.test>", "250"),
+            ("DATA", "354"),
+            ("Subject: Invoice\r\n\r\n..hidden dot\r\n.", "250"),
+            ("QUIT", "221"),
+        ] {
+            writer.write_all(format!("{command}\r\n").as_bytes()).await.unwrap();
+            assert_eq!(reply().await, expected, "{command}");
+        }
+        session.await.unwrap();
+
+        let jobs = jobs.lock().await;
+        let job = jobs.iter_jobs().next().unwrap();
+        assert_eq!(job.email, b"Subject: Invoice\r\n\r\n.hidden dot\r\n");
+        assert_eq!(
+            job.source,
+            Some(JobSource::Smtp(SmtpEnvelope {
+                mail_from: String::from("bounce@mail.example.org"),
+                rcpt_to: vec![String::from("phishing@company.test")],
+                client_ip,
+                helo: String::from("mail.example.org"),
+            }))
+        );
+    }
+
+    #[async_test]
+    async fn test_receive_message_without_sender() {
+        let jobs = memory_jobs();
+        let analyzers = create_analyzers(&Config::default(), &SecretStore::new(vec![]));
+        let receiver = SmtpReceiver::new(
+            SmtpConfig::default(),
+            jobs.clone(),
+            analyzers,
+            AnalysisTimeouts::default(),
+        );
+
+        let (client, server) = tokio::io::duplex(4096);
+        let session = tokio::spawn(async move {
+            let client_ip = IpAddr::V4(Ipv4Addr::new(198, 51, 100, 7));
+            receiver.handle_connection(server, client_ip).await.unwrap();
+        });
+
+        let (reader, mut writer) = tokio::io::split(client);
+        let mut reader = BufReader::new(reader).lines();
+        let greeting = reader.next_line().await.unwrap().unwrap();
+        assert!(greeting.starts_with("220"));
+        writer
+            .write_all(
+                b"HELO mail.example.org\r\nMAIL FROM:<>\r\nRCPT TO:<phishing@company.test>\r\n\
+                  DATA\r\nSubject: Invoice\r\n\r\nhttp://192.0.2.1/login\r\n.\r\nQUIT\r\n",
+            )
+            .await
+            .unwrap();
+        let mut codes = vec![];
+        while let Some(line) = reader.next_line().await.unwrap() {
+            codes.push(line[..3].to_string());
+        }
+        assert_eq!(codes, ["250", "250", "250", "354", "250", "221"]);
+        session.await.unwrap();
+
+        // the analyzers run on the email, which has no sender and links without a domain
+        let job = jobs.lock().await.iter_jobs().next().unwrap().clone();
+        let mut events = job.subscribe_events(0);
+        let links_finished = async {
+            while let Some((_, event)) = events.next().await {
+                if matches!(&event, JobEvent::AnalyzerFinished(s) if s.name == "Links analysis") {
+                    return;
+                }
+            }
+            panic!("the links analysis did not finish");
+        };
+        tokio::time::timeout(Duration::from_secs(10), links_finished)
+            .await
+            .unwrap();
+    }
+}
@@ -1,9 +1,9 @@
 use log::warn;
-use reqwest::Client;
 use rocket::futures::StreamExt;
 use rocket::serde::Deserialize;
 use rocket::serde::json::Value;
 use rocket::futures::FutureExt;
+use crate::http::HttpClient;
 use crate::investigation::wikidata::data::{Snak, WikidataClaim, WikidataEntity, WikidataInvestFailure, WikidataReference, WikidataValue};
 
 #[derive(Deserialize, Debug)]
@@ -18,12 +18,11 @@ impl Pid {
 /// Searches on wikidata about a specific entity string
 /// and returns the found entities ID
 pub async fn search_by_name(
-    client: &Client,
+    client: &HttpClient,
     name: &str,
 ) -> Result<Vec<String>, WikidataInvestFailure> {
     let response = client
-        .get(format!("https://www.wikidata.org/w/api.php?action=wbsearchentities&search={name}&language=en&format=json"))
-        .send()
+        .send(client.get(format!("https://www.wikidata.org/w/api.php?action=wbsearchentities&search={name}&language=en&format=json")))
         .await
         .map_err(WikidataInvestFailure::from)?;
 
@@ -44,14 +43,13 @@ pub async fn search_by_name(
 }
 
 pub async fn get_entity(
-    client: &Client,
+    client: &HttpClient,
     entity_id: &str,
 ) -> Result<WikidataEntity, WikidataInvestFailure> {
     let response = client
-        .get(format!(
+        .send(client.get(format!(
             "https://www.wikidata.org/w/api.php?action=wbgetentities&ids={entity_id}&format=json"
-        ))
-        .send()
+        )))
         .await?;
 
     let value = response.json::<Value>().await?;
@@ -92,7 +90,7 @@ pub async fn get_entity(
 }
 
 async fn extract_claim(
-    client: &Client,
+    client: &HttpClient,
     cpid: &str,
     claim: &Value,
 ) -> Result<WikidataClaim, WikidataInvestFailure> {
@@ -138,7 +136,7 @@ async fn extract_claim(
 }
 
 async fn extract_claims(
-    client: &Client,
+    client: &HttpClient,
     pid: &str,
     claims: &[Value],
 ) -> Result<Vec<WikidataClaim>, Box<dyn std::error::Error>> {
@@ -149,12 +147,11 @@ async fn extract_claims(
     Ok(claims)
 }
 
-async fn get_entity_name(client: &Client, id: &str) -> Result<String, WikidataInvestFailure> {
+async fn get_entity_name(client: &HttpClient, id: &str) -> Result<String, WikidataInvestFailure> {
     let response = client
-        .get(format!(
+        .send(client.get(format!(
             "https://www.wikidata.org/w/api.php?action=wbgetentities&ids={id}&format=json"
-        ))
-        .send()
+        )))
         .await
         .map_err(WikidataInvestFailure::from)?;
 
@@ -1,38 +1,234 @@
-use crate::analysis::{AnalysisResult, JobEvent};
+mod events;
+
+use crate::analysis::{AnalysisError, AnalysisOptions, AnalysisResult, JobEvent};
+use crate::dedup::Submission;
+use crate::email::{EmailFormat, OriginalFile, Report};
+use crate::ingestion::{JobSource, SmtpEnvelope};
+use crate::recheck::Recheck;
+use crate::rules::RULES_ANALYSIS_NAME;
 use mail_parser::{Message, MessageParser};
-use rocket::serde::Serialize;
+use crate::scoring::RiskAssessment;
+use crate::storage::StoredJob;
+use chrono::{DateTime, Utc};
+use log::warn;
+use rocket::serde::{Deserialize, Serialize};
 use std::ops::Deref;
-use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
-use std::sync::Arc;
-use tokio::sync::broadcast::{Receiver, Sender};
-use tokio::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::task::AbortHandle;
+
+pub use events::{EventCursor, EventLog};
 
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum JobState {
     Analyzing,
     Error(String),
     Analyzed,
+    Cancelled,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum AnalyzerState {
+    Pending,
+    Running,
+    Done,
+    Failed,
+    TimedOut,
+    Cancelled,
 }
 
+/// Progression of one analyzer over a job
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AnalyzerStatus {
+    pub name: String,
+    pub state: AnalyzerState,
+    pub started_at: Option<DateTime<Utc>>,
+    pub ended_at: Option<DateTime<Utc>>,
+    pub result_count: usize,
+}
+
+impl AnalyzerStatus {
+    pub fn pending(name: String) -> Self {
+        Self {
+            name,
+            state: AnalyzerState::Pending,
+            started_at: None,
+            ended_at: None,
+            result_count: 0,
+        }
+    }
+
+    pub fn is_over(&self) -> bool {
+        !matches!(self.state, AnalyzerState::Pending | AnalyzerState::Running)
+    }
+}
+
+/// The progress of a job (results, failures, analyzers and risk) is only updated by publishing
+/// events through [`Job::publish`], which keeps it consistent with the job's event log.
 pub struct Job {
-    pub email: String,
+    /// The raw email, exactly as it was submitted or forwarded
+    pub email: Vec<u8>,
+    /// The submitted file, when the email was converted from another format
+    pub original: Option<OriginalFile>,
+    /// The wrapper of the email, when it was forwarded by a user
+    pub report: Option<Report>,
+    /// Where the email was ingested from, when it was not submitted through the API
+    pub source: Option<JobSource>,
+    /// Analyzers chosen at submission, along with their options
+    pub options: AnalysisOptions,
+    /// See [`crate::dedup::fingerprint`]
+    pub fingerprint: Option<String>,
+    /// The job whose results are reused, when the email was already submitted
+    pub duplicate_of: Option<usize>,
+    /// Every submission of the email, only tracked by the original job of linked jobs
+    pub submissions: Mutex<Vec<Submission>>,
     pub state: Mutex<JobState>,
     pub results: Mutex<Vec<AnalysisResult>>,
+    pub failures: Mutex<Vec<AnalysisError>>,
+    pub analyzers: Mutex<Vec<AnalyzerStatus>>,
+    pub risk: Mutex<Option<RiskAssessment>>,
+    /// Later lookups of the links of the job, see [`crate::recheck`]
+    pub rechecks: Mutex<Vec<Recheck>>,
     pub expected_result_count: AtomicI32,
     pub id: usize,
-    pub(crate) event_channel: Arc<Sender<JobEvent>>,
+    pub created_at: DateTime<Utc>,
+    events: Arc<EventLog>,
     is_complete: AtomicBool,
+    is_timed_out: AtomicBool,
+    is_cancelled: AtomicBool,
+    /// Number of times the job was rerun, see [`Job::rerun`]
+    rerun_count: AtomicUsize,
+    /// Tasks spawned by the analyzers, along with the name of their analyzer
+    tasks: Mutex<Vec<(String, AbortHandle)>>,
 }
 
 impl Job {
-    pub(crate) fn new(email: String, id: usize, event_channel: Sender<JobEvent>) -> Self {
+    pub(crate) fn new(email: Vec<u8>, id: usize) -> Self {
         Self {
             email,
+            original: None,
+            report: None,
+            source: None,
+            options: AnalysisOptions::default(),
+            fingerprint: None,
+            duplicate_of: None,
+            submissions: Mutex::new(Vec::new()),
             state: Mutex::new(JobState::Analyzing),
             results: Mutex::new(Vec::new()),
-            event_channel: Arc::new(event_channel),
+            failures: Mutex::new(Vec::new()),
+            analyzers: Mutex::new(Vec::new()),
+            risk: Mutex::new(None),
+            rechecks: Mutex::new(Vec::new()),
+            events: Arc::new(EventLog::new(Vec::new())),
             expected_result_count: AtomicI32::new(-1),
             is_complete: AtomicBool::new(false),
+            is_timed_out: AtomicBool::new(false),
+            is_cancelled: AtomicBool::new(false),
+            rerun_count: AtomicUsize::new(0),
+            tasks: Mutex::new(Vec::new()),
             id,
+            created_at: Utc::now(),
+        }
+    }
+
+    /// Recreates a job from its persisted form.
+    /// A job that was still being analyzed when it was stored can't be resumed and is marked as failed.
+    pub(crate) fn restore(stored: StoredJob) -> Self {
+        let state = match stored.state {
+            JobState::Analyzing => {
+                JobState::Error(String::from("analysis interrupted by a server restart"))
+            }
+            state => state,
+        };
+        let is_cancelled = matches!(state, JobState::Cancelled);
+
+        let mut analyzers = stored.analyzers;
+        for status in analyzers.iter_mut().filter(|s| !s.is_over()) {
+            status.state = AnalyzerState::Failed;
+        }
+
+        // the original events are not persisted, rebuild a log leading to the same state
+        let mut events = vec![];
+        if stored.expected_result_count >= 0 {
+            events.push(JobEvent::ExpandedResultCount(
+                stored.expected_result_count as usize,
+            ));
+        }
+        events.extend(stored.results.iter().cloned().map(JobEvent::Progress));
+        events.extend(stored.failures.iter().cloned().map(JobEvent::Error));
+        events.extend(analyzers.iter().cloned().map(JobEvent::AnalyzerFinished));
+        events.extend(stored.risk.clone().map(JobEvent::RiskAssessed));
+        events.push(JobEvent::JobComplete);
+        let rechecks = stored.rechecks.iter().cloned();
+        events.extend(rechecks.map(JobEvent::LinksRechecked));
+
+        // jobs stored before submissions were tracked were submitted once
+        let mut submissions = stored.submissions;
+        if submissions.is_empty() {
+            submissions.push(Submission {
+                job_id: stored.id,
+                submitted_at: stored.created_at,
+                reporter: stored.report.as_ref().and_then(|r| r.reporter.clone()),
+            });
+        }
+
+        Self {
+            email: stored.email,
+            original: stored.original,
+            report: stored.report,
+            source: stored.source,
+            options: stored.options,
+            fingerprint: stored.fingerprint,
+            duplicate_of: stored.duplicate_of,
+            submissions: Mutex::new(submissions),
+            state: Mutex::new(state),
+            results: Mutex::new(stored.results),
+            failures: Mutex::new(stored.failures),
+            analyzers: Mutex::new(analyzers),
+            risk: Mutex::new(stored.risk),
+            rechecks: Mutex::new(stored.rechecks),
+            events: Arc::new(EventLog::new(events)),
+            expected_result_count: AtomicI32::new(stored.expected_result_count),
+            is_complete: AtomicBool::new(true),
+            is_timed_out: AtomicBool::new(false),
+            is_cancelled: AtomicBool::new(is_cancelled),
+            rerun_count: AtomicUsize::new(0),
+            tasks: Mutex::new(Vec::new()),
+            id: stored.id,
+            created_at: stored.created_at,
+        }
+    }
+
+    pub fn to_stored(&self) -> StoredJob {
+        StoredJob {
+            id: self.id,
+            email: self.email.clone(),
+            original: self.original.clone(),
+            report: self.report.clone(),
+            source: self.source.clone(),
+            options: self.options.clone(),
+            fingerprint: self.fingerprint.clone(),
+            duplicate_of: self.duplicate_of,
+            submissions: self.submissions.lock().unwrap().clone(),
+            created_at: self.created_at,
+            state: self.state.lock().unwrap().clone(),
+            results: self.results.lock().unwrap().clone(),
+            failures: self.failures.lock().unwrap().clone(),
+            analyzers: self.analyzers.lock().unwrap().clone(),
+            risk: self.risk.lock().unwrap().clone(),
+            rechecks: self.rechecks.lock().unwrap().clone(),
+            expected_result_count: self.expected_result_count.load(Ordering::Acquire),
+        }
+    }
+
+    /// Replaces the status of the analyzer with the same name
+    fn update_analyzer(&self, status: AnalyzerStatus) {
+        let mut analyzers = self.analyzers.lock().unwrap();
+        match analyzers.iter_mut().find(|s| s.name == status.name) {
+            Some(current) => *current = status,
+            None => analyzers.push(status),
         }
     }
 
@@ -40,17 +236,158 @@ impl Job {
         MessageParser::new().parse(&self.email).unwrap()
     }
 
-    pub fn subscribe_events(&self) -> Receiver<JobEvent> {
-        self.event_channel.subscribe()
+    /// The SMTP envelope of the analyzed email. A forwarded email has none: the envelope of
+    /// a user report is the reporter's.
+    pub fn envelope(&self) -> Option<&SmtpEnvelope> {
+        match (&self.source, &self.report) {
+            (Some(JobSource::Smtp(envelope)), None) => Some(envelope),
+            _ => None,
+        }
     }
-    
+
+    /// Applies an event to the job and appends it to the job's event log
+    pub fn publish(&self, event: JobEvent) {
+        self.events.push(event, |event| self.apply(event));
+    }
+
+    fn apply(&self, event: &JobEvent) {
+        match event {
+            JobEvent::ExpandedResultCount(count) => {
+                let _ = self.expected_result_count.fetch_update(
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                    |current| Some(current.max(0) + *count as i32),
+                );
+            }
+            JobEvent::Progress(result) => {
+                if let Some(status) = self
+                    .analyzers
+                    .lock()
+                    .unwrap()
+                    .iter_mut()
+                    .find(|s| s.name == result.analysis_name)
+                {
+                    status.result_count += 1;
+                }
+                self.results.lock().unwrap().push(result.clone())
+            }
+            JobEvent::Error(error) => {
+                warn!(
+                    "Job {}: {} failed: {}",
+                    self.id, error.analysis_name, error.message
+                );
+                self.failures.lock().unwrap().push(error.clone())
+            }
+            JobEvent::AnalyzerStarted(status) | JobEvent::AnalyzerFinished(status) => {
+                self.update_analyzer(status.clone())
+            }
+            JobEvent::RiskAssessed(risk) => *self.risk.lock().unwrap() = Some(risk.clone()),
+            JobEvent::JobComplete => self.is_complete.store(true, Ordering::Release),
+            JobEvent::Rerun(names) => {
+                let discarded = |name: &String| names.contains(name) || name == RULES_ANALYSIS_NAME;
+
+                let mut results = self.results.lock().unwrap();
+                results.retain(|r| !discarded(&r.analysis_name));
+                self.failures
+                    .lock()
+                    .unwrap()
+                    .retain(|e| !discarded(&e.analysis_name));
+                for status in self.analyzers.lock().unwrap().iter_mut() {
+                    if names.contains(&status.name) {
+                        *status = AnalyzerStatus::pending(status.name.clone());
+                    }
+                }
+
+                // the analyzers that are run again expand it with their new results
+                self.expected_result_count
+                    .store(results.len() as i32, Ordering::Release);
+                *self.risk.lock().unwrap() = None;
+                *self.state.lock().unwrap() = JobState::Analyzing;
+                self.is_timed_out.store(false, Ordering::Release);
+                self.is_cancelled.store(false, Ordering::Release);
+                self.is_complete.store(false, Ordering::Release);
+                self.rerun_count.fetch_add(1, Ordering::AcqRel);
+            }
+            JobEvent::LinksRechecked(recheck) => {
+                self.rechecks.lock().unwrap().push(recheck.clone())
+            }
+            JobEvent::VerdictChanged(_) => {}
+        }
+    }
+
+    /// Reopens a complete job to run the given analyzers again, see [`JobEvent::Rerun`].
+    /// Returns the sequence number of the rerun event, or `None` if the job is not complete.
+    pub fn rerun(&self, analyzer_names: Vec<String>) -> Option<u64> {
+        self.is_complete
+            .compare_exchange(true, false, Ordering::AcqRel, Ordering::Acquire)
+            .ok()?;
+        Some(
+            self.events
+                .push(JobEvent::Rerun(analyzer_names), |event| self.apply(event)),
+        )
+    }
+
+    pub fn rerun_count(&self) -> usize {
+        self.rerun_count.load(Ordering::Acquire)
+    }
+
+    /// Reads the events of the job that follow the given sequence number,
+    /// starting from the first one if `sequence` is 0.
+    pub fn subscribe_events(&self, sequence: u64) -> EventCursor {
+        self.events.subscribe(sequence)
+    }
+
     pub fn is_complete(&self) -> bool {
         self.is_complete.load(Ordering::Acquire)
     }
     
+    pub(crate) fn register_task(&self, analysis_name: &str, handle: AbortHandle) {
+        self.tasks
+            .lock()
+            .unwrap()
+            .push((analysis_name.to_string(), handle));
+    }
+
+    /// Aborts the running tasks of the given analyzer, or of every analyzer if none is given
+    pub(crate) fn abort_tasks(&self, analysis_name: Option<&str>) {
+        let mut tasks = self.tasks.lock().unwrap();
+        tasks.retain(|(name, handle)| {
+            if analysis_name.is_some_and(|n| n != name) {
+                return !handle.is_finished();
+            }
+            handle.abort();
+            false
+        });
+    }
+
+    /// Abandons the analysis: every running task is aborted and the analyzers that did not
+    /// conclude yet are reported as timed out.
+    pub fn time_out(&self) {
+        self.is_timed_out.store(true, Ordering::Release);
+        self.abort_tasks(None);
+    }
+
+    pub fn is_timed_out(&self) -> bool {
+        self.is_timed_out.load(Ordering::Acquire)
+    }
+
+    /// Stops the analysis of the job: every running task is aborted and the analyzers that did
+    /// not conclude yet are reported as cancelled.
+    /// Returns false if the job was already complete.
+    pub fn cancel(&self) -> bool {
+        if self.is_complete() || self.is_cancelled.swap(true, Ordering::AcqRel) {
+            return false;
+        }
+        self.abort_tasks(None);
+        true
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.is_cancelled.load(Ordering::Acquire)
+    }
+
     pub fn mark_as_complete(&self) {
-        self.is_complete.store(true, Ordering::Release);
-        self.event_channel.send(JobEvent::JobComplete).unwrap();
+        self.publish(JobEvent::JobComplete);
     }
 }
 
@@ -62,18 +399,30 @@ pub struct JobDescription {
     error: Option<String>,
     id: usize,
     results: Vec<AnalysisResult>,
-    is_complete: bool
+    failures: Vec<AnalysisError>,
+    analyzers: Vec<AnalyzerStatus>,
+    risk: Option<RiskAssessment>,
+    rechecks: Vec<Recheck>,
+    is_complete: bool,
+    is_cancelled: bool,
+    created_at: DateTime<Utc>,
+    format: EmailFormat,
+    report: Option<Report>,
+    source: Option<JobSource>,
+    options: AnalysisOptions,
+    duplicate_of: Option<usize>,
+    submissions: Vec<Submission>,
 }
 
 impl JobDescription {
-    pub async fn from_job(job: &Job) -> Self {
-        let error = if let JobState::Error(s) = job.state.lock().await.deref() {
+    pub fn from_job(job: &Job) -> Self {
+        let error = if let JobState::Error(s) = job.state.lock().unwrap().deref() {
             Some(s.clone())
         } else {
             None
         };
 
-        let current_results = job.results.lock().await;
+        let current_results = job.results.lock().unwrap();
 
         let result_count = job.expected_result_count.load(Ordering::Acquire);
 
@@ -85,12 +434,119 @@ impl JobDescription {
             id: job.id,
             error,
             results: current_results.clone(),
+            failures: job.failures.lock().unwrap().clone(),
+            analyzers: job.analyzers.lock().unwrap().clone(),
+            risk: job.risk.lock().unwrap().clone(),
+            rechecks: job.rechecks.lock().unwrap().clone(),
             target_result_count: if result_count == -1 {
                 None
             } else {
                 Some(result_count as usize)
             },
             is_complete: job.is_complete.load(Ordering::Acquire),
+            is_cancelled: job.is_cancelled(),
+            created_at: job.created_at,
+            format: job
+                .original
+                .as_ref()
+                .map_or(EmailFormat::Eml, |original| original.format),
+            report: job.report.clone(),
+            source: job.source.clone(),
+            options: job.options.clone(),
+            duplicate_of: job.duplicate_of,
+            submissions: job.submissions.lock().unwrap().clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::analysis::{AnalysisResult, AnalysisVerdict, JobEvent};
+    use crate::job::{AnalyzerStatus, Job, JobState};
+    use crate::rules::RULES_ANALYSIS_NAME;
+    use rocket::async_test;
+    use std::sync::Arc;
+
+    #[async_test]
+    async fn test_event_log() {
+        let job = Arc::new(Job::new(Vec::new(), 1));
+
+        job.publish(JobEvent::ExpandedResultCount(2));
+        for _ in 0..1000 {
+            job.publish(JobEvent::Progress(AnalysisResult::new(
+                String::from("test"),
+                AnalysisVerdict::new("test", "value"),
+            )));
+        }
+
+        // a slow subscriber still reads every event, in order
+        let mut events = job.subscribe_events(0);
+        let (sequence, event) = events.next().await.unwrap();
+        assert_eq!(sequence, 1);
+        assert!(matches!(event, JobEvent::ExpandedResultCount(2)));
+
+        let mut resumed = job.subscribe_events(1000);
+        let writer = job.clone();
+        tokio::spawn(async move { writer.mark_as_complete() });
+
+        assert_eq!(resumed.next().await.unwrap().0, 1001);
+        assert!(resumed.next().await.unwrap().1.is_closing_action());
+        assert!(resumed.next().await.is_none());
+
+        let mut count = 1;
+        while events.next().await.is_some() {
+            count += 1;
+        }
+        assert_eq!(count, 1002);
+
+        assert_eq!(job.results.lock().unwrap().len(), 1000);
+        assert_eq!(job.expected_result_count.load(std::sync::atomic::Ordering::Acquire), 2);
+        assert!(job.is_complete());
+        assert!(job.subscribe_events(1002).next().await.is_none());
+    }
+
+    #[async_test]
+    async fn test_rerun() {
+        let job = Job::new(Vec::new(), 1);
+        let result = |name: &str| {
+            JobEvent::Progress(AnalysisResult::new(
+                String::from(name),
+                AnalysisVerdict::new("test", "value"),
+            ))
+        };
+
+        for name in ["links", "auth"] {
+            job.publish(JobEvent::AnalyzerFinished(AnalyzerStatus::pending(
+                String::from(name),
+            )));
+        }
+        job.publish(JobEvent::ExpandedResultCount(2));
+        job.publish(result("links"));
+        job.publish(result("auth"));
+        job.publish(result(RULES_ANALYSIS_NAME));
+        *job.state.lock().unwrap() = JobState::Analyzed;
+
+        assert!(job.rerun(vec![String::from("links")]).is_none());
+        job.mark_as_complete();
+
+        let sequence = job.rerun(vec![String::from("links")]).unwrap();
+        assert!(!job.is_complete());
+        assert!(job.rerun(vec![String::from("links")]).is_none());
+        assert!(matches!(*job.state.lock().unwrap(), JobState::Analyzing));
+        let results = job.results.lock().unwrap().clone();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].analysis_name, "auth");
+
+        job.publish(result("links"));
+        job.mark_as_complete();
+
+        // the events of the rerun follow the first completion of the job
+        let mut events = job.subscribe_events(0);
+        let mut read = 0;
+        while let Some((sequence, _)) = events.next().await {
+            read = sequence;
         }
+        assert_eq!(read, sequence + 2);
+        assert!(job.is_complete());
     }
 }
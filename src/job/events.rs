@@ -0,0 +1,93 @@
+use crate::analysis::JobEvent;
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+
+/// Ordered log of every event published for a job.
+/// The sequence number of an event is its position in the log, starting at 1.
+///
+/// Subscribers read the log through an [`EventCursor`] and are only notified that new events
+/// were appended, so a slow subscriber can never miss an event.
+pub struct EventLog {
+    events: Mutex<Vec<JobEvent>>,
+    /// Sequence number of the last appended event
+    notifier: watch::Sender<u64>,
+}
+
+impl EventLog {
+    pub fn new(events: Vec<JobEvent>) -> Self {
+        let sequence = events.len() as u64;
+        Self {
+            events: Mutex::new(events),
+            notifier: watch::Sender::new(sequence),
+        }
+    }
+
+    /// Appends an event to the log, `apply` is called while the log is locked so that
+    /// subscribers never observe the event before its effects.
+    pub(crate) fn push(&self, event: JobEvent, apply: impl FnOnce(&JobEvent)) -> u64 {
+        let mut events = self.events.lock().unwrap();
+        apply(&event);
+        events.push(event);
+
+        let sequence = events.len() as u64;
+        self.notifier.send_replace(sequence);
+        sequence
+    }
+
+    fn get(&self, sequence: u64) -> Option<JobEvent> {
+        let index = sequence.checked_sub(1)? as usize;
+        self.events.lock().unwrap().get(index).cloned()
+    }
+
+    fn len(&self) -> u64 {
+        self.events.lock().unwrap().len() as u64
+    }
+
+    /// Creates a cursor reading the events that follow the given sequence number
+    pub fn subscribe(self: &Arc<Self>, sequence: u64) -> EventCursor {
+        let events = self.events.lock().unwrap();
+        // a client resuming after the end of a complete job has nothing left to read
+        let is_over = sequence >= events.len() as u64
+            && events.last().is_some_and(JobEvent::is_closing_action);
+
+        EventCursor {
+            log: self.clone(),
+            sequence: sequence.min(events.len() as u64),
+            notifications: self.notifier.subscribe(),
+            is_over,
+        }
+    }
+}
+
+/// A reader of an [`EventLog`], which waits for new events once it reached the end of the log.
+pub struct EventCursor {
+    log: Arc<EventLog>,
+    /// Sequence number of the last read event
+    sequence: u64,
+    notifications: watch::Receiver<u64>,
+    is_over: bool,
+}
+
+impl EventCursor {
+    /// Returns the next event and its sequence number, waiting for it to be published if needed.
+    /// Returns `None` once the closing event of the job has been read, unless the job was rerun
+    /// in the meantime.
+    pub async fn next(&mut self) -> Option<(u64, JobEvent)> {
+        if self.is_over {
+            return None;
+        }
+
+        loop {
+            self.notifications.borrow_and_update();
+
+            if let Some(event) = self.log.get(self.sequence + 1) {
+                self.sequence += 1;
+                self.is_over = event.is_closing_action() && self.sequence == self.log.len();
+                return Some((self.sequence, event));
+            }
+
+            // the log lives as long as this cursor, so its notifier can't be dropped
+            self.notifications.changed().await.ok()?;
+        }
+    }
+}
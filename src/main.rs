@@ -1,106 +1,253 @@
 mod analysis;
+mod batch;
+mod cli;
+mod config;
+mod dedup;
 mod job;
 mod pipeline;
+mod recheck;
 mod state;
 mod command;
 mod email;
 mod entity;
+mod http;
+mod ingestion;
 mod splunk;
+mod rules;
+mod secrets;
+mod scoring;
+mod storage;
+mod submission;
 // mod investigation;
 
-use crate::analysis::{init_analyzers, start_email_analysis, JobEvent, ANALYZERS};
-use crate::job::{JobDescription, JobState};
-use crate::state::{Jobs, ServerState, ServerStateEvent};
+use crate::analysis::{
+    init_analyzers, AnalysisOptions, AnalysisTimeouts, LinkRechecker, ANALYZERS,
+};
+use crate::batch::{submit_batch, BatchDescription, BatchJobSummary, BatchLimits, RejectedMessage};
+use crate::config::{Cli, Command, Config};
+use crate::email::EmailFormat;
+use crate::http::HttpClient;
+use crate::ingestion::{ImapIngestion, MaildirIngestion, SmtpReceiver};
+use crate::job::JobDescription;
+use crate::recheck::RecheckScheduler;
+use crate::rules::{init_rules, RuleDefinition, RULES};
+use crate::secrets::{init_secrets, SECRETS, VIRUSTOTAL};
+use crate::state::{Jobs, RetentionPolicy, ServerState, ServerStateEvent};
+use crate::storage::SqliteStorage;
+use crate::submission::submit_email;
+use clap::Parser;
 use log::{log, Level};
-use mail_parser::MessageParser;
 use rocket::data::ByteUnit;
+use rocket::futures::stream::FuturesUnordered;
 use rocket::futures::StreamExt;
-use rocket::http::{Method, Status};
+use rocket::http::{ContentType, Header, Method, Status};
 use rocket::response::stream::{Event, EventStream};
 use rocket::serde::json::Json;
-use rocket::{get, launch, post, routes, Data, State};
+use rocket::request::{FromRequest, Outcome};
+use rocket::fairing::AdHoc;
+use rocket::{delete, get, post, routes, Build, Data, Request, Responder, Rocket, State};
 use rocket_cors::{AllowedOrigins, CorsOptions};
 use serde::Serialize;
-use std::collections::HashSet;
+use std::collections::HashMap;
 use std::ops::Index;
-use std::sync::atomic::Ordering;
+use std::process::ExitCode;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
 use tokio::sync::broadcast::Sender;
 use tokio::sync::Mutex;
 
+/// Interval at which the jobs that are no longer retained are deleted
+const RETENTION_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
 struct JobCreatedResponse {
     job_id: usize,
 }
 
-#[post("/job", data = "<data>")]
-async fn submit_mail<'a>(
+/// Reads a comma-separated list of analyzer ids
+fn analyzer_ids(analyzers: &str) -> Vec<String> {
+    analyzers
+        .split(',')
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(ToOwned::to_owned)
+        .collect()
+}
+
+/// Reads the analyzers and the options chosen in the query of a submission, e.g.
+/// `?analyzers=links,auth&links.submit_unknown=false`
+fn analysis_options(
+    analyzers: Option<&str>,
+    options: HashMap<String, HashMap<String, bool>>,
+) -> Result<AnalysisOptions, (Status, String)> {
+    let options = AnalysisOptions {
+        analyzers: analyzers.map(analyzer_ids),
+        options,
+    };
+
+    options
+        .validate(ANALYZERS.get().unwrap())
+        .map_err(|err| (Status::BadRequest, err))?;
+
+    Ok(options)
+}
+
+/// Creates a job for an email, `report=true` marks it as a user report to unwrap
+#[post("/job?<analyzers>&<report>&<options..>", data = "<data>")]
+async fn submit_mail(
     state: &State<ServerState>,
     data: Data<'_>,
-) -> Result<Json<JobCreatedResponse>, Status> {
+    analyzers: Option<&str>,
+    report: Option<bool>,
+    options: HashMap<String, HashMap<String, bool>>,
+) -> Result<Json<JobCreatedResponse>, (Status, String)> {
+    let options = analysis_options(analyzers, options)?;
+
     let result = data.open(ByteUnit::Gigabyte(1));
 
     let file_content = result
-        .into_string()
+        .into_bytes()
         .await
-        .map_err(|_| Status::InternalServerError)?;
+        .map_err(|err| (Status::InternalServerError, err.to_string()))?;
     let file_content = file_content.value;
 
-    let is_valid_email = MessageParser::new().parse(&file_content).is_some();
+    let reported = report.unwrap_or(false);
+    let analyzers = ANALYZERS.get().unwrap();
+    let submitted = submit_email(
+        &state.jobs,
+        file_content,
+        reported,
+        None,
+        options,
+        analyzers,
+        &state.timeouts,
+    );
+    let submitted = submitted.await;
+
+    //FIXME workaround for app's desynchronisation after a job is submitted
+    tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+
+    match submitted {
+        Some(job) => Ok(Json(JobCreatedResponse { job_id: job.id })),
+        None => Err((Status::BadRequest, String::from("not a valid email"))),
+    }
+}
 
-    if is_valid_email {
-        let job = state.jobs.lock().await.add_job(file_content).await;
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct BatchCreatedResponse {
+    batch_id: usize,
+    job_ids: Vec<usize>,
+    rejected: Vec<RejectedMessage>,
+}
 
-        let analyzers = ANALYZERS.get().unwrap();
+/// Creates one job per email of an mbox file or a zip archive of `.eml` and `.msg` files
+/// Takes the same query as `/job`
+#[post("/batch?<analyzers>&<report>&<options..>", data = "<data>")]
+async fn submit_archive(
+    state: &State<ServerState>,
+    data: Data<'_>,
+    analyzers: Option<&str>,
+    report: Option<bool>,
+    options: HashMap<String, HashMap<String, bool>>,
+) -> Result<Json<BatchCreatedResponse>, (Status, String)> {
+    let options = analysis_options(analyzers, options)?;
+
+    let archive = data
+        .open(ByteUnit::Gigabyte(1))
+        .into_bytes()
+        .await
+        .map_err(|err| (Status::InternalServerError, err.to_string()))?;
+
+    let analyzers = ANALYZERS.get().unwrap();
+    let batch = submit_batch(
+        &state.jobs,
+        &archive.value,
+        report.unwrap_or(false),
+        &state.batch,
+        &options,
+        analyzers,
+        &state.timeouts,
+    )
+    .await
+    .map_err(|err| (Status::BadRequest, err.to_string()))?;
+
+    Ok(Json(BatchCreatedResponse {
+        batch_id: batch.id,
+        job_ids: batch.jobs.iter().map(|j| j.job_id).collect(),
+        rejected: batch.rejected.clone(),
+    }))
+}
 
-        let job_id = job.id;
+#[get("/batch/<batch_id>")]
+async fn get_batch(
+    state: &State<ServerState>,
+    batch_id: usize,
+) -> Result<Json<BatchDescription>, Status> {
+    let jobs = state.jobs.lock().await;
 
-        let event_channel = job.event_channel.clone();
+    let Some(batch) = jobs.find_batch(batch_id) else {
+        return Err(Status::NotFound);
+    };
 
-        let mut remaining_analyzers: Vec<_> = analyzers.iter().map(|a| a.name()).collect();
+    Ok(Json(BatchDescription::new(&batch, &jobs)))
+}
 
-        {
-            let job = job.clone();
-            let mut rx = job.subscribe_events();
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct BatchProgress {
+    complete: usize,
+    total: usize,
+    job: BatchJobSummary,
+}
 
-            tokio::spawn(async move {
-                log!(Level::Info, "Subscribed to job {} events", job.id);
+/// Streams a `job_complete` event as each job of the batch completes, in completion order,
+/// then a `batch_complete` event holding the description of the batch.
+/// Jobs that are already complete are sent first.
+#[get("/batch/<batch_id>/events")]
+async fn listen_batch_events(
+    state: &State<ServerState>,
+    batch_id: usize,
+) -> Result<EventStream![], Status> {
+    let jobs = state.jobs.lock().await;
 
-                while let Ok(event) = rx.recv().await {
-                    match event {
-                        JobEvent::Progress(result) => job.results.lock().await.push(result),
-                        JobEvent::ExpandedResultCount(new_count) => {
-                            job.expected_result_count
-                                .fetch_add(new_count as i32, Ordering::Relaxed);
-                        }
-                        JobEvent::Error(_) => todo!("handle error events"),
-                        JobEvent::AnalysisDone(name) => {
-                            remaining_analyzers.retain(|a| a != &name);
-                            if remaining_analyzers.is_empty() {
-                                job.mark_as_complete();
-                                break;
-                            }
-                        }
-                        _ => {}
-                    }
-                }
+    let Some(batch) = jobs.find_batch(batch_id) else {
+        return Err(Status::NotFound);
+    };
+
+    let batch_jobs: Vec<_> = batch
+        .jobs
+        .iter()
+        .filter_map(|entry| Some((entry.clone(), jobs.find_job(entry.job_id)?)))
+        .collect();
 
-                let mut state_ref = job.state.lock().await;
-                *state_ref = JobState::Analyzed;
+    drop(jobs); //release lock
 
-                //TODO jobs.lock().await.complete_job(job_id);
+    let jobs = state.jobs.clone();
+    let total = batch_jobs.len();
+    let mut pending: FuturesUnordered<_> = batch_jobs
+        .into_iter()
+        .map(|(entry, job)| async move {
+            let mut events = job.subscribe_events(0);
+            while events.next().await.is_some() {}
+            BatchJobSummary::new(&entry, &job)
+        })
+        .collect();
 
-                log!(Level::Info, "Unsubscribed from job {} events", job.id)
-            });
+    let stream = EventStream! {
+        let mut complete = 0;
+        while let Some(job) = pending.next().await {
+            complete += 1;
+            yield Event::json(&BatchProgress { complete, total, job }).event("job_complete");
         }
 
-        start_email_analysis(analyzers.clone(), job).await;
+        let description = BatchDescription::new(&batch, &*jobs.lock().await);
+        yield Event::json(&description).event("batch_complete");
+    };
 
-        Ok(Json(JobCreatedResponse { job_id }))
-    } else {
-        Err(Status::BadRequest)
-    }
+    Ok(stream)
 }
 
 #[derive(Serialize)]
@@ -113,10 +260,10 @@ struct ListJobsResponse {
 async fn list_jobs(state: &State<ServerState>) -> Result<Json<ListJobsResponse>, Status> {
     let jobs = state.jobs.lock().await;
 
-    let jobs: Vec<JobDescription> = tokio_stream::iter(jobs.iter_jobs())
-        .then(|j: &Arc<_>| JobDescription::from_job(j))
-        .collect()
-        .await;
+    let jobs: Vec<JobDescription> = jobs
+        .iter_jobs()
+        .map(|j| JobDescription::from_job(j))
+        .collect();
 
     Ok(Json(ListJobsResponse { jobs }))
 }
@@ -138,9 +285,22 @@ async fn listen_new_jobs(state: &State<ServerState>) -> Result<EventStream![], S
     drop(jobs); //release lock
 
     let stream = EventStream! {
-        while let Ok(event) = tx.recv().await {
-            if let ServerStateEvent::NewJob(job_desc) = event {
-                yield Event::json(&job_desc).event("new_job")
+        loop {
+            match tx.recv().await {
+                Ok(ServerStateEvent::NewJob(job_desc)) => {
+                    yield Event::json(&job_desc).event("new_job")
+                }
+                Ok(ServerStateEvent::JobDeleted(job_id)) => {
+                    yield Event::json(&job_id).event("job_deleted")
+                }
+                Ok(ServerStateEvent::VerdictChanged(change)) => {
+                    yield Event::json(&change).event("verdict_changed")
+                }
+                // a client that falls behind only misses job notifications, not job results
+                Err(RecvError::Lagged(count)) => {
+                    log!(Level::Warn, "New jobs listener lagged behind by {count} jobs")
+                }
+                Err(RecvError::Closed) => break,
             }
         }
     };
@@ -148,10 +308,30 @@ async fn listen_new_jobs(state: &State<ServerState>) -> Result<EventStream![], S
     Ok(stream)
 }
 
+/// Sequence number of the last event received by a reconnecting SSE client,
+/// taken from the `Last-Event-ID` header. Defaults to 0, which replays the whole job.
+struct LastEventId(u64);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for LastEventId {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match request.headers().get_one("Last-Event-ID") {
+            None => Outcome::Success(LastEventId(0)),
+            Some(id) => match id.trim().parse() {
+                Ok(id) => Outcome::Success(LastEventId(id)),
+                Err(_) => Outcome::Error((Status::BadRequest, ())),
+            },
+        }
+    }
+}
+
 #[get("/job/<job_id>/events")]
 async fn listen_job_events(
     state: &State<ServerState>,
     job_id: usize,
+    last_event_id: LastEventId,
 ) -> Result<EventStream![], Status> {
     let jobs = state.jobs.lock().await;
 
@@ -161,32 +341,23 @@ async fn listen_job_events(
 
     drop(jobs); //release lock
 
-    if let JobState::Analyzed = *job.state.lock().await {
-        return Err(Status::NoContent);
-    }
-
-    let mut rx = job.subscribe_events();
+    let mut events = job.subscribe_events(last_event_id.0);
 
     let stream = EventStream! {
-        while let Ok(event) = rx.recv().await {
-
-            yield Event::json(&event).event("result");
-
-            if let JobEvent::Error(_) | JobEvent::JobComplete = event {
-                break;
-            }
-
-            if job.is_complete() { //in case the JobComplete event has not been sent/received
-                break;
-            }
+        while let Some((sequence, event)) = events.next().await {
+            yield Event::json(&event).event("result").id(sequence.to_string());
         }
     };
 
     Ok(stream)
 }
 
+/// Serves the raw analyzed email, as text to be displayed by browsers
 #[get("/job/<job_id>/email")]
-async fn get_job_email(state: &State<ServerState>, job_id: usize) -> Result<String, Status> {
+async fn get_job_email(
+    state: &State<ServerState>,
+    job_id: usize,
+) -> Result<(ContentType, Vec<u8>), Status> {
     let jobs = state.jobs.lock().await;
 
     let Some(job) = jobs.find_job(job_id) else {
@@ -195,17 +366,247 @@ async fn get_job_email(state: &State<ServerState>, job_id: usize) -> Result<Stri
 
     drop(jobs); //release lock
 
-    Ok(job.email.clone())
+    Ok((ContentType::new("text", "plain"), job.email.clone()))
 }
 
-#[launch]
-fn rocket() -> _ {
-    init_analyzers();
+/// Serves the file the job was submitted as, which differs from its email when it was converted
+#[get("/job/<job_id>/original")]
+async fn get_job_original(
+    state: &State<ServerState>,
+    job_id: usize,
+) -> Result<OriginalFileResponse, Status> {
+    let jobs = state.jobs.lock().await;
+
+    let Some(job) = jobs.find_job(job_id) else {
+        return Err(Status::NotFound);
+    };
+
+    drop(jobs); //release lock
+
+    let (format, content) = match &job.original {
+        Some(original) => (original.format, original.content.clone()),
+        None => (EmailFormat::Eml, job.email.clone()),
+    };
+
+    Ok(OriginalFileResponse {
+        content,
+        content_type: ContentType::parse_flexible(format.content_type()).unwrap(),
+        disposition: Header::new(
+            "Content-Disposition",
+            format!("attachment; filename=\"job-{job_id}.{}\"", format.extension()),
+        ),
+    })
+}
+
+#[derive(Responder)]
+struct OriginalFileResponse {
+    content: Vec<u8>,
+    content_type: ContentType,
+    disposition: Header<'static>,
+}
+
+#[delete("/job/<job_id>")]
+async fn delete_job(state: &State<ServerState>, job_id: usize) -> Status {
+    match state.jobs.lock().await.remove_job(job_id) {
+        Some(_) => Status::NoContent,
+        None => Status::NotFound,
+    }
+}
+
+#[post("/job/<job_id>/cancel")]
+async fn cancel_job(state: &State<ServerState>, job_id: usize) -> Status {
+    let jobs = state.jobs.lock().await;
+
+    let Some(job) = jobs.find_job(job_id) else {
+        return Status::NotFound;
+    };
+
+    drop(jobs); //release lock
+
+    if job.cancel() {
+        log!(Level::Info, "Cancelling job {job_id}");
+        Status::Accepted
+    } else {
+        Status::Conflict
+    }
+}
+
+/// Runs analyzers of a complete job again, e.g. after a service outage, in place of their
+/// previous results. Every analyzer the job ran is run again unless some are chosen, as in `/job`.
+/// The new results are streamed on the events of the job.
+#[post("/job/<job_id>/rerun?<analyzers>")]
+async fn rerun_job(
+    state: &State<ServerState>,
+    job_id: usize,
+    analyzers: Option<&str>,
+) -> Result<Status, (Status, String)> {
+    let jobs = state.jobs.lock().await;
+
+    let Some(job) = jobs.find_job(job_id) else {
+        return Err((Status::NotFound, format!("no job {job_id}")));
+    };
+
+    drop(jobs); //release lock
+
+    if let Some(original) = job.duplicate_of {
+        return Err((
+            Status::Conflict,
+            format!("job {job_id} reuses the results of job {original}, rerun it instead"),
+        ));
+    }
+
+    let ids = analyzers.map(analyzer_ids);
+    let selected: Vec<_> = ANALYZERS
+        .get()
+        .unwrap()
+        .iter()
+        .filter(|a| job.options.runs(a.id()))
+        .filter(|a| ids.as_ref().is_none_or(|ids| ids.iter().any(|id| id == a.id())))
+        .cloned()
+        .collect();
+
+    if let Some(id) = ids
+        .iter()
+        .flatten()
+        .find(|id| !selected.iter().any(|a| a.id() == id.as_str()))
+    {
+        return Err((
+            Status::BadRequest,
+            format!("analyzer `{id}` is disabled or did not run on job {job_id}"),
+        ));
+    }
+    if selected.is_empty() {
+        return Err((Status::BadRequest, String::from("no analyzer is selected")));
+    }
+
+    if submission::rerun_job(&state.jobs, job, selected, &state.timeouts) {
+        log!(Level::Info, "Rerunning job {job_id}");
+        Ok(Status::Accepted)
+    } else {
+        Err((
+            Status::Conflict,
+            format!("job {job_id} is still being analyzed"),
+        ))
+    }
+}
+
+#[get("/rules")]
+async fn list_rules() -> Json<Vec<RuleDefinition>> {
+    let rules = RULES.get().unwrap().rules();
+    Json(rules.iter().map(|r| r.definition.clone()).collect())
+}
+
+#[post("/rules/reload")]
+async fn reload_rules() -> Result<Json<Vec<RuleDefinition>>, (Status, String)> {
+    let rules = RULES
+        .get()
+        .unwrap()
+        .reload()
+        .map_err(|err| (Status::BadRequest, err.to_string()))?;
+
+    Ok(Json(rules.iter().map(|r| r.definition.clone()).collect()))
+}
+
+/// Fetches the secrets again, e.g. after a key rotation. Only the number of keys is returned.
+#[post("/secrets/reload")]
+async fn reload_secrets() -> Result<Json<HashMap<String, usize>>, (Status, String)> {
+    let secrets = SECRETS.get().unwrap();
+    secrets
+        .reload()
+        .await
+        .map_err(|err| (Status::BadRequest, err.to_string()))?;
+
+    Ok(Json(secrets.key_counts()))
+}
+
+#[rocket::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let config = Config::load(&cli).unwrap_or_else(|err| {
+        panic!("could not load configuration {}: {err}", cli.config.display())
+    });
+
+    init_secrets(&config.secrets).unwrap_or_else(|err| panic!("could not set up secrets: {err}"));
+    init_analyzers(&config);
+    init_rules(&config.rules.directory);
+
+    match &cli.command {
+        Some(Command::Analyze(args)) => cli::analyze(args, &config).await,
+        Some(Command::Batch(args)) => cli::batch(args, &config).await,
+        None => match rocket(&config).launch().await {
+            Ok(_) => ExitCode::SUCCESS,
+            // the error reports itself when dropped
+            Err(_) => ExitCode::FAILURE,
+        },
+    }
+}
+
+fn rocket(config: &Config) -> Rocket<Build> {
+    let storage = SqliteStorage::open(&config.storage.path).expect("could not open job storage");
+    let jobs = Jobs::load(Box::new(storage), RetentionPolicy::from(&config.retention))
+        .expect("could not load stored jobs")
+        .with_dedup_policy(config.deduplication.policy);
+    let jobs = Arc::new(Mutex::new(jobs));
+    let timeouts = AnalysisTimeouts::from(&config.timeouts);
+    let analyzers = ANALYZERS.get().unwrap();
+
+    // created before the secrets are loaded, which only loads the requested ones
+    let mailboxes: Vec<_> = config
+        .ingestion
+        .imap
+        .iter()
+        .map(|mailbox| {
+            Arc::new(ImapIngestion::new(
+                mailbox.clone(),
+                jobs.clone(),
+                analyzers.clone(),
+                timeouts.clone(),
+            ))
+        })
+        .collect();
+    let directories: Vec<_> = config
+        .ingestion
+        .maildir
+        .iter()
+        .map(|directory| {
+            Arc::new(MaildirIngestion::new(
+                directory.clone(),
+                jobs.clone(),
+                analyzers.clone(),
+                timeouts.clone(),
+            ))
+        })
+        .collect();
+    let smtp = config
+        .ingestion
+        .smtp
+        .clone()
+        .map(|smtp| {
+            Arc::new(SmtpReceiver::new(
+                smtp,
+                jobs.clone(),
+                analyzers.clone(),
+                timeouts.clone(),
+            ))
+        });
+
+    let http = HttpClient::new(&config.http);
+    let rechecks = Arc::new(RecheckScheduler::new(
+        config.recheck.clone(),
+        jobs.clone(),
+        LinkRechecker::new(
+            config.services.virustotal.clone(),
+            SECRETS.get().unwrap().key_ring(VIRUSTOTAL),
+            http.clone(),
+        ),
+        http,
+        timeouts.task,
+    ));
 
     let cors = CorsOptions::default()
-        .allowed_origins(AllowedOrigins::some_exact(&["http://localhost:5173"]))
+        .allowed_origins(AllowedOrigins::some_exact(&config.server.cors_origins))
         .allowed_methods(
-            vec![Method::Get, Method::Post, Method::Options]
+            vec![Method::Get, Method::Post, Method::Delete, Method::Options]
                 .into_iter()
                 .map(From::from)
                 .collect(),
@@ -214,20 +615,73 @@ fn rocket() -> _ {
         .to_cors()
         .unwrap();
 
-    rocket::build()
+    let figment = rocket::Config::figment()
+        .merge(("address", config.server.address))
+        .merge(("port", config.server.port));
+
+    rocket::custom(figment)
         .attach(cors)
+        .attach(AdHoc::try_on_ignite("Secrets", |rocket| async {
+            match SECRETS.get().unwrap().reload().await {
+                Ok(()) => Ok(rocket),
+                Err(err) => {
+                    log!(Level::Error, "could not load secrets: {err}");
+                    Err(rocket)
+                }
+            }
+        }))
+        .attach(AdHoc::on_liftoff("Job retention", |rocket| {
+            Box::pin(async move {
+                let jobs = rocket.state::<ServerState>().unwrap().jobs.clone();
+                tokio::spawn(async move {
+                    let mut interval = tokio::time::interval(RETENTION_CHECK_INTERVAL);
+                    loop {
+                        interval.tick().await;
+                        jobs.lock().await.apply_retention();
+                    }
+                });
+            })
+        }))
+        .attach(AdHoc::on_liftoff("Link rechecks", move |_| {
+            rechecks.clone().start();
+            Box::pin(async {})
+        }))
+        .attach(AdHoc::on_liftoff("Ingestion", move |_| {
+            for mailbox in &mailboxes {
+                mailbox.clone().start();
+            }
+            for directory in &directories {
+                directory.clone().start();
+            }
+            if let Some(smtp) = &smtp {
+                smtp.clone().start();
+            }
+            Box::pin(async {})
+        }))
         .manage(ServerState {
-            jobs: Arc::new(Mutex::new(Jobs::new())),
+            jobs,
+            timeouts,
+            batch: BatchLimits::from(&config.batch),
         })
         .mount(
             "/",
             routes![
                 submit_mail,
+                submit_archive,
+                get_batch,
+                listen_batch_events,
                 list_jobs,
                 listen_job_events,
                 listen_new_jobs,
                 list_jobs_ids,
-                get_job_email
+                get_job_email,
+                get_job_original,
+                delete_job,
+                cancel_job,
+                rerun_job,
+                list_rules,
+                reload_rules,
+                reload_secrets
             ],
         )
 }
@@ -0,0 +1,479 @@
+//! Follow-up lookups of the links of analyzed emails, since a link VirusTotal reports as clean
+//! when the email is delivered is often flagged a few hours later.
+//!
+//! Jobs submitted with the `links.recheck` option have their `url` and `domain` verdicts looked
+//! up again after each configured delay. Every lookup is recorded on the job, and a
+//! [`VerdictChange`] is published when a link is now flagged as malicious. A lookup that failed
+//! for any of the links is tried again at the next checks, and recorded without these links once
+//! it failed `max_attempts` times.
+
+use crate::analysis::{AnalysisVerdict, JobEvent, LinkRechecker};
+use crate::config::RecheckConfig;
+use crate::http::HttpClient;
+use crate::job::Job;
+use crate::scoring::{link_detections, link_label, RiskLabel};
+use crate::state::{Jobs, ServerStateEvent};
+use chrono::{DateTime, TimeDelta, Utc};
+use log::{info, warn};
+use rocket::serde::json::Value;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+/// Interval at which the jobs are checked for due lookups
+const RECHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// State of a link at a lookup
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct LinkSnapshot {
+    /// Kind of the verdict of the link, `url` or `domain`
+    pub kind: String,
+    pub link: String,
+    /// Number of VirusTotal engines flagging the link as malicious
+    pub malicious: u64,
+    /// Number of VirusTotal engines flagging the link as suspicious
+    pub suspicious: u64,
+    pub label: RiskLabel,
+}
+
+impl LinkSnapshot {
+    pub fn new(kind: &str, link: &str, report: &Value) -> Self {
+        let (malicious, suspicious) = link_detections(report);
+        Self {
+            kind: kind.to_string(),
+            link: link.to_string(),
+            malicious,
+            suspicious,
+            label: link_label(malicious, suspicious),
+        }
+    }
+}
+
+/// A lookup of the links of a job
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Recheck {
+    pub checked_at: DateTime<Utc>,
+    pub links: Vec<LinkSnapshot>,
+    /// Links that could not be looked up at any attempt
+    #[serde(default)]
+    pub failed: Vec<String>,
+}
+
+/// A link of a job that was not flagged as malicious, and now is
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct VerdictChange {
+    pub job_id: usize,
+    pub checked_at: DateTime<Utc>,
+    /// Label of the link at its previous lookup, or at the analysis
+    pub previous: RiskLabel,
+    pub link: LinkSnapshot,
+}
+
+/// Looks the links of the jobs up again once their lookups are due
+pub struct RecheckScheduler {
+    config: RecheckConfig,
+    jobs: Arc<Mutex<Jobs>>,
+    checker: LinkRechecker,
+    http: HttpClient,
+    /// Maximum duration of the lookup of a link
+    lookup_timeout: Duration,
+    /// Failed attempts at the due lookup of each job
+    attempts: std::sync::Mutex<HashMap<usize, u32>>,
+}
+
+impl RecheckScheduler {
+    pub fn new(
+        config: RecheckConfig,
+        jobs: Arc<Mutex<Jobs>>,
+        checker: LinkRechecker,
+        http: HttpClient,
+        lookup_timeout: Duration,
+    ) -> Self {
+        Self {
+            config,
+            jobs,
+            checker,
+            http,
+            lookup_timeout,
+            attempts: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Spawns the periodic lookups
+    pub fn start(self: Arc<Self>) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(RECHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                self.recheck_due_jobs().await;
+            }
+        });
+    }
+
+    /// Returns when the next lookup of the links of a job is due, if any
+    fn next_recheck(&self, job: &Job) -> Option<DateTime<Utc>> {
+        let enabled = job.options.runs("links")
+            && job.options.option("links", "recheck") == Some(true)
+            && job.duplicate_of.is_none();
+        if !enabled {
+            return None;
+        }
+
+        let round = job.rechecks.lock().unwrap().len();
+        let delay = TimeDelta::seconds(*self.config.delays.get(round)? as i64);
+        Some(job.created_at + delay)
+    }
+
+    async fn recheck_due_jobs(&self) {
+        let now = Utc::now();
+        let due: Vec<_> = self
+            .jobs
+            .lock()
+            .await
+            .iter_jobs()
+            .filter(|job| job.is_complete() && !job.is_cancelled())
+            .filter(|job| self.next_recheck(job).is_some_and(|at| at <= now))
+            .cloned()
+            .collect();
+
+        for job in due {
+            self.recheck_job(&job).await;
+        }
+    }
+
+    /// Looks the links of a job up again, then publishes the lookup and the changed verdicts.
+    /// Nothing is published if a link could not be looked up, the lookup is then still due,
+    /// until it failed `max_attempts` times.
+    async fn recheck_job(&self, job: &Arc<Job>) {
+        // URLs of confidential emails are only looked up, not scanned
+        let rescan = job.options.option("links", "submit_unknown") != Some(false);
+
+        let mut links = vec![];
+        let mut failed = vec![];
+        for (kind, link) in job_links(job) {
+            let lookup = self.checker.report(&kind, &link, rescan);
+            match tokio::time::timeout(self.lookup_timeout, lookup).await {
+                Ok(Ok(report)) => links.push(LinkSnapshot::new(&kind, &link, &report)),
+                Ok(Err(err)) => {
+                    warn!("Job {}: could not look {link} up again: {err}", job.id);
+                    failed.push(link);
+                }
+                Err(_) => {
+                    warn!("Job {}: the lookup of {link} timed out", job.id);
+                    failed.push(link);
+                }
+            }
+        }
+
+        if !failed.is_empty() {
+            let mut attempts = self.attempts.lock().unwrap();
+            let attempt = attempts.entry(job.id).or_default();
+            *attempt += 1;
+            if *attempt < self.config.max_attempts {
+                warn!("Job {}: links will be looked up again at the next check", job.id);
+                return;
+            }
+            warn!(
+                "Job {}: {} links given up after {attempt} attempts",
+                job.id,
+                failed.len()
+            );
+        }
+        self.attempts.lock().unwrap().remove(&job.id);
+
+        let recheck = Recheck {
+            checked_at: Utc::now(),
+            links,
+            failed,
+        };
+        let changes = verdict_changes(job, &recheck);
+
+        // the job may have been rerun in the meantime, its lookup is then done again later
+        if !job.is_complete() {
+            return;
+        }
+
+        {
+            let jobs = self.jobs.lock().await;
+            for change in &changes {
+                info!(
+                    "Job {}: {} {} is now flagged as malicious",
+                    job.id, change.link.kind, change.link.link
+                );
+                job.publish(JobEvent::VerdictChanged(change.clone()));
+                jobs.notify(ServerStateEvent::VerdictChanged(change.clone()));
+            }
+            // published last, the events of the job end with it
+            info!("Links of job {} looked up again", job.id);
+            job.publish(JobEvent::LinksRechecked(recheck));
+            jobs.save_job(job);
+        }
+
+        for change in &changes {
+            self.notify_webhook(change).await;
+        }
+    }
+
+    async fn notify_webhook(&self, change: &VerdictChange) {
+        let Some(webhook) = &self.config.webhook else {
+            return;
+        };
+
+        let request = self.http.post(webhook.clone()).json(change);
+        match self.http.send(request).await {
+            Ok(response) if !response.status().is_success() => {
+                warn!("verdict change webhook answered {}", response.status())
+            }
+            Ok(_) => {}
+            Err(err) => warn!("could not notify the verdict change webhook: {err}"),
+        }
+    }
+}
+
+/// Returns the kind and the link of the `url` and `domain` verdicts of a job
+fn job_links(job: &Job) -> Vec<(String, String)> {
+    job.results
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|r| matches!(r.verdict.kind.as_str(), "url" | "domain"))
+        .filter_map(|r| {
+            Some((
+                r.verdict.kind.clone(),
+                verdict_link(&r.verdict)?.to_string(),
+            ))
+        })
+        .collect()
+}
+
+/// The link of a `url` or `domain` verdict. Verdicts produced before the link was part of them
+/// only hold it in their report.
+fn verdict_link(verdict: &AnalysisVerdict) -> Option<&str> {
+    let value = &verdict.value;
+    let data = &value["report"]["data"];
+    let link = match verdict.kind.as_str() {
+        "url" => data["attributes"]["url"].as_str(),
+        _ => data["id"].as_str(),
+    };
+    value["link"].as_str().or(link)
+}
+
+/// Compares a lookup to the previous state of the links of a job, and returns the links
+/// that are now flagged as malicious
+fn verdict_changes(job: &Job, recheck: &Recheck) -> Vec<VerdictChange> {
+    let rechecks = job.rechecks.lock().unwrap();
+    let results = job.results.lock().unwrap();
+
+    let previous_label = |snapshot: &LinkSnapshot| {
+        let rechecked = rechecks
+            .iter()
+            .rev()
+            .flat_map(|r| &r.links)
+            .find(|s| s.kind == snapshot.kind && s.link == snapshot.link)
+            .map(|s| s.label);
+
+        rechecked.or_else(|| {
+            let verdict = results
+                .iter()
+                .map(|r| &r.verdict)
+                .find(|v| v.kind == snapshot.kind && verdict_link(v) == Some(&snapshot.link))?;
+            let (malicious, suspicious) = link_detections(&verdict.value["report"]);
+            Some(link_label(malicious, suspicious))
+        })
+    };
+
+    recheck
+        .links
+        .iter()
+        .filter(|snapshot| snapshot.label == RiskLabel::Malicious)
+        .filter_map(|snapshot| {
+            let previous = previous_label(snapshot)?;
+            (previous != RiskLabel::Malicious).then(|| VerdictChange {
+                job_id: job.id,
+                checked_at: recheck.checked_at,
+                previous,
+                link: snapshot.clone(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::analysis::{AnalysisResult, AnalysisVerdict, JobEvent, LinkRechecker};
+    use crate::config::{RecheckConfig, VirusTotalConfig};
+    use crate::http::HttpClient;
+    use crate::job::Job;
+    use crate::recheck::{
+        job_links, verdict_changes, LinkSnapshot, Recheck, RecheckScheduler, VerdictChange,
+    };
+    use crate::scoring::RiskLabel;
+    use crate::secrets::{KeyRing, Secret};
+    use crate::state::{Jobs, RetentionPolicy};
+    use crate::storage::SqliteStorage;
+    use chrono::Utc;
+    use rocket::async_test;
+    use rocket::serde::json::json;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+    use tokio::sync::Mutex;
+
+    fn report(malicious: u64) -> rocket::serde::json::Value {
+        json!({"data": {"attributes": {"last_analysis_stats": {"malicious": malicious}}}})
+    }
+
+    #[test]
+    fn test_verdict_changes() {
+        let job = Job::new(Vec::new(), 1);
+        job.publish(JobEvent::Progress(AnalysisResult::new(
+            String::from("Links analysis"),
+            AnalysisVerdict::new(
+                "url",
+                json!({"link": "https://example.org/login", "tags": [], "report": report(0)}),
+            ),
+        )));
+        job.publish(JobEvent::Progress(AnalysisResult::new(
+            String::from("Links analysis"),
+            AnalysisVerdict::new(
+                "domain",
+                json!({"tags": [], "report": {"data": {"id": "example.org"}}}),
+            ),
+        )));
+        assert_eq!(
+            job_links(&job),
+            [
+                (
+                    String::from("url"),
+                    String::from("https://example.org/login")
+                ),
+                (String::from("domain"), String::from("example.org")),
+            ]
+        );
+
+        let recheck = |malicious| Recheck {
+            checked_at: Utc::now(),
+            links: vec![LinkSnapshot::new(
+                "url",
+                "https://example.org/login",
+                &report(malicious),
+            )],
+            failed: vec![],
+        };
+
+        assert!(verdict_changes(&job, &recheck(1)).is_empty());
+        job.publish(JobEvent::LinksRechecked(recheck(1)));
+
+        let flagged = recheck(5);
+        let changes = verdict_changes(&job, &flagged);
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].previous, RiskLabel::Suspicious);
+        assert_eq!(changes[0].link, flagged.links[0]);
+        job.publish(JobEvent::LinksRechecked(flagged));
+
+        // a link is only reported once it turns malicious
+        assert!(verdict_changes(&job, &recheck(7)).is_empty());
+    }
+
+    fn scheduler(url: String, max_attempts: u32) -> RecheckScheduler {
+        let storage = SqliteStorage::open(":memory:").unwrap();
+        let jobs = Jobs::load(Box::new(storage), RetentionPolicy::default()).unwrap();
+        let checker = LinkRechecker::new(
+            VirusTotalConfig {
+                url: url.parse().unwrap(),
+            },
+            Arc::new(KeyRing::new("virustotal", vec![Secret::new("key")])),
+            HttpClient::default(),
+        );
+        RecheckScheduler::new(
+            RecheckConfig {
+                max_attempts,
+                ..RecheckConfig::default()
+            },
+            Arc::new(Mutex::new(jobs)),
+            checker,
+            HttpClient::default(),
+            Duration::from_millis(200),
+        )
+    }
+
+    fn link_job() -> Arc<Job> {
+        let job = Arc::new(Job::new(Vec::new(), 1));
+        job.publish(JobEvent::Progress(AnalysisResult::new(
+            String::from("Links analysis"),
+            AnalysisVerdict::new(
+                "url",
+                json!({"link": "https://example.org/login", "tags": [], "report": report(0)}),
+            ),
+        )));
+        job.publish(JobEvent::JobComplete);
+        job
+    }
+
+    #[async_test]
+    async fn test_failed_lookup() {
+        // nothing listens on the port once the listener is dropped
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        drop(listener);
+
+        let scheduler = scheduler(url, 2);
+        let job = link_job();
+
+        // the round is not consumed, the lookup is done again at the next check
+        scheduler.recheck_job(&job).await;
+        assert!(job.rechecks.lock().unwrap().is_empty());
+
+        // until it failed too many times
+        scheduler.recheck_job(&job).await;
+        let rechecks = job.rechecks.lock().unwrap();
+        assert_eq!(rechecks.len(), 1);
+        assert!(rechecks[0].links.is_empty());
+        assert_eq!(rechecks[0].failed, ["https://example.org/login"]);
+    }
+
+    #[async_test]
+    async fn test_lookup_timeout() {
+        // connections are accepted by the system, but never answered
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+
+        let scheduler = scheduler(url, 1);
+        let job = link_job();
+
+        scheduler.recheck_job(&job).await;
+        assert_eq!(job.rechecks.lock().unwrap()[0].failed.len(), 1);
+        drop(listener);
+    }
+
+    #[async_test]
+    async fn test_recheck_events() {
+        let job = Job::new(Vec::new(), 1);
+        job.publish(JobEvent::JobComplete);
+
+        let flagged = Recheck {
+            checked_at: Utc::now(),
+            links: vec![LinkSnapshot::new("url", "https://example.org/login", &report(5))],
+            failed: vec![],
+        };
+        job.publish(JobEvent::VerdictChanged(VerdictChange {
+            job_id: 1,
+            checked_at: flagged.checked_at,
+            previous: RiskLabel::Clean,
+            link: flagged.links[0].clone(),
+        }));
+
+        // the events go on after a verdict change, until the lookup that flagged the link
+        let mut events = job.subscribe_events(0);
+        assert!(matches!(events.next().await, Some((1, JobEvent::JobComplete))));
+        assert!(matches!(events.next().await, Some((2, JobEvent::VerdictChanged(_)))));
+        job.publish(JobEvent::LinksRechecked(flagged));
+        assert!(matches!(events.next().await, Some((3, JobEvent::LinksRechecked(_)))));
+        assert!(events.next().await.is_none());
+    }
+}
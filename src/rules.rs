@@ -0,0 +1,282 @@
+mod expression;
+
+use crate::analysis::AnalysisVerdict;
+use crate::rules::expression::{EvaluationContext, Expression};
+use log::{info, warn};
+use mail_parser::Message;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use tokio::sync::OnceCell;
+
+/// Name under which rule matches are reported in the job results
+pub const RULES_ANALYSIS_NAME: &str = "Detection Rules";
+
+pub static RULES: OnceCell<RuleEngine> = OnceCell::const_new();
+
+/// Loads the detection rules of the given directory.
+/// A missing directory is not an error, the server simply runs without custom detections.
+pub fn init_rules(directory: impl Into<PathBuf>) {
+    let engine = RuleEngine::new(directory.into());
+    if let Err(err) = engine.reload() {
+        warn!("could not load detection rules: {err}")
+    }
+    if RULES.set(engine).is_err() {
+        panic!("rules should not be already initialized")
+    };
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "camelCase")]
+pub enum Severity {
+    Info,
+    Low,
+    Medium,
+    High,
+    Critical,
+}
+
+/// A detection rule, as written in the rule files :
+/// ```toml
+/// [[rule]]
+/// id = "dmarc-fail-new-domain"
+/// severity = "high"
+/// description = "DMARC fails and the sender domain is less than 30 days old"
+/// condition = '''
+///     auth-dmarc.spf == "fail"
+///     and age_days(domain[sender].report.data.attributes.creation_date) < 30
+/// '''
+/// ```
+/// or, in a YAML file :
+/// ```yaml
+/// rule:
+///   - id: dmarc-fail-new-domain
+///     severity: high
+///     description: DMARC fails and the sender domain is less than 30 days old
+///     condition: >
+///       auth-dmarc.spf == "fail"
+///       and age_days(domain[sender].report.data.attributes.creation_date) < 30
+/// ```
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RuleDefinition {
+    pub id: String,
+    pub severity: Severity,
+    pub description: String,
+    pub condition: String,
+}
+
+#[derive(Deserialize)]
+struct RuleFile {
+    #[serde(default, rename = "rule")]
+    rules: Vec<RuleDefinition>,
+}
+
+pub struct Rule {
+    pub definition: RuleDefinition,
+    condition: Expression,
+}
+
+/// Value of the `rule-match` verdicts
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RuleMatch {
+    pub id: String,
+    pub severity: Severity,
+    pub description: String,
+}
+
+#[derive(Debug)]
+pub enum RuleError {
+    Std(Box<dyn std::error::Error + Send + Sync>),
+    Syntax(String),
+}
+
+impl<E: std::error::Error + Send + Sync + 'static> From<E> for RuleError {
+    fn from(value: E) -> Self {
+        RuleError::Std(Box::new(value))
+    }
+}
+
+impl Display for RuleError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RuleError::Std(err) => write!(f, "{err}"),
+            RuleError::Syntax(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+pub struct RuleEngine {
+    directory: PathBuf,
+    rules: RwLock<Arc<Vec<Rule>>>,
+}
+
+impl RuleEngine {
+    fn new(directory: PathBuf) -> Self {
+        Self {
+            directory,
+            rules: RwLock::new(Arc::new(vec![])),
+        }
+    }
+
+    /// Reloads every `.toml`, `.yaml` and `.yml` file of the rules directory.
+    /// If any rule is invalid, the currently loaded rules are kept.
+    pub fn reload(&self) -> Result<Arc<Vec<Rule>>, RuleError> {
+        let rules = Arc::new(load_rules(&self.directory)?);
+
+        info!(
+            "Loaded {} detection rules from {}",
+            rules.len(),
+            self.directory.display()
+        );
+
+        *self.rules.write().unwrap() = rules.clone();
+        Ok(rules)
+    }
+
+    pub fn rules(&self) -> Arc<Vec<Rule>> {
+        self.rules.read().unwrap().clone()
+    }
+
+    /// Evaluates every rule against the verdicts and headers of an email,
+    /// and returns a `rule-match` verdict for each matching rule
+    pub fn evaluate<'a>(
+        &self,
+        email: &Message,
+        verdicts: impl IntoIterator<Item = &'a AnalysisVerdict>,
+    ) -> Vec<AnalysisVerdict> {
+        let verdicts: Vec<_> = verdicts
+            .into_iter()
+            .map(|v| (v.kind.clone(), v.value.clone()))
+            .collect();
+
+        let headers: Vec<_> = email
+            .headers_raw()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+
+        let context = EvaluationContext {
+            verdicts: &verdicts,
+            headers: &headers,
+        };
+
+        self.rules()
+            .iter()
+            .filter(|rule| rule.condition.evaluate(&context))
+            .map(|rule| {
+                AnalysisVerdict::new(
+                    "rule-match",
+                    RuleMatch {
+                        id: rule.definition.id.clone(),
+                        severity: rule.definition.severity,
+                        description: rule.definition.description.clone(),
+                    },
+                )
+            })
+            .collect()
+    }
+}
+
+fn load_rules(directory: &Path) -> Result<Vec<Rule>, RuleError> {
+    if !directory.is_dir() {
+        warn!("rules directory {} does not exist", directory.display());
+        return Ok(vec![]);
+    }
+
+    let mut paths = std::fs::read_dir(directory)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    paths.retain(|p| {
+        p.extension()
+            .is_some_and(|e| e == "toml" || e == "yaml" || e == "yml")
+    });
+    paths.sort();
+
+    let mut rules: Vec<Rule> = vec![];
+    for path in paths {
+        let content = std::fs::read_to_string(&path)?;
+        let file: RuleFile = if path.extension().is_some_and(|e| e == "toml") {
+            toml::from_str(&content)?
+        } else {
+            serde_yaml::from_str(&content)?
+        };
+
+        for definition in file.rules {
+            if rules.iter().any(|r| r.definition.id == definition.id) {
+                return Err(RuleError::Syntax(format!(
+                    "duplicated rule id `{}` in {}",
+                    definition.id,
+                    path.display()
+                )));
+            }
+
+            let condition = Expression::parse(&definition.condition).map_err(|e| {
+                RuleError::Syntax(format!(
+                    "rule `{}` of {}: {e}",
+                    definition.id,
+                    path.display()
+                ))
+            })?;
+
+            rules.push(Rule {
+                definition,
+                condition,
+            });
+        }
+    }
+
+    Ok(rules)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::analysis::AnalysisVerdict;
+    use crate::rules::{RuleEngine, Severity};
+    use mail_parser::MessageParser;
+    use rocket::serde::json::json;
+
+    #[test]
+    fn test_bundled_rules() {
+        let engine = RuleEngine::new("rules".into());
+        assert!(!engine.reload().unwrap().is_empty());
+
+        let email = MessageParser::new()
+            .parse("From: a@example.com\r\nReply-To: b@evil.example\r\nSubject: test\r\n\r\nhello")
+            .unwrap();
+        let verdicts = [AnalysisVerdict::new("auth-dmarc", json!({"dkim": "fail", "spf": "fail"}))];
+
+        let matches = engine.evaluate(&email, &verdicts);
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].kind, "rule-match");
+        assert_eq!(matches[0].value["id"], "reply-to-mismatch");
+
+        // the same address under a display name is not a mismatch
+        let email = MessageParser::new()
+            .parse("From: PayPal <Service@PayPal.com>\r\nReply-To: service@paypal.com\r\n\r\nhi")
+            .unwrap();
+        assert!(engine.evaluate(&email, &verdicts).is_empty());
+    }
+
+    #[test]
+    fn test_yaml_rules() {
+        let directory = std::env::temp_dir().join(format!("rules-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(
+            directory.join("soc.yaml"),
+            "rule:\n  - id: dmarc-fail\n    severity: medium\n    description: DMARC fails\n    condition: auth-dmarc.spf == \"fail\"\n",
+        )
+        .unwrap();
+
+        let engine = RuleEngine::new(directory.clone());
+        let rules = engine.reload().unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(rules.len(), 1);
+        assert_eq!(rules[0].definition.severity, Severity::Medium);
+        let verdicts = [AnalysisVerdict::new("auth-dmarc", json!({"spf": "fail"}))];
+        let email = MessageParser::new().parse("Subject: test\r\n\r\nhello").unwrap();
+        assert_eq!(engine.evaluate(&email, &verdicts).len(), 1);
+    }
+}
@@ -0,0 +1,537 @@
+//! Condition language of the detection rules.
+//!
+//! A condition combines comparisons with `and`, `or`, `not` and parentheses.
+//! Operands are literals (`"fail"`, `30`, `true`), paths or function calls over a path :
+//! - `auth-dmarc.spf` selects the `spf` field of every `auth-dmarc` verdict
+//! - `url[body].report.data.attributes.last_analysis_stats.malicious` only keeps the `url`
+//!   verdicts tagged `body`
+//! - `header.Reply-To` selects the values of the `Reply-To` headers of the email
+//! - `age_days(...)`, `count(...)` and `lower(...)` transform the selected values
+//! - `address(...)` reads address headers, e.g. `"PayPal" <a@x>`, as their lowercase addresses
+//!
+//! A path can select several values, a comparison holds if it holds for any of them.
+
+use chrono::{DateTime, Utc};
+use mail_parser::{Address, MessageParser};
+use regex::Regex;
+use rocket::serde::json::Value;
+use std::iter::Peekable;
+use std::str::Chars;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    LParen,
+    RParen,
+    LBracket,
+    RBracket,
+    Dot,
+    Str(String),
+    Num(f64),
+    Ident(String),
+    Op(CompareOp),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Contains,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Function {
+    AgeDays,
+    Count,
+    Lower,
+    Address,
+}
+
+#[derive(Debug)]
+struct Path {
+    root: String,
+    tag: Option<String>,
+    fields: Vec<String>,
+}
+
+#[derive(Debug)]
+enum Operand {
+    Literal(Value),
+    Path(Path),
+    Call(Function, Path),
+}
+
+#[derive(Debug)]
+enum Node {
+    Or(Box<Node>, Box<Node>),
+    And(Box<Node>, Box<Node>),
+    Not(Box<Node>),
+    Compare(Operand, CompareOp, Operand),
+    Matches(Operand, Regex),
+    Exists(Operand),
+}
+
+/// A parsed rule condition
+#[derive(Debug)]
+pub struct Expression {
+    root: Node,
+}
+
+/// What a condition is evaluated against
+pub struct EvaluationContext<'a> {
+    pub verdicts: &'a [(String, Value)],
+    pub headers: &'a [(String, String)],
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut chars = source.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' | ')' | '[' | ']' | '.' => {
+                chars.next();
+                tokens.push(match c {
+                    '(' => Token::LParen,
+                    ')' => Token::RParen,
+                    '[' => Token::LBracket,
+                    ']' => Token::RBracket,
+                    _ => Token::Dot,
+                });
+            }
+            '"' | '\'' => tokens.push(Token::Str(read_string(&mut chars)?)),
+            '=' | '!' | '<' | '>' => {
+                chars.next();
+                let followed_by_eq = chars.next_if_eq(&'=').is_some();
+                tokens.push(Token::Op(match (c, followed_by_eq) {
+                    ('=', true) => CompareOp::Eq,
+                    ('!', true) => CompareOp::Ne,
+                    ('<', false) => CompareOp::Lt,
+                    ('<', true) => CompareOp::Le,
+                    ('>', false) => CompareOp::Gt,
+                    ('>', true) => CompareOp::Ge,
+                    _ => return Err(format!("unexpected character `{c}`")),
+                }));
+            }
+            c if c.is_ascii_digit() => {
+                let mut number = String::new();
+                while let Some(c) = chars.next_if(|c| c.is_ascii_digit() || *c == '.') {
+                    number.push(c);
+                }
+                let number = number
+                    .parse()
+                    .map_err(|_| format!("invalid number `{number}`"))?;
+                tokens.push(Token::Num(number));
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let mut ident = String::new();
+                while let Some(c) =
+                    chars.next_if(|c| c.is_alphanumeric() || *c == '_' || *c == '-')
+                {
+                    ident.push(c);
+                }
+                tokens.push(Token::Ident(ident));
+            }
+            c => return Err(format!("unexpected character `{c}`")),
+        }
+    }
+
+    Ok(tokens)
+}
+
+fn read_string(chars: &mut Peekable<Chars>) -> Result<String, String> {
+    let quote = chars.next().unwrap();
+    let mut string = String::new();
+
+    loop {
+        match chars.next() {
+            None => return Err(String::from("unterminated string")),
+            Some('\\') => match chars.next() {
+                Some(c) => string.push(c),
+                None => return Err(String::from("unterminated string")),
+            },
+            Some(c) if c == quote => return Ok(string),
+            Some(c) => string.push(c),
+        }
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), String> {
+        match self.next() {
+            Some(token) if token == expected => Ok(()),
+            Some(token) => Err(format!("expected {expected:?}, found {token:?}")),
+            None => Err(format!("expected {expected:?}, found end of condition")),
+        }
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Ident(i)) if i == keyword)
+    }
+
+    fn parse_or(&mut self) -> Result<Node, String> {
+        let mut expression = self.parse_and()?;
+        while self.is_keyword("or") {
+            self.next();
+            expression = Node::Or(Box::new(expression), Box::new(self.parse_and()?));
+        }
+        Ok(expression)
+    }
+
+    fn parse_and(&mut self) -> Result<Node, String> {
+        let mut expression = self.parse_not()?;
+        while self.is_keyword("and") {
+            self.next();
+            expression = Node::And(Box::new(expression), Box::new(self.parse_not()?));
+        }
+        Ok(expression)
+    }
+
+    fn parse_not(&mut self) -> Result<Node, String> {
+        if self.is_keyword("not") {
+            self.next();
+            return Ok(Node::Not(Box::new(self.parse_not()?)));
+        }
+        if self.peek() == Some(&Token::LParen) {
+            self.next();
+            let expression = self.parse_or()?;
+            self.expect(Token::RParen)?;
+            return Ok(expression);
+        }
+        self.parse_comparison()
+    }
+
+    fn parse_comparison(&mut self) -> Result<Node, String> {
+        let left = self.parse_operand()?;
+
+        let op = match self.peek() {
+            Some(Token::Op(op)) => *op,
+            Some(Token::Ident(i)) if i == "contains" => CompareOp::Contains,
+            Some(Token::Ident(i)) if i == "matches" => {
+                self.next();
+                return match self.next() {
+                    Some(Token::Str(pattern)) => Regex::new(&pattern)
+                        .map(|regex| Node::Matches(left, regex))
+                        .map_err(|e| format!("invalid regex `{pattern}`: {e}")),
+                    _ => Err(String::from("`matches` expects a string pattern")),
+                };
+            }
+            _ => return Ok(Node::Exists(left)),
+        };
+        self.next();
+
+        Ok(Node::Compare(left, op, self.parse_operand()?))
+    }
+
+    fn parse_operand(&mut self) -> Result<Operand, String> {
+        match self.next() {
+            Some(Token::Str(s)) => Ok(Operand::Literal(Value::from(s))),
+            Some(Token::Num(n)) => Ok(Operand::Literal(Value::from(n))),
+            Some(Token::Ident(i)) if i == "true" || i == "false" => {
+                Ok(Operand::Literal(Value::Bool(i == "true")))
+            }
+            Some(Token::Ident(i)) if self.peek() == Some(&Token::LParen) => {
+                let function = match i.as_str() {
+                    "age_days" => Function::AgeDays,
+                    "count" => Function::Count,
+                    "lower" => Function::Lower,
+                    "address" => Function::Address,
+                    _ => return Err(format!("unknown function `{i}`")),
+                };
+                self.next();
+                let path = match self.next() {
+                    Some(Token::Ident(root)) => self.parse_path(root)?,
+                    _ => return Err(format!("`{i}` expects a path")),
+                };
+                self.expect(Token::RParen)?;
+                Ok(Operand::Call(function, path))
+            }
+            Some(Token::Ident(root)) => Ok(Operand::Path(self.parse_path(root)?)),
+            Some(token) => Err(format!("unexpected {token:?}")),
+            None => Err(String::from("unexpected end of condition")),
+        }
+    }
+
+    fn parse_path(&mut self, root: String) -> Result<Path, String> {
+        let mut tag = None;
+        if self.peek() == Some(&Token::LBracket) {
+            self.next();
+            tag = match self.next() {
+                Some(Token::Ident(t)) | Some(Token::Str(t)) => Some(t),
+                _ => return Err(format!("invalid tag selector for `{root}`")),
+            };
+            self.expect(Token::RBracket)?;
+        }
+
+        let mut fields = vec![];
+        while self.peek() == Some(&Token::Dot) {
+            self.next();
+            match self.next() {
+                Some(Token::Ident(f)) | Some(Token::Str(f)) => fields.push(f),
+                Some(Token::Num(n)) => fields.push(n.to_string()),
+                _ => return Err(format!("invalid field in path `{root}`")),
+            }
+        }
+
+        if root == "header" && fields.len() != 1 {
+            return Err(String::from("header paths must be of the form `header.<name>`"));
+        }
+
+        Ok(Path { root, tag, fields })
+    }
+}
+
+impl Expression {
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let mut parser = Parser {
+            tokens: tokenize(source)?,
+            position: 0,
+        };
+
+        let root = parser.parse_or()?;
+
+        match parser.next() {
+            None => Ok(Self { root }),
+            Some(token) => Err(format!("unexpected {token:?}")),
+        }
+    }
+
+    pub fn evaluate(&self, context: &EvaluationContext) -> bool {
+        self.root.evaluate(context)
+    }
+}
+
+impl Node {
+    fn evaluate(&self, context: &EvaluationContext) -> bool {
+        match self {
+            Node::Or(a, b) => a.evaluate(context) || b.evaluate(context),
+            Node::And(a, b) => a.evaluate(context) && b.evaluate(context),
+            Node::Not(e) => !e.evaluate(context),
+            Node::Exists(operand) => operand
+                .resolve(context)
+                .iter()
+                .any(|v| !matches!(v, Value::Null | Value::Bool(false))),
+            Node::Matches(operand, regex) => operand
+                .resolve(context)
+                .iter()
+                .filter_map(Value::as_str)
+                .any(|s| regex.is_match(s)),
+            Node::Compare(left, op, right) => {
+                let left = left.resolve(context);
+                let right = right.resolve(context);
+                left.iter()
+                    .any(|l| right.iter().any(|r| compare(l, *op, r)))
+            }
+        }
+    }
+}
+
+impl Operand {
+    fn resolve(&self, context: &EvaluationContext) -> Vec<Value> {
+        match self {
+            Operand::Literal(value) => vec![value.clone()],
+            Operand::Path(path) => path.resolve(context),
+            Operand::Call(Function::Count, path) => vec![Value::from(path.resolve(context).len())],
+            Operand::Call(Function::Lower, path) => path
+                .resolve(context)
+                .iter()
+                .filter_map(Value::as_str)
+                .map(|s| Value::from(s.to_lowercase()))
+                .collect(),
+            Operand::Call(Function::Address, path) => path
+                .resolve(context)
+                .iter()
+                .filter_map(Value::as_str)
+                .flat_map(addresses)
+                .map(Value::from)
+                .collect(),
+            Operand::Call(Function::AgeDays, path) => path
+                .resolve(context)
+                .iter()
+                .filter_map(age_days)
+                .map(Value::from)
+                .collect(),
+        }
+    }
+}
+
+impl Path {
+    fn resolve(&self, context: &EvaluationContext) -> Vec<Value> {
+        if self.root == "header" {
+            return context
+                .headers
+                .iter()
+                .filter(|(name, _)| name.eq_ignore_ascii_case(&self.fields[0]))
+                .map(|(_, value)| Value::from(value.trim()))
+                .collect();
+        }
+
+        let mut values: Vec<&Value> = context
+            .verdicts
+            .iter()
+            .filter(|(kind, _)| kind == &self.root)
+            .map(|(_, value)| value)
+            .filter(|value| match &self.tag {
+                None => true,
+                Some(tag) => value["tags"]
+                    .as_array()
+                    .is_some_and(|tags| tags.iter().any(|t| t == tag)),
+            })
+            .collect();
+
+        for field in &self.fields {
+            values = values
+                .into_iter()
+                .flat_map(|value| match value {
+                    Value::Array(items) => items.iter().filter_map(|i| i.get(field)).collect(),
+                    value => value.get(field).into_iter().collect::<Vec<_>>(),
+                })
+                .collect();
+        }
+
+        values.into_iter().cloned().collect()
+    }
+}
+
+/// Lowercase addresses of an address header value, without their display names
+fn addresses(value: &str) -> Vec<String> {
+    let header = format!("To: {value}\r\n\r\n");
+    let Some(message) = MessageParser::new().parse(header.as_bytes()) else {
+        return vec![];
+    };
+    let addresses = match message.to() {
+        Some(Address::List(list)) => list.iter().collect(),
+        Some(Address::Group(groups)) => groups.iter().flat_map(|g| &g.addresses).collect(),
+        None => vec![],
+    };
+    addresses
+        .into_iter()
+        .filter_map(|a| a.address())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// Number of days elapsed since a unix timestamp or a date
+fn age_days(value: &Value) -> Option<f64> {
+    let date = match value {
+        Value::Number(n) => DateTime::from_timestamp(n.as_f64()? as i64, 0)?,
+        Value::String(s) => DateTime::parse_from_rfc3339(s)
+            .or_else(|_| DateTime::parse_from_rfc2822(s))
+            .ok()?
+            .with_timezone(&Utc),
+        _ => return None,
+    };
+
+    Some((Utc::now() - date).num_seconds() as f64 / 86400.0)
+}
+
+fn compare(left: &Value, op: CompareOp, right: &Value) -> bool {
+    match op {
+        CompareOp::Eq => values_equal(left, right),
+        CompareOp::Ne => !values_equal(left, right),
+        CompareOp::Contains => match (left, right) {
+            (Value::Array(items), right) => items.iter().any(|i| values_equal(i, right)),
+            (Value::String(l), Value::String(r)) => l.to_lowercase().contains(&r.to_lowercase()),
+            _ => false,
+        },
+        CompareOp::Lt | CompareOp::Le | CompareOp::Gt | CompareOp::Ge => {
+            let (Some(l), Some(r)) = (as_number(left), as_number(right)) else {
+                return false;
+            };
+            match op {
+                CompareOp::Lt => l < r,
+                CompareOp::Le => l <= r,
+                CompareOp::Gt => l > r,
+                _ => l >= r,
+            }
+        }
+    }
+}
+
+fn values_equal(left: &Value, right: &Value) -> bool {
+    match (as_number(left), as_number(right)) {
+        (Some(l), Some(r)) => l == r,
+        _ => left == right,
+    }
+}
+
+fn as_number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) => s.parse().ok(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::rules::expression::{EvaluationContext, Expression};
+    use chrono::{TimeDelta, Utc};
+    use rocket::serde::json::json;
+
+    #[test]
+    fn test_evaluate() {
+        let creation_date = (Utc::now() - TimeDelta::days(5)).timestamp();
+
+        let verdicts = vec![
+            (String::from("auth-dmarc"), json!({"dkim": "pass", "spf": "fail"})),
+            (
+                String::from("domain"),
+                json!({"tags": ["sender"], "report": {"data": {"attributes": {"creation_date": creation_date}}}}),
+            ),
+            (
+                String::from("url"),
+                json!({"tags": ["body"], "report": {"data": {"attributes": {"last_analysis_stats": {"malicious": 3}}}}}),
+            ),
+        ];
+        let headers = vec![(String::from("Reply-To"), String::from(" attacker@evil.example"))];
+        let context = EvaluationContext {
+            verdicts: &verdicts,
+            headers: &headers,
+        };
+
+        let holds = |condition: &str| Expression::parse(condition).unwrap().evaluate(&context);
+
+        assert!(holds(
+            r#"auth-dmarc.spf == "fail"
+               and age_days(domain[sender].report.data.attributes.creation_date) < 30
+               and url[body].report.data.attributes.last_analysis_stats.malicious > 2"#
+        ));
+        assert!(!holds(r#"url[sender].report.data.attributes.last_analysis_stats.malicious > 2"#));
+        assert!(holds(r#"header.reply-to contains "EVIL.example" and not auth-dmarc.dkim == "fail""#));
+        assert!(holds(r#"header.Reply-To matches "@evil\\.example$" or count(url) > 5"#));
+        assert!(holds("count(domain) == 1 and auth-dmarc"));
+        assert!(holds(r#"address(header.Reply-To) == "attacker@evil.example""#));
+        assert!(!holds("missing-verdict"));
+    }
+
+    #[test]
+    fn test_syntax_errors() {
+        assert!(Expression::parse(r#"auth-dmarc.spf == "fail" and"#).is_err());
+        assert!(Expression::parse(r#"(auth-dmarc.spf == "fail""#).is_err());
+        assert!(Expression::parse(r#"unknown(url) > 1"#).is_err());
+        assert!(Expression::parse(r#"header == "x""#).is_err());
+        assert!(Expression::parse(r#"address("x") == "x""#).is_err());
+    }
+}
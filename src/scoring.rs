@@ -0,0 +1,321 @@
+use crate::analysis::AnalysisVerdict;
+use crate::rules::{RuleMatch, Severity};
+use rocket::serde::json::Value;
+use serde::{Deserialize, Serialize};
+
+/// Score from which a job is labeled as suspicious
+const SUSPICIOUS_THRESHOLD: f64 = 25.0;
+/// Score from which a job is labeled as malicious
+const MALICIOUS_THRESHOLD: f64 = 60.0;
+const MAX_SCORE: f64 = 100.0;
+
+const DKIM_FAIL_WEIGHT: f64 = 15.0;
+const DKIM_MISSING_WEIGHT: f64 = 5.0;
+const SPF_FAIL_WEIGHT: f64 = 15.0;
+const SPF_SOFTFAIL_WEIGHT: f64 = 8.0;
+const DMARC_FAIL_WEIGHT: f64 = 20.0;
+/// Weight of a link flagged as malicious by at least [`VT_MALICIOUS_ENGINES`] engines
+const VT_MALICIOUS_WEIGHT: f64 = 40.0;
+const VT_MALICIOUS_ENGINES: u64 = 3;
+/// Weight of a link flagged as malicious by less than [`VT_MALICIOUS_ENGINES`] engines
+const VT_FLAGGED_WEIGHT: f64 = 20.0;
+const VT_SUSPICIOUS_WEIGHT: f64 = 10.0;
+const NLP_KEYWORD_WEIGHT: f64 = 10.0;
+const UNKNOWN_ENTITY_WEIGHT: f64 = 5.0;
+/// Maximum contribution of all unknown entities together
+const UNKNOWN_ENTITIES_MAX_WEIGHT: f64 = 15.0;
+
+/// Words of the NLP summary that indicate an attempt to phish the receiver
+const NLP_PHISHING_KEYWORDS: &[&str] = &[
+    "phishing",
+    "password",
+    "credentials",
+    "verify your account",
+    "urgent",
+    "suspicious",
+    "gift card",
+];
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum RiskLabel {
+    Clean,
+    Suspicious,
+    Malicious,
+}
+
+/// A verdict that contributed to the score of a job
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RiskFactor {
+    /// Kind of the verdict this factor comes from
+    pub kind: String,
+    pub description: String,
+    pub weight: f64,
+}
+
+/// The final answer to "is this email phishing ?"
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RiskAssessment {
+    /// Score between 0 (clean) and 100 (malicious)
+    pub score: f64,
+    pub label: RiskLabel,
+    pub factors: Vec<RiskFactor>,
+}
+
+/// Combines all the verdicts of a job into a risk assessment
+pub fn assess<'a>(verdicts: impl IntoIterator<Item = &'a AnalysisVerdict>) -> RiskAssessment {
+    let mut factors = vec![];
+    let mut unknown_entities = 0;
+
+    for verdict in verdicts {
+        match verdict.kind.as_str() {
+            "auth-dkim" => score_dkim(&verdict.value, &mut factors),
+            "auth-spf" => score_spf(&verdict.value, &mut factors),
+            "auth-dmarc" => score_dmarc(&verdict.value, &mut factors),
+            "url" | "domain" => score_link(&verdict.kind, &verdict.value, &mut factors),
+            "nlp-summary" => score_nlp_summary(&verdict.value, &mut factors),
+            "rule-match" => score_rule_match(&verdict.value, &mut factors),
+            "entity-investigation"
+                if verdict.value["is_known_on_internet"] == Value::Bool(false) =>
+            {
+                unknown_entities += 1
+            }
+            _ => {}
+        }
+    }
+
+    if unknown_entities > 0 {
+        factors.push(RiskFactor {
+            kind: String::from("entity-investigation"),
+            description: format!("{unknown_entities} mentioned entities are unknown on the internet"),
+            weight: (unknown_entities as f64 * UNKNOWN_ENTITY_WEIGHT).min(UNKNOWN_ENTITIES_MAX_WEIGHT),
+        })
+    }
+
+    let score = factors
+        .iter()
+        .map(|f| f.weight)
+        .sum::<f64>()
+        .min(MAX_SCORE);
+
+    let label = if score >= MALICIOUS_THRESHOLD {
+        RiskLabel::Malicious
+    } else if score >= SUSPICIOUS_THRESHOLD {
+        RiskLabel::Suspicious
+    } else {
+        RiskLabel::Clean
+    };
+
+    RiskAssessment {
+        score,
+        label,
+        factors,
+    }
+}
+
+fn factor(factors: &mut Vec<RiskFactor>, kind: &str, description: String, weight: f64) {
+    factors.push(RiskFactor {
+        kind: kind.to_string(),
+        description,
+        weight,
+    })
+}
+
+fn score_dkim(value: &Value, factors: &mut Vec<RiskFactor>) {
+    let Some(signatures) = value.as_object() else {
+        return;
+    };
+
+    if signatures.is_empty() {
+        return factor(
+            factors,
+            "auth-dkim",
+            String::from("the email is not DKIM signed"),
+            DKIM_MISSING_WEIGHT,
+        );
+    }
+
+    for (domain, result) in signatures {
+        if result["type"] == "Fail" {
+            factor(
+                factors,
+                "auth-dkim",
+                format!("DKIM signature of {domain} is invalid"),
+                DKIM_FAIL_WEIGHT,
+            )
+        }
+    }
+}
+
+fn score_spf(value: &Value, factors: &mut Vec<RiskFactor>) {
+    let domain = value["domain"].as_str().unwrap_or_default();
+    match value["result"].as_str() {
+        Some("fail") => factor(
+            factors,
+            "auth-spf",
+            format!("the sender is not allowed to send emails for {domain} (SPF fail)"),
+            SPF_FAIL_WEIGHT,
+        ),
+        Some("softfail") => factor(
+            factors,
+            "auth-spf",
+            format!("the sender is probably not allowed to send emails for {domain} (SPF softfail)"),
+            SPF_SOFTFAIL_WEIGHT,
+        ),
+        _ => {}
+    }
+}
+
+fn score_dmarc(value: &Value, factors: &mut Vec<RiskFactor>) {
+    if value["dkim"] == "fail" && value["spf"] == "fail" {
+        factor(
+            factors,
+            "auth-dmarc",
+            String::from("neither DKIM nor SPF are aligned with the sender domain (DMARC fail)"),
+            DMARC_FAIL_WEIGHT,
+        )
+    }
+}
+
+/// Returns the number of VirusTotal engines flagging a link as malicious and as suspicious,
+/// read from its VirusTotal report
+pub fn link_detections(report: &Value) -> (u64, u64) {
+    let stats = &report["data"]["attributes"]["last_analysis_stats"];
+    (
+        stats["malicious"].as_u64().unwrap_or(0),
+        stats["suspicious"].as_u64().unwrap_or(0),
+    )
+}
+
+/// Labels a link by its detections, as weighted in the risk assessment
+pub fn link_label(malicious: u64, suspicious: u64) -> RiskLabel {
+    if malicious >= VT_MALICIOUS_ENGINES {
+        RiskLabel::Malicious
+    } else if malicious > 0 || suspicious > 0 {
+        RiskLabel::Suspicious
+    } else {
+        RiskLabel::Clean
+    }
+}
+
+fn score_link(kind: &str, value: &Value, factors: &mut Vec<RiskFactor>) {
+    let attributes = &value["report"]["data"]["attributes"];
+    let (malicious, suspicious) = link_detections(&value["report"]);
+
+    let link = attributes["url"]
+        .as_str()
+        .or(value["report"]["data"]["id"].as_str())
+        .unwrap_or("<unknown>");
+
+    if malicious >= VT_MALICIOUS_ENGINES {
+        factor(
+            factors,
+            kind,
+            format!("{kind} {link} is flagged as malicious by {malicious} VirusTotal engines"),
+            VT_MALICIOUS_WEIGHT,
+        )
+    } else if malicious > 0 {
+        factor(
+            factors,
+            kind,
+            format!("{kind} {link} is flagged as malicious by {malicious} VirusTotal engines"),
+            VT_FLAGGED_WEIGHT,
+        )
+    } else if suspicious > 0 {
+        factor(
+            factors,
+            kind,
+            format!("{kind} {link} is flagged as suspicious by {suspicious} VirusTotal engines"),
+            VT_SUSPICIOUS_WEIGHT,
+        )
+    }
+}
+
+/// Weight of a matching detection rule, by severity
+fn rule_match_weight(severity: Severity) -> f64 {
+    match severity {
+        Severity::Info => 0.0,
+        Severity::Low => 5.0,
+        Severity::Medium => 15.0,
+        Severity::High => 30.0,
+        Severity::Critical => 60.0,
+    }
+}
+
+fn score_rule_match(value: &Value, factors: &mut Vec<RiskFactor>) {
+    let Ok(rule_match) = serde_json::from_value::<RuleMatch>(value.clone()) else {
+        return;
+    };
+
+    factor(
+        factors,
+        "rule-match",
+        format!("detection rule `{}` matched: {}", rule_match.id, rule_match.description),
+        rule_match_weight(rule_match.severity),
+    )
+}
+
+fn score_nlp_summary(value: &Value, factors: &mut Vec<RiskFactor>) {
+    let summary = value.as_str().unwrap_or_default().to_lowercase();
+
+    let keywords: Vec<_> = NLP_PHISHING_KEYWORDS
+        .iter()
+        .filter(|k| summary.contains(*k))
+        .copied()
+        .collect();
+
+    if !keywords.is_empty() {
+        factor(
+            factors,
+            "nlp-summary",
+            format!("the email summary mentions {}", keywords.join(", ")),
+            NLP_KEYWORD_WEIGHT,
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::analysis::AnalysisVerdict;
+    use crate::scoring::{assess, RiskLabel};
+    use rocket::serde::json::json;
+
+    #[test]
+    fn test_clean_email() {
+        let verdicts = [
+            AnalysisVerdict::new("auth-dkim", json!({"example.com": {"type": "Pass"}})),
+            AnalysisVerdict::new("auth-spf", json!({"domain": "example.com", "result": "pass"})),
+            AnalysisVerdict::new("auth-dmarc", json!({"dkim": "pass", "spf": "pass"})),
+        ];
+
+        let assessment = assess(&verdicts);
+        assert_eq!(assessment.label, RiskLabel::Clean);
+        assert_eq!(assessment.score, 0.0);
+        assert!(assessment.factors.is_empty());
+    }
+
+    #[test]
+    fn test_malicious_email() {
+        let verdicts = [
+            AnalysisVerdict::new("auth-dmarc", json!({"dkim": "fail", "spf": "fail"})),
+            AnalysisVerdict::new(
+                "url",
+                json!({
+                    "tags": ["body"],
+                    "report": {"data": {"attributes": {
+                        "url": "https://evil.example",
+                        "last_analysis_stats": {"malicious": 5, "suspicious": 0}
+                    }}}
+                }),
+            ),
+            AnalysisVerdict::new("nlp-summary", "The sender asks to verify your account password"),
+        ];
+
+        let assessment = assess(&verdicts);
+        assert_eq!(assessment.label, RiskLabel::Malicious);
+        assert_eq!(assessment.score, 70.0);
+        assert_eq!(assessment.factors.len(), 3);
+    }
+}
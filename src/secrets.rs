@@ -0,0 +1,313 @@
+mod env;
+mod file;
+mod vault;
+
+use crate::config::{SecretProviderKind, SecretsConfig};
+use async_trait::async_trait;
+use log::{info, warn};
+use rocket::serde::json::Value;
+use std::collections::HashMap;
+use std::fmt::{Debug, Display, Formatter};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::OnceCell;
+
+pub use env::{EnvProvider, SECRET_ENV_PREFIX};
+pub use file::FileProvider;
+pub use vault::VaultProvider;
+
+/// Name of the VirusTotal API keys
+pub const VIRUSTOTAL: &str = "virustotal";
+
+/// Duration during which a key rejected by its service is not used
+const REJECTED_KEY_COOLDOWN: Duration = Duration::from_secs(60);
+const REDACTED: &str = "[REDACTED]";
+
+pub static SECRETS: OnceCell<SecretStore> = OnceCell::const_new();
+
+/// Creates the secret store with the configured providers.
+/// The secrets are only fetched by [`SecretStore::reload`].
+pub fn init_secrets(config: &SecretsConfig) -> Result<(), SecretError> {
+    let mut providers: Vec<Box<dyn SecretProvider>> = vec![];
+    for kind in &config.providers {
+        providers.push(match kind {
+            SecretProviderKind::Env => Box::new(EnvProvider),
+            SecretProviderKind::File => Box::new(FileProvider::new(config.file.clone())),
+            SecretProviderKind::Vault => {
+                let Some(vault) = &config.vault else {
+                    return Err(SecretError::Provider(String::from(
+                        "the vault provider is enabled but [secrets.vault] is not configured",
+                    )));
+                };
+                Box::new(VaultProvider::new(vault.clone()))
+            }
+        })
+    }
+
+    if SECRETS.set(SecretStore::new(providers)).is_err() {
+        panic!("secrets should not be already initialized")
+    };
+    Ok(())
+}
+
+/// A credential. It can't be printed or serialized, its value must be explicitly exposed.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: impl Into<String>) -> Self {
+        Self(value.into())
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Debug for Secret {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Secret({REDACTED})")
+    }
+}
+
+#[derive(Debug)]
+pub enum SecretError {
+    Std(Box<dyn std::error::Error + Send + Sync>),
+    Provider(String),
+}
+
+impl<E: std::error::Error + Send + Sync + 'static> From<E> for SecretError {
+    fn from(value: E) -> Self {
+        SecretError::Std(Box::new(value))
+    }
+}
+
+impl Display for SecretError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SecretError::Std(err) => write!(f, "{err}"),
+            SecretError::Provider(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+/// A source of secrets
+#[async_trait]
+pub trait SecretProvider: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Returns the keys stored under the given name, or `None` if the provider does not know it
+    async fn fetch(&self, name: &str) -> Result<Option<Vec<Secret>>, SecretError>;
+}
+
+/// The keys of a service. Keys are used in turn, and a key rejected by the service is put
+/// aside for a while so that the next ones are used instead.
+pub struct KeyRing {
+    name: String,
+    keys: RwLock<Vec<Secret>>,
+    next: AtomicUsize,
+    /// Rejected keys, along with the moment they can be used again
+    rejected: Mutex<Vec<(Secret, Instant)>>,
+}
+
+impl KeyRing {
+    pub fn new(name: &str, keys: Vec<Secret>) -> Self {
+        Self {
+            name: name.to_string(),
+            keys: RwLock::new(keys),
+            next: AtomicUsize::new(0),
+            rejected: Mutex::new(vec![]),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the next usable key. If every key has been rejected recently, the next one is
+    /// returned anyway.
+    pub fn key(&self) -> Option<Secret> {
+        let keys = self.keys.read().unwrap();
+        if keys.is_empty() {
+            return None;
+        }
+
+        let now = Instant::now();
+        let mut rejected = self.rejected.lock().unwrap();
+        rejected.retain(|(_, until)| *until > now);
+
+        let start = self.next.fetch_add(1, Ordering::AcqRel);
+        let key = (0..keys.len())
+            .map(|i| &keys[(start + i) % keys.len()])
+            .find(|key| !rejected.iter().any(|(r, _)| r == *key))
+            .unwrap_or(&keys[start % keys.len()]);
+
+        Some(key.clone())
+    }
+
+    /// Marks a key as rejected by its service (revoked, quota exceeded...)
+    pub fn reject(&self, key: &Secret) {
+        warn!("a {} key has been rejected, rotating to the next one", self.name);
+        self.rejected
+            .lock()
+            .unwrap()
+            .push((key.clone(), Instant::now() + REJECTED_KEY_COOLDOWN));
+    }
+
+    fn replace(&self, keys: Vec<Secret>) {
+        *self.keys.write().unwrap() = keys;
+        self.rejected.lock().unwrap().clear();
+    }
+}
+
+pub struct SecretStore {
+    providers: Vec<Box<dyn SecretProvider>>,
+    rings: Mutex<HashMap<String, Arc<KeyRing>>>,
+}
+
+impl SecretStore {
+    pub fn new(providers: Vec<Box<dyn SecretProvider>>) -> Self {
+        Self {
+            providers,
+            rings: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the keys of the given name. They are empty until the store is reloaded.
+    pub fn key_ring(&self, name: &str) -> Arc<KeyRing> {
+        self.rings
+            .lock()
+            .unwrap()
+            .entry(name.to_string())
+            .or_insert_with(|| Arc::new(KeyRing::new(name, vec![])))
+            .clone()
+    }
+
+    /// Fetches again every requested secret. For each secret, the first provider knowing it
+    /// supplies its keys.
+    pub async fn reload(&self) -> Result<(), SecretError> {
+        let rings: Vec<_> = self.rings.lock().unwrap().values().cloned().collect();
+
+        for ring in rings {
+            let mut keys = None;
+            for provider in &self.providers {
+                if let Some(found) = provider.fetch(&ring.name).await? {
+                    info!(
+                        "Loaded {} {} keys from {}",
+                        found.len(),
+                        ring.name,
+                        provider.name()
+                    );
+                    keys = Some(found);
+                    break;
+                }
+            }
+
+            if keys.is_none() {
+                warn!("no provider has {} keys", ring.name)
+            }
+            ring.replace(keys.unwrap_or_default());
+        }
+        Ok(())
+    }
+
+    /// Number of keys of each secret
+    pub fn key_counts(&self) -> HashMap<String, usize> {
+        self.rings
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, ring)| (name.clone(), ring.len()))
+            .collect()
+    }
+
+    fn for_each_key(&self, mut f: impl FnMut(&str)) {
+        for ring in self.rings.lock().unwrap().values() {
+            for key in ring.keys.read().unwrap().iter() {
+                if !key.expose().is_empty() {
+                    f(key.expose())
+                }
+            }
+        }
+    }
+
+    /// Replaces every known key found in the text
+    pub fn redact(&self, text: &str) -> String {
+        let mut text = text.to_string();
+        self.for_each_key(|key| {
+            if text.contains(key) {
+                text = text.replace(key, REDACTED)
+            }
+        });
+        text
+    }
+
+    /// Replaces every known key found in the strings of a JSON value
+    pub fn redact_value(&self, value: &mut Value) {
+        match value {
+            Value::String(s) => *s = self.redact(s),
+            Value::Array(values) => values.iter_mut().for_each(|v| self.redact_value(v)),
+            Value::Object(map) => map.values_mut().for_each(|v| self.redact_value(v)),
+            _ => {}
+        }
+    }
+}
+
+/// Parses a list of keys, given either as a single comma-separated string or as an array
+fn parse_keys(value: &Value) -> Option<Vec<Secret>> {
+    let keys: Vec<_> = match value {
+        Value::String(keys) => keys.split(',').map(str::trim).map(Secret::new).collect(),
+        Value::Array(keys) => keys
+            .iter()
+            .filter_map(Value::as_str)
+            .map(Secret::new)
+            .collect(),
+        _ => return None,
+    };
+    Some(keys.into_iter().filter(|k| !k.expose().is_empty()).collect())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::secrets::{KeyRing, Secret, SecretStore};
+    use rocket::serde::json::json;
+
+    #[test]
+    fn test_key_rotation() {
+        let ring = KeyRing::new("test", vec![Secret::new("a"), Secret::new("b")]);
+
+        assert_eq!(ring.key().unwrap().expose(), "a");
+        assert_eq!(ring.key().unwrap().expose(), "b");
+
+        ring.reject(&Secret::new("a"));
+        assert_eq!(ring.key().unwrap().expose(), "b");
+        assert_eq!(ring.key().unwrap().expose(), "b");
+
+        ring.reject(&Secret::new("b"));
+        assert!(ring.key().is_some());
+
+        assert_eq!(format!("{:?}", Secret::new("a")), "Secret([REDACTED])");
+    }
+
+    #[test]
+    fn test_redaction() {
+        let store = SecretStore::new(vec![]);
+        store
+            .key_ring("test")
+            .replace(vec![Secret::new("s3cr3t")]);
+
+        assert_eq!(store.redact("key=s3cr3t&a=b"), "key=[REDACTED]&a=b");
+
+        let mut value = json!({"url": "https://example.com/?key=s3cr3t", "list": ["s3cr3t"]});
+        store.redact_value(&mut value);
+        assert_eq!(
+            value,
+            json!({"url": "https://example.com/?key=[REDACTED]", "list": ["[REDACTED]"]})
+        );
+    }
+}
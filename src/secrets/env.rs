@@ -0,0 +1,24 @@
+use crate::secrets::{parse_keys, Secret, SecretError, SecretProvider};
+use async_trait::async_trait;
+use rocket::serde::json::Value;
+
+/// Prefix of the environment variables holding secrets, e.g. `MAILANALYZER_SECRET_VIRUSTOTAL`.
+/// Several keys are separated by commas.
+pub const SECRET_ENV_PREFIX: &str = "MAILANALYZER_SECRET_";
+
+/// Reads secrets from environment variables
+pub struct EnvProvider;
+
+#[async_trait]
+impl SecretProvider for EnvProvider {
+    fn name(&self) -> &'static str {
+        "environment"
+    }
+
+    async fn fetch(&self, name: &str) -> Result<Option<Vec<Secret>>, SecretError> {
+        let variable = format!("{SECRET_ENV_PREFIX}{}", name.to_uppercase());
+        Ok(std::env::var(variable)
+            .ok()
+            .and_then(|keys| parse_keys(&Value::String(keys))))
+    }
+}
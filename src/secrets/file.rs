@@ -0,0 +1,79 @@
+use crate::secrets::{parse_keys, Secret, SecretError, SecretProvider};
+use async_trait::async_trait;
+use rocket::serde::json::Value;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// Reads secrets from a TOML file, which must only be readable by the service:
+///
+/// ```toml
+/// virustotal = ["first-key", "second-key"]
+/// splunk = "token"
+/// ```
+pub struct FileProvider {
+    path: PathBuf,
+}
+
+impl FileProvider {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    fn check_permissions(&self) -> Result<(), SecretError> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let mode = std::fs::metadata(&self.path)?.permissions().mode();
+            if mode & 0o077 != 0 {
+                return Err(SecretError::Provider(format!(
+                    "{} must only be accessible by its owner (mode {:o})",
+                    self.path.display(),
+                    mode & 0o777
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl SecretProvider for FileProvider {
+    fn name(&self) -> &'static str {
+        "file"
+    }
+
+    async fn fetch(&self, name: &str) -> Result<Option<Vec<Secret>>, SecretError> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+        self.check_permissions()?;
+
+        let secrets: HashMap<String, Value> = toml::from_str(&std::fs::read_to_string(&self.path)?)?;
+        Ok(secrets.get(name).and_then(parse_keys))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::secrets::{FileProvider, SecretProvider};
+    use rocket::async_test;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[async_test]
+    async fn test_file_permissions() {
+        let path = std::env::temp_dir().join(format!("secrets-{}.toml", std::process::id()));
+        std::fs::write(&path, "virustotal = [\"a\", \"b\"]\n").unwrap();
+        let provider = FileProvider::new(path.clone());
+
+        std::fs::set_permissions(&path, PermissionsExt::from_mode(0o644)).unwrap();
+        assert!(provider.fetch("virustotal").await.is_err());
+
+        std::fs::set_permissions(&path, PermissionsExt::from_mode(0o600)).unwrap();
+        let keys = provider.fetch("virustotal").await.unwrap().unwrap();
+        assert_eq!(keys.iter().map(|k| k.expose()).collect::<Vec<_>>(), ["a", "b"]);
+        assert!(provider.fetch("splunk").await.unwrap().is_none());
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,107 @@
+use crate::config::VaultConfig;
+use crate::secrets::{parse_keys, Secret, SecretError, SecretProvider};
+use async_trait::async_trait;
+use reqwest::{Client, StatusCode};
+use rocket::serde::json::Value;
+
+/// Reads secrets from a KV version 2 engine of a Vault-compatible HTTP server.
+/// Every secret is a field of the configured path, holding a key or a list of keys.
+pub struct VaultProvider {
+    config: VaultConfig,
+    client: Client,
+}
+
+impl VaultProvider {
+    pub fn new(config: VaultConfig) -> Self {
+        Self {
+            config,
+            client: Client::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl SecretProvider for VaultProvider {
+    fn name(&self) -> &'static str {
+        "vault"
+    }
+
+    async fn fetch(&self, name: &str) -> Result<Option<Vec<Secret>>, SecretError> {
+        let token = std::env::var(&self.config.token_env).map_err(|_| {
+            SecretError::Provider(format!("{} is not set", self.config.token_env))
+        })?;
+
+        let url = self.config.url.join(&format!(
+            "v1/{}/data/{}",
+            self.config.mount, self.config.path
+        ))?;
+
+        let response = self
+            .client
+            .get(url)
+            .header("X-Vault-Token", token)
+            .send()
+            .await?;
+
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if !response.status().is_success() {
+            return Err(SecretError::Provider(format!(
+                "vault responded with status {}",
+                response.status()
+            )));
+        }
+
+        let body: Value = response.json().await?;
+        Ok(body
+            .pointer(&format!("/data/data/{name}"))
+            .and_then(parse_keys))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::VaultConfig;
+    use crate::secrets::{SecretProvider, VaultProvider};
+    use rocket::async_test;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use url::Url;
+
+    #[async_test]
+    async fn test_vault() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        // a stand-in answering a single KV request
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = vec![0; 4096];
+            let len = socket.read(&mut request).await.unwrap();
+            let request = String::from_utf8_lossy(&request[..len]).to_lowercase();
+
+            let body = r#"{"data": {"data": {"virustotal": ["a", "b"]}}}"#;
+            let response = format!(
+                "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            socket.write_all(response.as_bytes()).await.unwrap();
+            request
+        });
+
+        std::env::set_var("MAILANALYZER_TEST_VAULT_TOKEN", "token");
+        let provider = VaultProvider::new(VaultConfig {
+            url: Url::parse(&format!("http://{address}/")).unwrap(),
+            token_env: String::from("MAILANALYZER_TEST_VAULT_TOKEN"),
+            ..VaultConfig::default()
+        });
+
+        let keys = provider.fetch("virustotal").await.unwrap().unwrap();
+        assert_eq!(keys.iter().map(|k| k.expose()).collect::<Vec<_>>(), ["a", "b"]);
+
+        let request = server.await.unwrap();
+        assert!(request.starts_with("get /v1/secret/data/mailanalyzer "));
+        assert!(request.contains("x-vault-token: token"));
+    }
+}
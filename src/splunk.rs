@@ -1,8 +1,10 @@
 mod web_client;
 mod job;
 
+use crate::http::HttpClient;
 use crate::splunk::job::{Job, JobDescription};
 use reqwest::RequestBuilder;
+use serde::Deserialize;
 use std::sync::Arc;
 use url::Url;
 
@@ -26,7 +28,7 @@ impl<E: std::error::Error + 'static> From<E> for SplunkError {
 
 impl<C: SplunkClient> Splunk<C> {
     async fn job(&self, jq: JobDescription) -> Result<Job<C>, SplunkError> {
-        let response = self.client
+        let request = self.client
             .post("/jobs")
             .form(&vec![
                 ("adhoc_search_level", jq.level.name().to_string()),
@@ -34,9 +36,8 @@ impl<C: SplunkClient> Splunk<C> {
                 ("latest_time", jq.time_range.end.timestamp().to_string()),
                 ("search", jq.search),
                 ("output_mode", "json".to_string()),
-            ])
-            .send()
-            .await?;
+            ]);
+        let response = self.client.http().send(request).await?;
 
         if !response.status().is_success() {
             return Err(SplunkError::Message(format!("Received response status {} : {}", response.status(), response.text().await.unwrap_or("<no content>".to_string()))));
@@ -65,12 +66,22 @@ impl<C: SplunkClient> Splunk<C> {
 pub trait SplunkClient {
     fn post(&self, url: &str) -> RequestBuilder;
     fn get(&self, url: &str) -> RequestBuilder;
-    
+    /// Client sending the built requests
+    fn http(&self) -> &HttpClient;
 }
 
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct SplunkClientConfig {
     endpoint: Url,
     portal: Url,
+    /// WebDriver used to authenticate on the web portal
+    #[serde(default = "default_webdriver")]
+    webdriver: Url,
+}
+
+fn default_webdriver() -> Url {
+    Url::parse("http://localhost:4444").unwrap()
 }
 
 
@@ -84,9 +95,10 @@ impl<C: SplunkClient> From<C> for Splunk<C> {
 
 #[cfg(test)]
 mod test {
+    use crate::config::HttpConfig;
     use crate::splunk::job::{JobDescription, SearchLevel};
     use crate::splunk::web_client::WebClient;
-    use crate::splunk::{Splunk, SplunkClientConfig};
+    use crate::splunk::{default_webdriver, Splunk, SplunkClientConfig};
     use chrono::{DateTime, TimeDelta};
     use rocket::async_test;
     use std::ops::Sub;
@@ -98,7 +110,8 @@ mod test {
         let client = WebClient::new_via_web_portal(SplunkClientConfig {
             portal: Url::parse("https://soc-siem.eu.airbus.corp:8000/").unwrap(),
             endpoint: Url::parse("https://soc-siem.eu.airbus.corp:8000/en-US/splunkd/__raw/servicesNS/mbat3wm0/SplunkEnterpriseSecuritySuite/search/v2").unwrap(),
-        }).await.unwrap();
+            webdriver: default_webdriver(),
+        }, &HttpConfig::default()).await.unwrap();
         let splunk = Splunk::from(client);
 
         let job = splunk.job(JobDescription {
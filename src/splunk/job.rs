@@ -50,11 +50,11 @@ impl<C: SplunkClient> Job<C> {
                 let progress = self.get_job_progress().await?;
                 
                 if progress.event_count != event_count {
-                    event_count = progress.event_count;
-                    
-                    for event in self.poll_job_events().await? {
+                    for event in self.poll_job_events(event_count).await? {
                         yield Ok(event)
                     }
+
+                    event_count = progress.event_count;
                 }
                 
                 if progress.is_complete {
@@ -66,20 +66,34 @@ impl<C: SplunkClient> Job<C> {
         Ok(stream)
     }
 
+    /// Fetches the events of the job that follow the first `offset` ones
     async fn poll_job_events(&self, offset: u32) -> Result<Vec<Event>, SplunkError> {
-        let response = self.client
-            .get(&format!("/jobs/{}/events?output_mode=json&offset={offset}&count=0", self.sid))
-            .send()
-            .await?;
-        
+        let request = self.client
+            .get(&format!("/jobs/{}/events?output_mode=json&offset={offset}&count=0", self.sid));
+        let response = self.client.http().send(request).await?;
+
         let json: Value = response.json().await?;
+
+        let events = json
+            .get("results")
+            .and_then(Value::as_array)
+            .ok_or_else(|| SplunkError::Message(format!("Received unexpected events: {json}")))?
+            .iter()
+            .map(|result| Event {
+                value: result
+                    .get("_raw")
+                    .and_then(Value::as_str)
+                    .map_or_else(|| result.to_string(), ToOwned::to_owned),
+            })
+            .collect();
+
+        Ok(events)
     }
     
     async fn get_job_progress(&self) -> Result<JobProgress, SplunkError> {
-        let response = self.client
-            .get(&format!("/{}?output_mode=json", self.sid))
-            .send()
-            .await?;
+        let request = self.client
+            .get(&format!("/jobs/{}?output_mode=json", self.sid));
+        let response = self.client.http().send(request).await?;
 
 
         #[derive(Deserialize)]
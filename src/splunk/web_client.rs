@@ -1,7 +1,9 @@
+use crate::config::HttpConfig;
+use crate::http::HttpClient;
 use crate::splunk::{SplunkClient, SplunkClientConfig};
 use reqwest::cookie::{CookieStore, Jar};
 use reqwest::ClientBuilder;
-use reqwest::{Client, RequestBuilder};
+use reqwest::RequestBuilder;
 use std::sync::Arc;
 use std::time::Duration;
 use thirtyfour::{ChromiumLikeCapabilities, Cookie, DesiredCapabilities, SameSite, WebDriver};
@@ -9,17 +11,17 @@ use url::Url;
 
 pub struct WebClient {
     config: SplunkClientConfig,
-    client: Client,
+    http: HttpClient,
     cookie_jar: Arc<Jar>,
 }
 
 impl WebClient {
-    pub async fn new_via_web_portal(config: SplunkClientConfig) -> Result<Self, Box<dyn std::error::Error>> {
+    pub async fn new_via_web_portal(config: SplunkClientConfig, http: &HttpConfig) -> Result<Self, Box<dyn std::error::Error>> {
         let mut caps = DesiredCapabilities::chrome();
         caps.add_arg("--lang=en")?;
         caps.add_arg("--ignore-ssl-errors=yes")?;
         caps.add_arg("--ignore-ignore-certificate-errors")?;
-        let driver = WebDriver::new("http://localhost:4444", caps)
+        let driver = WebDriver::new(config.webdriver.as_str(), caps)
             .await
             .unwrap();
 
@@ -52,7 +54,7 @@ impl WebClient {
 
                 return Ok(Self {
                     config,
-                    client,
+                    http: HttpClient::with_client(http, client),
                     cookie_jar,
                 });
             }
@@ -87,11 +89,15 @@ impl WebClient {
 
 impl SplunkClient for WebClient {
     fn post(&self, url: &str) -> RequestBuilder {
-        self.decorate(self.client.post(format!("{}{}", self.config.endpoint, url)))
+        self.decorate(self.http.post(format!("{}{}", self.config.endpoint, url)))
     }
 
     fn get(&self, url: &str) -> RequestBuilder {
-        self.decorate(self.client.get(format!("{}{}", self.config.endpoint, url)))
+        self.decorate(self.http.get(format!("{}{}", self.config.endpoint, url)))
+    }
+
+    fn http(&self) -> &HttpClient {
+        &self.http
     }
 }
 
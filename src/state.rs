@@ -1,59 +1,283 @@
+use crate::analysis::{AnalysisOptions, AnalysisTimeouts};
+use crate::batch::{Batch, BatchJob, BatchLimits, RejectedMessage};
+use crate::dedup::{fingerprint, DedupPolicy, Submission};
+use crate::email::SubmittedEmail;
 use crate::JobDescription;
+use chrono::Utc;
 use std::sync::Arc;
+use std::time::Duration;
+use log::{error, info};
 use tokio::sync::broadcast::{Receiver, Sender};
 use tokio::sync::Mutex;
-use crate::job::Job;
+use crate::job::{Job, JobState};
+use crate::recheck::VerdictChange;
+use crate::storage::{JobStorage, StorageError};
 
 pub struct ServerState {
     pub(crate) jobs: Arc<Mutex<Jobs>>,
+    pub(crate) timeouts: AnalysisTimeouts,
+    pub(crate) batch: BatchLimits,
 }
 
 pub struct Jobs {
     //TODO Arc here might be removable
     jobs: Vec<Arc<Job>>,
     total_jobs_count: usize,
+    batches: Vec<Arc<Batch>>,
+    total_batches_count: usize,
     event_channel: Sender<ServerStateEvent>,
+    storage: Box<dyn JobStorage>,
+    retention: RetentionPolicy,
+    dedup: DedupPolicy,
+}
+
+/// Rules deciding when complete jobs, along with their emails, are deleted.
+/// Jobs that are still being analyzed are never evicted.
+#[derive(Debug, Clone, Default)]
+pub struct RetentionPolicy {
+    /// Age after which a job is deleted
+    pub max_age: Option<Duration>,
+    /// Maximum number of jobs to keep, the oldest ones are deleted first
+    pub max_count: Option<usize>,
+}
+
+/// Outcome of [`Jobs::add_job`]
+pub enum JobAddition {
+    /// A new job, to analyze
+    Created(Arc<Job>),
+    /// The email was already submitted as this job
+    Existing(Arc<Job>),
+    /// A new job taking the results of the job the email was already submitted as
+    Linked { job: Arc<Job>, original: Arc<Job> },
+}
+
+impl JobAddition {
+    /// The job the email was submitted as
+    pub fn job(&self) -> &Arc<Job> {
+        match self {
+            JobAddition::Created(job)
+            | JobAddition::Existing(job)
+            | JobAddition::Linked { job, .. } => job,
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
 #[non_exhaustive]
 pub enum ServerStateEvent {
-    NewJob(JobDescription),
+    NewJob(Box<JobDescription>),
+    JobDeleted(usize),
+    VerdictChanged(VerdictChange),
 }
 
 impl Jobs {
-    pub fn new() -> Self {
-        Self {
-            jobs: vec![],
-            total_jobs_count: 0,
+    /// Creates the job list, restoring every job and batch previously saved in the given storage.
+    pub fn load(
+        storage: Box<dyn JobStorage>,
+        retention: RetentionPolicy,
+    ) -> Result<Self, StorageError> {
+        let jobs: Vec<_> = storage
+            .load_jobs()?
+            .into_iter()
+            .map(|stored| Arc::new(Job::restore(stored)))
+            .collect();
+        let batches: Vec<_> = storage.load_batches()?.into_iter().map(Arc::new).collect();
+        // ids of deleted jobs and batches are not given again, they may still be referenced
+        let last_job_id = storage.last_job_id()?;
+        let last_batch_id = storage.last_batch_id()?;
+
+        Ok(Self {
+            total_jobs_count: jobs.iter().map(|j| j.id).fold(last_job_id, usize::max),
+            jobs,
+            total_batches_count: batches.iter().map(|b| b.id).fold(last_batch_id, usize::max),
+            batches,
             event_channel: tokio::sync::broadcast::channel::<ServerStateEvent>(100).0,
-        }
+            storage,
+            retention,
+            dedup: DedupPolicy::Off,
+        })
+    }
+
+    pub fn with_dedup_policy(self, dedup: DedupPolicy) -> Self {
+        Self { dedup, ..self }
     }
 
     pub fn iter_jobs(&self) -> impl Iterator<Item = &Arc<Job>> {
         self.jobs.iter()
     }
 
-    pub async fn add_job(&mut self, email_content: String) -> Arc<Job> {
+    /// Adds a job for the given email. When the email was already submitted, the deduplication
+    /// policy decides whether a job is created, and the submission is recorded by the original job.
+    pub fn add_job(&mut self, email: SubmittedEmail) -> JobAddition {
+        let fingerprint = fingerprint(&email.email);
+        let original = match (self.dedup, &fingerprint) {
+            (DedupPolicy::Off, _) | (_, None) => None,
+            (_, Some(fingerprint)) => self.find_original(fingerprint, &email.options),
+        };
+
+        if let (DedupPolicy::Existing, Some(original)) = (self.dedup, &original) {
+            let submission = Submission::new(original.id, &email);
+            original.submissions.lock().unwrap().push(submission);
+            self.save_job(original);
+            return JobAddition::Existing(original.clone());
+        }
+
         self.total_jobs_count += 1;
 
         let job_id = self.total_jobs_count;
+        let submission = Submission::new(job_id, &email);
 
-        let (sx, _) = tokio::sync::broadcast::channel(100);
-
-        let job = Arc::new(Job::new(email_content, job_id, sx));
+        let mut job = Job::new(email.email, job_id);
+        job.original = email.original;
+        job.report = email.report;
+        job.source = email.source;
+        job.options = email.options;
+        job.fingerprint = fingerprint;
+        job.duplicate_of = original.as_ref().map(|o| o.id);
+        job.submissions.get_mut().unwrap().push(submission.clone());
+        let job = Arc::new(job);
 
         self.jobs.push(job.clone());
-        
-        self.event_channel
-            .send(ServerStateEvent::NewJob(JobDescription::from_job(&job).await))
-            .expect("could not send job add event");
 
-        job
+        self.save_job(&job);
+
+        // nobody may be listening, which is fine
+        let _ = self
+            .event_channel
+            .send(ServerStateEvent::NewJob(Box::new(JobDescription::from_job(&job))));
+
+        self.apply_retention();
+
+        match original {
+            Some(original) => {
+                original.submissions.lock().unwrap().push(submission);
+                self.save_job(&original);
+                JobAddition::Linked { job, original }
+            }
+            None => JobAddition::Created(job),
+        }
+    }
+
+    /// Returns the job first created for the given fingerprint and analysis options, unless its
+    /// analysis did not succeed
+    fn find_original(&self, fingerprint: &str, options: &AnalysisOptions) -> Option<Arc<Job>> {
+        self.jobs
+            .iter()
+            .filter(|j| j.duplicate_of.is_none() && j.fingerprint.as_deref() == Some(fingerprint))
+            .filter(|j| j.options == *options)
+            .find(|j| {
+                !j.is_cancelled() && !matches!(*j.state.lock().unwrap(), JobState::Error(_))
+            })
+            .cloned()
+    }
+
+    /// Persists the current state of the given job, unless it has been deleted
+    pub fn save_job(&self, job: &Job) {
+        if self.find_job(job.id).is_none() {
+            return;
+        }
+        if let Err(err) = self.storage.save_job(&job.to_stored()) {
+            error!("could not save job {}: {err}", job.id)
+        }
     }
 
-    pub fn complete_job(&mut self, job_id: usize) {
-        self.jobs.retain(|j| j.id != job_id)
+    /// Sends an event to the subscribers of the server events
+    pub fn notify(&self, event: ServerStateEvent) {
+        let _ = self.event_channel.send(event);
+    }
+
+    /// Deletes a job and its stored email, cancelling its analysis if it is still running
+    pub fn remove_job(&mut self, job_id: usize) -> Option<Arc<Job>> {
+        let index = self.jobs.iter().position(|j| j.id == job_id)?;
+        let job = self.jobs.remove(index);
+
+        job.cancel();
+
+        if let Err(err) = self.storage.delete_job(job_id) {
+            error!("could not delete job {job_id}: {err}")
+        }
+
+        let _ = self.event_channel.send(ServerStateEvent::JobDeleted(job_id));
+
+        self.remove_emptied_batches(job_id);
+
+        Some(job)
+    }
+
+    /// Groups jobs created from the same archive, see [`crate::batch`]
+    pub fn add_batch(&mut self, jobs: Vec<BatchJob>, rejected: Vec<RejectedMessage>) -> Arc<Batch> {
+        self.total_batches_count += 1;
+
+        let batch = Arc::new(Batch {
+            id: self.total_batches_count,
+            created_at: Utc::now(),
+            jobs,
+            rejected,
+        });
+
+        if let Err(err) = self.storage.save_batch(&batch) {
+            error!("could not save batch {}: {err}", batch.id)
+        }
+
+        self.batches.push(batch.clone());
+
+        batch
+    }
+
+    pub fn find_batch(&self, batch_id: usize) -> Option<Arc<Batch>> {
+        self.batches.iter().find(|b| b.id == batch_id).cloned()
+    }
+
+    /// Deletes the batches of the given deleted job that no longer have any job
+    fn remove_emptied_batches(&mut self, job_id: usize) {
+        let emptied: Vec<usize> = self
+            .batches
+            .iter()
+            .filter(|b| b.jobs.iter().any(|j| j.job_id == job_id))
+            .filter(|b| b.jobs.iter().all(|j| self.find_job(j.job_id).is_none()))
+            .map(|b| b.id)
+            .collect();
+
+        for batch_id in emptied {
+            self.batches.retain(|b| b.id != batch_id);
+            if let Err(err) = self.storage.delete_batch(batch_id) {
+                error!("could not delete batch {batch_id}: {err}")
+            }
+        }
+    }
+
+    /// Deletes the complete jobs that are no longer retained by the retention policy
+    pub fn apply_retention(&mut self) {
+        let now = Utc::now();
+
+        let mut expired: Vec<usize> = match self.retention.max_age {
+            Some(max_age) => self
+                .jobs
+                .iter()
+                .filter(|j| j.is_complete())
+                .filter(|j| (now - j.created_at).to_std().is_ok_and(|age| age > max_age))
+                .map(|j| j.id)
+                .collect(),
+            None => vec![],
+        };
+
+        if let Some(max_count) = self.retention.max_count {
+            let excess = (self.jobs.len() - expired.len()).saturating_sub(max_count);
+            // jobs are kept in creation order
+            expired.extend(
+                self.jobs
+                    .iter()
+                    .filter(|j| j.is_complete() && !expired.contains(&j.id))
+                    .take(excess)
+                    .map(|j| j.id)
+                    .collect::<Vec<_>>(),
+            );
+        }
+
+        for job_id in expired {
+            info!("Evicting job {job_id}");
+            self.remove_job(job_id);
+        }
     }
 
     pub fn find_job(&self, job_id: usize) -> Option<Arc<Job>> {
@@ -64,3 +288,103 @@ impl Jobs {
         self.event_channel.subscribe()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::dedup::DedupPolicy;
+    use crate::email::{read_email, SubmittedEmail};
+    use crate::ingestion::{JobSource, SmtpEnvelope};
+    use crate::job::JobState;
+    use crate::state::{JobAddition, Jobs, RetentionPolicy};
+    use crate::storage::{JobStorage, SqliteStorage};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_retention() {
+        let storage = Arc::new(SqliteStorage::open(":memory:").unwrap());
+        let retention = RetentionPolicy {
+            max_age: None,
+            max_count: Some(2),
+        };
+        let mut jobs = Jobs::load(Box::new(storage.clone()), retention).unwrap();
+
+        let first = jobs.add_job(SubmittedEmail::new(b"Subject: 1\r\n\r\n".to_vec()));
+        first.job().mark_as_complete();
+        jobs.add_job(SubmittedEmail::new(b"Subject: 2\r\n\r\n".to_vec()));
+        // the first job is complete and can be evicted
+        jobs.add_job(SubmittedEmail::new(b"Subject: 3\r\n\r\n".to_vec()));
+        // the remaining jobs are still being analyzed and are kept
+        jobs.add_job(SubmittedEmail::new(b"Subject: 4\r\n\r\n".to_vec()));
+
+        let ids: Vec<_> = jobs.iter_jobs().map(|j| j.id).collect();
+        assert_eq!(ids, vec![2, 3, 4]);
+
+        let stored: Vec<_> = storage.load_jobs().unwrap().iter().map(|j| j.id).collect();
+        assert_eq!(stored, vec![2, 3, 4]);
+
+        // the id of a deleted job is not given again after a restart
+        jobs.remove_job(4);
+        let mut jobs = Jobs::load(Box::new(storage), RetentionPolicy::default()).unwrap();
+        let added = jobs.add_job(SubmittedEmail::new(b"Subject: 5\r\n\r\n".to_vec()));
+        assert_eq!(added.job().id, 5);
+    }
+
+    #[test]
+    fn test_deduplication() {
+        let reported_by = |reporter: &str| {
+            let report = format!(
+                "From: {reporter}\r\nSubject: Fwd: hi\r\n\
+                 Content-Type: multipart/mixed; boundary=b\r\n\r\n\
+                 --b\r\nContent-Type: message/rfc822\r\n\r\n\
+                 Message-ID: <1@example.org>\r\n\r\nlog in\r\n--b--\r\n"
+            );
+            read_email(report.into_bytes(), true).unwrap()
+        };
+        let storage = SqliteStorage::open(":memory:").unwrap();
+
+        let mut jobs = Jobs::load(Box::new(storage), RetentionPolicy::default())
+            .unwrap()
+            .with_dedup_policy(DedupPolicy::Linked);
+        let JobAddition::Created(original) = jobs.add_job(reported_by("alice@example.org")) else {
+            panic!("the first submission is analyzed");
+        };
+        let JobAddition::Linked { job, .. } = jobs.add_job(reported_by("bob@example.org")) else {
+            panic!("the second submission is linked");
+        };
+        assert_eq!(job.duplicate_of, Some(original.id));
+        let submissions = original.submissions.lock().unwrap().clone();
+        let reporters: Vec<_> = submissions.iter().map(|s| s.reporter.as_deref()).collect();
+        assert_eq!(reporters, [Some("alice@example.org"), Some("bob@example.org")]);
+        assert_eq!(submissions[1].job_id, job.id);
+
+        // a failed analysis is not reused
+        *original.state.lock().unwrap() = JobState::Error(String::from("every analyzer failed"));
+        let mut jobs = jobs.with_dedup_policy(DedupPolicy::Existing);
+        assert!(matches!(
+            jobs.add_job(reported_by("carol@example.org")),
+            JobAddition::Created(_)
+        ));
+        let JobAddition::Existing(existing) = jobs.add_job(reported_by("dave@example.org")) else {
+            panic!("the existing job is returned");
+        };
+        assert_eq!(existing.id, 3);
+        assert_eq!(existing.submissions.lock().unwrap().len(), 2);
+        assert_eq!(jobs.iter_jobs().count(), 3);
+
+        // the sender of a journaled copy is not a reporter
+        let journaled = SubmittedEmail {
+            source: Some(JobSource::Smtp(SmtpEnvelope {
+                mail_from: String::from("journal@example.org"),
+                rcpt_to: vec![String::from("phishing@example.org")],
+                client_ip: [127, 0, 0, 1].into(),
+                helo: String::from("localhost"),
+            })),
+            ..SubmittedEmail::new(b"Message-ID: <1@example.org>\r\n\r\nlog in".to_vec())
+        };
+        let JobAddition::Existing(existing) = jobs.add_job(journaled) else {
+            panic!("the existing job is returned");
+        };
+        let submissions = existing.submissions.lock().unwrap().clone();
+        assert_eq!(submissions.last().unwrap().reporter, None);
+    }
+}
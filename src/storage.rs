@@ -0,0 +1,116 @@
+mod sqlite;
+
+use crate::analysis::{AnalysisError, AnalysisOptions, AnalysisResult};
+use crate::batch::Batch;
+use crate::dedup::Submission;
+use crate::email::{OriginalFile, Report};
+use crate::ingestion::JobSource;
+use crate::job::{AnalyzerStatus, JobState};
+use crate::recheck::Recheck;
+use crate::scoring::RiskAssessment;
+use chrono::{DateTime, Utc};
+use std::fmt::{Display, Formatter};
+
+pub use sqlite::SqliteStorage;
+
+/// Persisted form of a [`crate::job::Job`].
+#[derive(Debug, Clone)]
+pub struct StoredJob {
+    pub id: usize,
+    pub email: Vec<u8>,
+    pub original: Option<OriginalFile>,
+    pub report: Option<Report>,
+    pub source: Option<JobSource>,
+    pub options: AnalysisOptions,
+    pub fingerprint: Option<String>,
+    pub duplicate_of: Option<usize>,
+    pub submissions: Vec<Submission>,
+    pub created_at: DateTime<Utc>,
+    pub state: JobState,
+    pub results: Vec<AnalysisResult>,
+    pub failures: Vec<AnalysisError>,
+    pub analyzers: Vec<AnalyzerStatus>,
+    pub risk: Option<RiskAssessment>,
+    pub rechecks: Vec<Recheck>,
+    pub expected_result_count: i32,
+}
+
+#[derive(Debug)]
+pub enum StorageError {
+    Std(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl<E: std::error::Error + Send + Sync + 'static> From<E> for StorageError {
+    fn from(value: E) -> Self {
+        StorageError::Std(Box::new(value))
+    }
+}
+
+impl Display for StorageError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StorageError::Std(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+/// A backend able to persist jobs so that they survive server restarts.
+pub trait JobStorage: Send + Sync {
+    /// Inserts or replaces the given job
+    fn save_job(&self, job: &StoredJob) -> Result<(), StorageError>;
+
+    /// Returns every stored job, ordered by id
+    fn load_jobs(&self) -> Result<Vec<StoredJob>, StorageError>;
+
+    /// Deletes the given job, does nothing if it does not exist
+    fn delete_job(&self, job_id: usize) -> Result<(), StorageError>;
+
+    /// Returns the highest job id ever saved, including deleted jobs
+    fn last_job_id(&self) -> Result<usize, StorageError>;
+
+    /// Inserts or replaces the given batch
+    fn save_batch(&self, batch: &Batch) -> Result<(), StorageError>;
+
+    /// Returns every stored batch, ordered by id
+    fn load_batches(&self) -> Result<Vec<Batch>, StorageError>;
+
+    /// Deletes the given batch, its jobs are left untouched
+    fn delete_batch(&self, batch_id: usize) -> Result<(), StorageError>;
+
+    /// Returns the highest batch id ever saved, including deleted batches
+    fn last_batch_id(&self) -> Result<usize, StorageError>;
+}
+
+impl<S: JobStorage + ?Sized> JobStorage for std::sync::Arc<S> {
+    fn save_job(&self, job: &StoredJob) -> Result<(), StorageError> {
+        (**self).save_job(job)
+    }
+
+    fn load_jobs(&self) -> Result<Vec<StoredJob>, StorageError> {
+        (**self).load_jobs()
+    }
+
+    fn delete_job(&self, job_id: usize) -> Result<(), StorageError> {
+        (**self).delete_job(job_id)
+    }
+
+    fn last_job_id(&self) -> Result<usize, StorageError> {
+        (**self).last_job_id()
+    }
+
+    fn save_batch(&self, batch: &Batch) -> Result<(), StorageError> {
+        (**self).save_batch(batch)
+    }
+
+    fn load_batches(&self) -> Result<Vec<Batch>, StorageError> {
+        (**self).load_batches()
+    }
+
+    fn delete_batch(&self, batch_id: usize) -> Result<(), StorageError> {
+        (**self).delete_batch(batch_id)
+    }
+
+    fn last_batch_id(&self) -> Result<usize, StorageError> {
+        (**self).last_batch_id()
+    }
+}
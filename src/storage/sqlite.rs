@@ -0,0 +1,418 @@
+use crate::batch::Batch;
+use crate::email::OriginalFile;
+use crate::storage::{JobStorage, StorageError, StoredJob};
+use chrono::DateTime;
+use rusqlite::{params, Connection};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Schema migrations, applied in order. The index of the last applied migration is kept in
+/// sqlite's `user_version` pragma.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE jobs (
+        id INTEGER PRIMARY KEY,
+        email TEXT NOT NULL,
+        state TEXT NOT NULL,
+        results TEXT NOT NULL,
+        expected_result_count INTEGER NOT NULL
+    )",
+    "ALTER TABLE jobs ADD COLUMN failures TEXT NOT NULL DEFAULT '[]'",
+    "ALTER TABLE jobs ADD COLUMN analyzers TEXT NOT NULL DEFAULT '[]'",
+    "ALTER TABLE jobs ADD COLUMN risk TEXT",
+    "ALTER TABLE jobs ADD COLUMN created_at INTEGER NOT NULL DEFAULT 0;
+     UPDATE jobs SET created_at = unixepoch()",
+    // highest ids ever saved, which are not given again once their job is deleted
+    "CREATE TABLE last_ids (name TEXT PRIMARY KEY, id INTEGER NOT NULL);
+     INSERT INTO last_ids SELECT 'jobs', IFNULL(MAX(id), 0) FROM jobs",
+    "ALTER TABLE jobs ADD COLUMN original_format TEXT;
+     ALTER TABLE jobs ADD COLUMN original BLOB",
+    "ALTER TABLE jobs ADD COLUMN report TEXT",
+    // emails are raw bytes, which are not always valid UTF-8
+    "UPDATE jobs SET email = CAST(email AS BLOB)",
+    "CREATE TABLE batches (
+        id INTEGER PRIMARY KEY,
+        created_at INTEGER NOT NULL,
+        jobs TEXT NOT NULL,
+        rejected TEXT NOT NULL
+    );
+     INSERT INTO last_ids VALUES ('batches', 0)",
+    "ALTER TABLE jobs ADD COLUMN source TEXT",
+    "ALTER TABLE jobs ADD COLUMN fingerprint TEXT;
+     ALTER TABLE jobs ADD COLUMN duplicate_of INTEGER;
+     ALTER TABLE jobs ADD COLUMN submissions TEXT NOT NULL DEFAULT '[]'",
+    "ALTER TABLE jobs ADD COLUMN options TEXT NOT NULL DEFAULT '{}'",
+    "ALTER TABLE jobs ADD COLUMN rechecks TEXT NOT NULL DEFAULT '[]'",
+];
+
+pub struct SqliteStorage {
+    connection: Mutex<Connection>,
+}
+
+impl SqliteStorage {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, StorageError> {
+        let mut connection = Connection::open(path)?;
+        migrate(&mut connection, MIGRATIONS)?;
+
+        Ok(Self {
+            connection: Mutex::new(connection),
+        })
+    }
+}
+
+/// Applies the migrations following the current `user_version`. Each migration is committed
+/// along with its version, so that a failed one is retried as a whole on the next start.
+fn migrate(connection: &mut Connection, migrations: &[&str]) -> Result<(), StorageError> {
+    let version: usize = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+
+    for (i, migration) in migrations.iter().enumerate().skip(version) {
+        let transaction = connection.transaction()?;
+        transaction.execute_batch(migration)?;
+        transaction.pragma_update(None, "user_version", i + 1)?;
+        transaction.commit()?;
+    }
+
+    Ok(())
+}
+
+fn update_last_id(connection: &Connection, name: &str, id: usize) -> Result<(), StorageError> {
+    connection.execute(
+        "INSERT INTO last_ids (name, id) VALUES (?1, ?2)
+         ON CONFLICT(name) DO UPDATE SET id = MAX(id, excluded.id)",
+        params![name, id],
+    )?;
+    Ok(())
+}
+
+fn last_id(connection: &Connection, name: &str) -> Result<usize, StorageError> {
+    let id = connection.query_row("SELECT id FROM last_ids WHERE name = ?1", [name], |row| {
+        row.get(0)
+    })?;
+    Ok(id)
+}
+
+impl JobStorage for SqliteStorage {
+    fn save_job(&self, job: &StoredJob) -> Result<(), StorageError> {
+        let connection = self.connection.lock().unwrap();
+
+        connection.execute(
+            "INSERT INTO jobs (id, email, state, results, expected_result_count, failures, analyzers, risk, created_at, original_format, original, report, source, fingerprint, duplicate_of, submissions, options, rechecks)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18)
+             ON CONFLICT(id) DO UPDATE SET
+                state = excluded.state,
+                results = excluded.results,
+                expected_result_count = excluded.expected_result_count,
+                failures = excluded.failures,
+                analyzers = excluded.analyzers,
+                risk = excluded.risk,
+                submissions = excluded.submissions,
+                rechecks = excluded.rechecks",
+            params![
+                job.id,
+                job.email,
+                serde_json::to_string(&job.state)?,
+                serde_json::to_string(&job.results)?,
+                job.expected_result_count,
+                serde_json::to_string(&job.failures)?,
+                serde_json::to_string(&job.analyzers)?,
+                job.risk.as_ref().map(serde_json::to_string).transpose()?,
+                job.created_at.timestamp(),
+                job.original.as_ref().map(|o| serde_json::to_string(&o.format)).transpose()?,
+                job.original.as_ref().map(|o| &o.content),
+                job.report.as_ref().map(serde_json::to_string).transpose()?,
+                job.source.as_ref().map(serde_json::to_string).transpose()?,
+                job.fingerprint,
+                job.duplicate_of,
+                serde_json::to_string(&job.submissions)?,
+                serde_json::to_string(&job.options)?,
+                serde_json::to_string(&job.rechecks)?,
+            ],
+        )?;
+        update_last_id(&connection, "jobs", job.id)?;
+
+        Ok(())
+    }
+
+    fn load_jobs(&self) -> Result<Vec<StoredJob>, StorageError> {
+        let connection = self.connection.lock().unwrap();
+
+        let mut statement = connection.prepare(
+            "SELECT id, email, state, results, expected_result_count, failures, analyzers, risk, created_at, original_format, original, report, source, fingerprint, duplicate_of, submissions, options, rechecks
+             FROM jobs ORDER BY id",
+        )?;
+
+        let rows = statement.query_map([], |row| {
+            Ok((
+                row.get::<_, usize>(0)?,
+                row.get::<_, Vec<u8>>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, i32>(4)?,
+                row.get::<_, String>(5)?,
+                row.get::<_, String>(6)?,
+                row.get::<_, Option<String>>(7)?,
+                row.get::<_, i64>(8)?,
+                row.get::<_, Option<String>>(9)?,
+                row.get::<_, Option<Vec<u8>>>(10)?,
+                row.get::<_, Option<String>>(11)?,
+                row.get::<_, Option<String>>(12)?,
+                row.get::<_, Option<String>>(13)?,
+                row.get::<_, Option<usize>>(14)?,
+                row.get::<_, String>(15)?,
+                row.get::<_, String>(16)?,
+                row.get::<_, String>(17)?,
+            ))
+        })?;
+
+        let mut jobs = vec![];
+        for row in rows {
+            let (
+                id,
+                email,
+                state,
+                results,
+                expected_result_count,
+                failures,
+                analyzers,
+                risk,
+                created_at,
+                original_format,
+                original,
+                report,
+                source,
+                fingerprint,
+                duplicate_of,
+                submissions,
+                options,
+                rechecks,
+            ) = row?;
+            let original = match (original_format, original) {
+                (Some(format), Some(content)) => Some(OriginalFile {
+                    format: serde_json::from_str(&format)?,
+                    content,
+                }),
+                _ => None,
+            };
+            jobs.push(StoredJob {
+                id,
+                email,
+                original,
+                report: report.as_deref().map(serde_json::from_str).transpose()?,
+                source: source.as_deref().map(serde_json::from_str).transpose()?,
+                fingerprint,
+                duplicate_of,
+                submissions: serde_json::from_str(&submissions)?,
+                options: serde_json::from_str(&options)?,
+                created_at: DateTime::from_timestamp(created_at, 0).unwrap_or_default(),
+                state: serde_json::from_str(&state)?,
+                results: serde_json::from_str(&results)?,
+                expected_result_count,
+                failures: serde_json::from_str(&failures)?,
+                analyzers: serde_json::from_str(&analyzers)?,
+                risk: risk.as_deref().map(serde_json::from_str).transpose()?,
+                rechecks: serde_json::from_str(&rechecks)?,
+            });
+        }
+
+        Ok(jobs)
+    }
+
+    fn delete_job(&self, job_id: usize) -> Result<(), StorageError> {
+        let connection = self.connection.lock().unwrap();
+        connection.execute("DELETE FROM jobs WHERE id = ?1", params![job_id])?;
+        Ok(())
+    }
+
+    fn last_job_id(&self) -> Result<usize, StorageError> {
+        last_id(&self.connection.lock().unwrap(), "jobs")
+    }
+
+    fn save_batch(&self, batch: &Batch) -> Result<(), StorageError> {
+        let connection = self.connection.lock().unwrap();
+
+        connection.execute(
+            "INSERT OR REPLACE INTO batches (id, created_at, jobs, rejected) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                batch.id,
+                batch.created_at.timestamp(),
+                serde_json::to_string(&batch.jobs)?,
+                serde_json::to_string(&batch.rejected)?,
+            ],
+        )?;
+        update_last_id(&connection, "batches", batch.id)?;
+
+        Ok(())
+    }
+
+    fn load_batches(&self) -> Result<Vec<Batch>, StorageError> {
+        let connection = self.connection.lock().unwrap();
+
+        let mut statement =
+            connection.prepare("SELECT id, created_at, jobs, rejected FROM batches ORDER BY id")?;
+
+        let rows = statement.query_map([], |row| {
+            Ok((
+                row.get::<_, usize>(0)?,
+                row.get::<_, i64>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, String>(3)?,
+            ))
+        })?;
+
+        let mut batches = vec![];
+        for row in rows {
+            let (id, created_at, jobs, rejected) = row?;
+            batches.push(Batch {
+                id,
+                created_at: DateTime::from_timestamp(created_at, 0).unwrap_or_default(),
+                jobs: serde_json::from_str(&jobs)?,
+                rejected: serde_json::from_str(&rejected)?,
+            });
+        }
+
+        Ok(batches)
+    }
+
+    fn delete_batch(&self, batch_id: usize) -> Result<(), StorageError> {
+        let connection = self.connection.lock().unwrap();
+        connection.execute("DELETE FROM batches WHERE id = ?1", params![batch_id])?;
+        Ok(())
+    }
+
+    fn last_batch_id(&self) -> Result<usize, StorageError> {
+        last_id(&self.connection.lock().unwrap(), "batches")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::analysis::{AnalysisOptions, AnalysisResult, AnalysisVerdict};
+    use crate::batch::{Batch, BatchJob};
+    use crate::dedup::Submission;
+    use crate::recheck::Recheck;
+    use crate::email::{EmailFormat, OriginalFile};
+    use crate::ingestion::JobSource;
+    use crate::job::JobState;
+    use crate::storage::sqlite::migrate;
+    use crate::storage::{JobStorage, SqliteStorage, StoredJob};
+    use chrono::{Timelike, Utc};
+    use rusqlite::Connection;
+
+    #[test]
+    fn test_save_and_load() {
+        let storage = SqliteStorage::open(":memory:").unwrap();
+
+        let mut job = StoredJob {
+            id: 1,
+            email: b"Subject: test\r\n\r\nh\xe9llo".to_vec(),
+            original: Some(OriginalFile {
+                format: EmailFormat::Msg,
+                content: vec![0xD0, 0xCF],
+            }),
+            report: None,
+            source: Some(JobSource::Imap {
+                mailbox: String::from("reports"),
+                folder: String::from("INBOX"),
+                uid: 42,
+            }),
+            options: AnalysisOptions {
+                analyzers: Some(vec![String::from("auth")]),
+                ..AnalysisOptions::default()
+            },
+            fingerprint: Some(String::from("0f1e")),
+            duplicate_of: None,
+            submissions: vec![],
+            created_at: Utc::now().with_nanosecond(0).unwrap(),
+            state: JobState::Analyzing,
+            results: vec![],
+            failures: vec![],
+            analyzers: vec![],
+            risk: None,
+            rechecks: vec![],
+            expected_result_count: -1,
+        };
+        storage.save_job(&job).unwrap();
+
+        job.state = JobState::Analyzed;
+        job.submissions.push(Submission {
+            job_id: 2,
+            submitted_at: job.created_at,
+            reporter: Some(String::from("alice@example.org")),
+        });
+        job.rechecks.push(Recheck {
+            checked_at: job.created_at,
+            links: vec![],
+            failed: vec![String::from("https://example.org/login")],
+        });
+        job.expected_result_count = 1;
+        job.results.push(AnalysisResult::new(
+            String::from("test"),
+            AnalysisVerdict::new("test", "value"),
+        ));
+        storage.save_job(&job).unwrap();
+
+        let jobs = storage.load_jobs().unwrap();
+        assert_eq!(jobs.len(), 1);
+        assert!(matches!(jobs[0].state, JobState::Analyzed));
+        assert_eq!(jobs[0].expected_result_count, 1);
+        assert_eq!(jobs[0].results[0].id(), job.results[0].id());
+        assert_eq!(jobs[0].created_at, job.created_at);
+        assert_eq!(jobs[0].email, job.email);
+        assert_eq!(jobs[0].source, job.source);
+        assert_eq!(jobs[0].options, job.options);
+        assert_eq!(jobs[0].fingerprint, job.fingerprint);
+        assert_eq!(jobs[0].submissions, job.submissions);
+        assert_eq!(jobs[0].rechecks.len(), 1);
+        let original = jobs[0].original.as_ref().unwrap();
+        assert_eq!(original.format, EmailFormat::Msg);
+        assert_eq!(original.content, [0xD0, 0xCF]);
+
+        storage.delete_job(1).unwrap();
+        assert!(storage.load_jobs().unwrap().is_empty());
+        assert_eq!(storage.last_job_id().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_batches() {
+        let storage = SqliteStorage::open(":memory:").unwrap();
+
+        let batch = Batch {
+            id: 1,
+            created_at: Utc::now().with_nanosecond(0).unwrap(),
+            jobs: vec![BatchJob {
+                job_id: 3,
+                index: 0,
+                name: Some(String::from("reports/1.eml")),
+            }],
+            rejected: vec![],
+        };
+        storage.save_batch(&batch).unwrap();
+
+        let batches = storage.load_batches().unwrap();
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].created_at, batch.created_at);
+        assert_eq!(batches[0].jobs[0].job_id, 3);
+        assert_eq!(batches[0].jobs[0].name.as_deref(), Some("reports/1.eml"));
+
+        storage.delete_batch(1).unwrap();
+        assert!(storage.load_batches().unwrap().is_empty());
+        assert_eq!(storage.last_batch_id().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_failed_migration() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        let migrations = [
+            "CREATE TABLE jobs (id INTEGER PRIMARY KEY)",
+            "ALTER TABLE jobs ADD COLUMN state TEXT;
+             ALTER TABLE missing ADD COLUMN state TEXT",
+        ];
+        assert!(migrate(&mut connection, &migrations).is_err());
+
+        // the failed migration is rolled back entirely, along with its version
+        let version: usize = connection
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, 1);
+        assert!(connection.prepare("SELECT state FROM jobs").is_err());
+    }
+}
@@ -0,0 +1,219 @@
+use crate::analysis::{
+    start_email_analysis, AnalysisOptions, AnalysisResult, AnalysisTimeouts, JobEvent,
+    MailAnalyzer,
+};
+use crate::email::read_email;
+use crate::ingestion::JobSource;
+use crate::job::{AnalyzerState, Job, JobState};
+use crate::rules::{RULES, RULES_ANALYSIS_NAME};
+use crate::scoring;
+use crate::state::{JobAddition, Jobs};
+use log::{info, warn};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+/// Creates a job for the given file and starts its analysis by the `analyzers` chosen
+/// in `options`, in the background: only the creation of the job is waited for.
+/// Once the analyzers are over, the detection rules and the risk assessment conclude the job.
+///
+/// The file is either an RFC 822 email or an Outlook message, converted to MIME first.
+/// The message forwarded by a user report is analyzed in place of the report, `reported` is set
+/// when the file is known to be a report.
+/// An email that was already submitted is handled as set by the deduplication policy,
+/// see [`Jobs::add_job`].
+/// Returns `None` if the file can't be read as an email.
+pub async fn submit_email(
+    jobs: &Arc<Mutex<Jobs>>,
+    content: Vec<u8>,
+    reported: bool,
+    source: Option<JobSource>,
+    options: AnalysisOptions,
+    analyzers: &[Arc<dyn MailAnalyzer>],
+    timeouts: &AnalysisTimeouts,
+) -> Option<Arc<Job>> {
+    let mut email = read_email(content, reported)?;
+    email.source = source;
+    email.options = options;
+
+    let added = jobs.lock().await.add_job(email);
+
+    start_added_job(jobs, &added, analyzers, timeouts);
+
+    Some(added.job().clone())
+}
+
+/// Starts the analysis of a new job, or the copy of the results of the original job
+/// of a linked one
+pub fn start_added_job(
+    jobs: &Arc<Mutex<Jobs>>,
+    added: &JobAddition,
+    analyzers: &[Arc<dyn MailAnalyzer>],
+    timeouts: &AnalysisTimeouts,
+) {
+    match added {
+        JobAddition::Created(job) => start_job(jobs, job.clone(), analyzers, timeouts),
+        JobAddition::Existing(job) => info!("Email already submitted as job {}", job.id),
+        JobAddition::Linked { job, original } => {
+            info!("Job {} linked to job {}", job.id, original.id);
+            tokio::spawn(follow_job(job.clone(), original.clone(), jobs.clone()));
+        }
+    }
+}
+
+/// Starts the analysis of a job added to `jobs` by the analyzers its options choose among
+/// `analyzers`, along with its conclusion and its time limit
+pub fn start_job(
+    jobs: &Arc<Mutex<Jobs>>,
+    job: Arc<Job>,
+    analyzers: &[Arc<dyn MailAnalyzer>],
+    timeouts: &AnalysisTimeouts,
+) {
+    let analyzers: Vec<_> = analyzers
+        .iter()
+        .filter(|a| job.options.runs(a.id()))
+        .cloned()
+        .collect();
+
+    run_analyzers(jobs, job, analyzers, 0, timeouts);
+}
+
+/// Runs the given analyzers of a complete job again, in place of their previous results,
+/// see [`Job::rerun`]. The new results are published on the events of the job.
+/// Returns false if the job is not complete.
+pub fn rerun_job(
+    jobs: &Arc<Mutex<Jobs>>,
+    job: Arc<Job>,
+    analyzers: Vec<Arc<dyn MailAnalyzer>>,
+    timeouts: &AnalysisTimeouts,
+) -> bool {
+    let Some(sequence) = job.rerun(analyzers.iter().map(|a| a.name()).collect()) else {
+        return false;
+    };
+    info!("Job {} rerun", job.id);
+
+    run_analyzers(jobs, job, analyzers, sequence, timeouts);
+    true
+}
+
+/// Starts analyzers on a job, then concludes it once the analyzers finished after the event
+/// of the given sequence number
+fn run_analyzers(
+    jobs: &Arc<Mutex<Jobs>>,
+    job: Arc<Job>,
+    analyzers: Vec<Arc<dyn MailAnalyzer>>,
+    sequence: u64,
+    timeouts: &AnalysisTimeouts,
+) {
+    tokio::spawn(conclude_job(
+        job.clone(),
+        jobs.clone(),
+        analyzers.iter().map(|a| a.name()).collect(),
+        sequence,
+    ));
+
+    spawn_job_timeout(job.clone(), timeouts.job);
+
+    let timeouts = timeouts.clone();
+    tokio::spawn(async move { start_email_analysis(analyzers, job, &timeouts).await });
+}
+
+fn spawn_job_timeout(job: Arc<Job>, timeout: Duration) {
+    let rerun_count = job.rerun_count();
+
+    tokio::spawn(async move {
+        tokio::time::sleep(timeout).await;
+        // a rerun job is given the full time again
+        if !job.is_complete() && job.rerun_count() == rerun_count {
+            warn!("Job {} timed out", job.id);
+            job.time_out();
+        }
+    });
+}
+
+/// Publishes the events of the original job to a linked job, until the original job completes
+async fn follow_job(job: Arc<Job>, original: Arc<Job>, jobs: Arc<Mutex<Jobs>>) {
+    let mut events = original.subscribe_events(0);
+
+    while let Some((_, event)) = events.next().await {
+        if matches!(event, JobEvent::JobComplete) {
+            *job.state.lock().unwrap() = original.state.lock().unwrap().clone();
+        }
+        job.publish(event);
+    }
+
+    jobs.lock().await.save_job(&job);
+}
+
+/// Waits for every analyzer to be over, then completes the job
+async fn conclude_job(
+    job: Arc<Job>,
+    jobs: Arc<Mutex<Jobs>>,
+    mut remaining_analyzers: Vec<String>,
+    sequence: u64,
+) {
+    let mut events = job.subscribe_events(sequence);
+
+    info!("Subscribed to job {} events", job.id);
+
+    // the job is updated by its events, only wait for every analyzer to be over
+    while !remaining_analyzers.is_empty() {
+        let Some((_, event)) = events.next().await else {
+            break;
+        };
+        if let JobEvent::AnalyzerFinished(status) = event {
+            remaining_analyzers.retain(|a| a != &status.name);
+            jobs.lock().await.save_job(&job);
+        }
+    }
+
+    if job.is_cancelled() {
+        *job.state.lock().unwrap() = JobState::Cancelled;
+        job.mark_as_complete();
+        jobs.lock().await.save_job(&job);
+
+        info!("Job {} cancelled", job.id);
+        return;
+    }
+
+    let all_failed = job
+        .analyzers
+        .lock()
+        .unwrap()
+        .iter()
+        .all(|s| s.state == AnalyzerState::Failed);
+
+    *job.state.lock().unwrap() = if all_failed {
+        JobState::Error(String::from("every analyzer failed"))
+    } else {
+        JobState::Analyzed
+    };
+
+    if let Some(rules) = RULES.get() {
+        let verdicts: Vec<_> = job
+            .results
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|r| r.verdict.clone())
+            .collect();
+
+        // the rule matches are expected, like the results of the analyzers
+        let matches = rules.evaluate(&job.email(), &verdicts);
+        job.publish(JobEvent::ExpandedResultCount(matches.len()));
+
+        for verdict in matches {
+            let result = AnalysisResult::new(String::from(RULES_ANALYSIS_NAME), verdict);
+            job.publish(JobEvent::Progress(result));
+        }
+    }
+
+    let risk = scoring::assess(job.results.lock().unwrap().iter().map(|r| &r.verdict));
+    job.publish(JobEvent::RiskAssessed(risk));
+
+    job.mark_as_complete();
+
+    jobs.lock().await.save_job(&job);
+
+    info!("Unsubscribed from job {} events", job.id)
+}